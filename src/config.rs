@@ -0,0 +1,95 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use rand::Rng;
+use rand::distr::Alphanumeric;
+
+use crate::dht::DEFAULT_BOOTSTRAP_NODES;
+use crate::peer::choker::DEFAULT_UPLOAD_SLOTS;
+use crate::peer::client::ClientFilter;
+use crate::peer::mse::EncryptionPolicy;
+
+pub const DEFAULT_PORT: u16 = 6881;
+pub const DEFAULT_DHT_STATE: &str = "dht.dat";
+pub const DEFAULT_BAN_LIST: &str = "bans.dat";
+pub const DEFAULT_MAX_CONNECTIONS: usize = 200;
+pub const DEFAULT_MAX_TORRENT_CONNECTIONS: usize = 50;
+
+// Azureus-style client prefix: `-` + two letter client code + four version digits + `-`
+const CLIENT_CODE: &[u8; 2] = b"TU";
+// sent to peers as `v` in the extended handshake
+pub const CLIENT_NAME: &str = concat!("Torrentium ", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub port: u16,
+    pub peer_id: [u8; 20],
+    pub numwant: Option<u32>,
+    pub key: u32,
+    pub ip: Option<IpAddr>,
+    pub no_peer_id: bool,
+    pub dht: bool,
+    // `host:port` of the nodes used to join the DHT
+    pub dht_nodes: Vec<String>,
+    // where the routing table is kept between runs
+    pub dht_state: Option<PathBuf>,
+    // BEP 14 multicast discovery of peers on the local network
+    pub lsd: bool,
+    // keep serving peers after a download completes
+    pub seed: bool,
+    // peers uploaded to at once, one of them chosen optimistically
+    pub upload_slots: usize,
+    // whether peer connections use message stream encryption
+    pub encryption: EncryptionPolicy,
+    // BEP 29 connections over UDP, alongside TCP
+    pub utp: bool,
+    // which peer clients we exchange pieces with
+    pub client_filter: ClientFilter,
+    // peer connections open at once across every torrent, and for any one torrent
+    pub max_connections: usize,
+    pub max_torrent_connections: usize,
+    // where banned peers are kept between runs
+    pub ban_list: Option<PathBuf>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            port: DEFAULT_PORT,
+            peer_id: generate_peer_id(),
+            numwant: None,
+            key: rand::random(),
+            ip: None,
+            no_peer_id: false,
+            dht: true,
+            dht_nodes: DEFAULT_BOOTSTRAP_NODES.iter().map(|s| s.to_string()).collect(),
+            dht_state: Some(PathBuf::from(DEFAULT_DHT_STATE)),
+            lsd: true,
+            seed: true,
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            encryption: EncryptionPolicy::default(),
+            utp: true,
+            client_filter: ClientFilter::default(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_torrent_connections: DEFAULT_MAX_TORRENT_CONNECTIONS,
+            ban_list: Some(PathBuf::from(DEFAULT_BAN_LIST)),
+        }
+    }
+}
+
+// e.g. `-TU0100-` followed by 12 random alphanumeric characters, unique to this session
+pub fn generate_peer_id() -> [u8; 20] {
+    let mut peer_id = [0u8; 20];
+    let version = format!("{}{}{}0",
+        env!("CARGO_PKG_VERSION_MAJOR"),
+        env!("CARGO_PKG_VERSION_MINOR"),
+        env!("CARGO_PKG_VERSION_PATCH"));
+    peer_id[0] = b'-';
+    peer_id[1..3].copy_from_slice(CLIENT_CODE);
+    peer_id[3..7].copy_from_slice(&version.as_bytes()[..4]);
+    peer_id[7] = b'-';
+    for (byte, c) in peer_id[8..].iter_mut().zip(rand::rng().sample_iter(Alphanumeric)) {
+        *byte = c;
+    }
+    peer_id
+}
@@ -0,0 +1,526 @@
+use std::collections::{HashMap, HashSet, BTreeMap};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, Instant};
+use std::{fs, io};
+
+use thiserror::Error;
+use tokio::net::{UdpSocket, lookup_host};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::{interval, sleep, timeout};
+use tracing::{info, warn};
+
+use crate::dht::krpc::{Body, KrpcMessage, NodeInfo, Query, Response, decode_nodes, encode_nodes};
+use crate::dht::routing::{K, RoutingTable, distance};
+use crate::dht::storage::PeerStorage;
+use crate::metadata::bencode::{BencodeError, BencodeValue};
+use crate::util::sha1::sha1_hash;
+
+pub mod krpc;
+pub mod routing;
+pub mod storage;
+
+pub const DEFAULT_BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+// number of queries in flight during an iterative lookup
+const ALPHA: usize = 3;
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+const REANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// how often peers announced to us are checked for expiry
+const STORAGE_SWEEP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const MAX_PACKET_LENGTH: usize = 1500;
+// peers returned for a single `get_peers` query, keeping the response within one datagram
+const MAX_VALUES: usize = 100;
+
+const ERROR_PROTOCOL: i64 = 203;
+const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+#[derive(Debug, Error)]
+pub enum DhtError {
+    #[error("KRPC message is not b-encoded: {0:?}")]
+    NonBencodedMessage(BencodeError),
+    #[error("malformed KRPC message: `{0}`")]
+    MalformedMessage(&'static str),
+    #[error("unknown KRPC method `{0}`")]
+    UnknownMethod(String),
+    #[error("DHT socket error: {0:?}")]
+    SocketError(io::Error),
+    #[error("no response from {0} within {1} seconds")]
+    Timeout(SocketAddr, u64),
+    #[error("node {0} responded with error {1}: {2}")]
+    ErrorResponse(SocketAddr, i64, String),
+    #[error("unable to access DHT state file {0:?}: {1:?}")]
+    StateFileError(PathBuf, io::Error),
+    #[error("malformed DHT state file {0:?}")]
+    MalformedStateFile(PathBuf),
+    #[error("no DHT nodes could be reached")]
+    NoNodes,
+}
+
+type Result<T> = std::result::Result<T, DhtError>;
+type PendingQueries = HashMap<Vec<u8>, (SocketAddr, oneshot::Sender<Result<Response>>)>;
+
+#[derive(Debug)]
+struct Tokens {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct Lookup {
+    pub peers: Vec<SocketAddr>,
+    // the closest nodes which responded, with the token each handed out
+    pub closest: Vec<(NodeInfo, Option<Vec<u8>>)>,
+}
+
+#[derive(Debug)]
+pub struct Dht {
+    id: [u8; 20],
+    socket: Arc<UdpSocket>,
+    table: Mutex<RoutingTable>,
+    pending: Mutex<PendingQueries>,
+    next_transaction: AtomicU16,
+    tokens: Mutex<Tokens>,
+    storage: Mutex<PeerStorage>,
+    listener: Mutex<Option<AbortHandle>>,
+}
+
+impl Dht {
+    // binds the node and starts answering queries; the node keeps running until `shutdown`
+    pub async fn bind(address: SocketAddr, id: Option<[u8; 20]>) -> Result<Arc<Self>> {
+        let socket = UdpSocket::bind(address).await.map_err(DhtError::SocketError)?;
+        let dht = Dht::new(Arc::new(socket), id);
+        let handle = tokio::spawn(dht.clone().listen()).abort_handle();
+        *dht.listener.lock().unwrap() = Some(handle);
+        Ok(dht)
+    }
+
+    // runs the node on a socket shared with another protocol, such as uTP, which passes on the datagrams it does not claim
+    pub fn attach(socket: Arc<UdpSocket>, datagrams: mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>, id: Option<[u8; 20]>) -> Arc<Self> {
+        let dht = Dht::new(socket, id);
+        let handle = tokio::spawn(dht.clone().listen_shared(datagrams)).abort_handle();
+        *dht.listener.lock().unwrap() = Some(handle);
+        dht
+    }
+
+    fn new(socket: Arc<UdpSocket>, id: Option<[u8; 20]>) -> Arc<Self> {
+        let id = id.unwrap_or_else(rand::random);
+        Arc::new(Dht {
+            id,
+            socket,
+            table: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(rand::random()),
+            tokens: Mutex::new(Tokens { current: rand::random(), previous: rand::random(), rotated: Instant::now() }),
+            storage: Mutex::new(PeerStorage::default()),
+            listener: Mutex::new(None),
+        })
+    }
+
+    pub fn id(&self) -> [u8; 20] {
+        self.id
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr().map_err(DhtError::SocketError)
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.table.lock().unwrap().len()
+    }
+
+    pub fn shutdown(&self) {
+        if let Some(handle) = self.listener.lock().unwrap().take() {
+            handle.abort();
+        }
+    }
+
+    async fn listen(self: Arc<Self>) {
+        let mut buf = vec![0u8; MAX_PACKET_LENGTH];
+        let mut sweep = interval(STORAGE_SWEEP_INTERVAL);
+        loop {
+            tokio::select! {
+                received = self.socket.recv_from(&mut buf) => match received {
+                    Ok((n, from)) => self.handle(&buf[..n], from).await,
+                    Err(e) => warn!("DHT receive failed: {:?}", e),
+                },
+                _ = sweep.tick() => self.storage.lock().unwrap().expire(),
+            }
+        }
+    }
+
+    async fn listen_shared(self: Arc<Self>, mut datagrams: mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>) {
+        let mut sweep = interval(STORAGE_SWEEP_INTERVAL);
+        loop {
+            tokio::select! {
+                datagram = datagrams.recv() => match datagram {
+                    Some((packet, from)) => self.handle(&packet, from).await,
+                    None => break,
+                },
+                _ = sweep.tick() => self.storage.lock().unwrap().expire(),
+            }
+        }
+    }
+
+    async fn handle(&self, packet: &[u8], from: SocketAddr) {
+        let message = match KrpcMessage::decode(packet) {
+            Ok(message) => message,
+            Err(DhtError::UnknownMethod(method)) => {
+                if let Ok(BencodeValue::Dictionary(items)) = BencodeValue::try_from(packet)
+                    && let Some(BencodeValue::ByteString(transaction)) = items.get(b"t".as_slice()) {
+                    let body = Body::Error { code: ERROR_METHOD_UNKNOWN, message: format!("method unknown: {method}") };
+                    self.send(&KrpcMessage { transaction: transaction.clone(), body }, from).await;
+                }
+                return
+            },
+            Err(_) => return,
+        };
+
+        match message.body {
+            Body::Query { id, query } => {
+                self.saw(id, from);
+                let body = self.answer(query, from);
+                self.send(&KrpcMessage { transaction: message.transaction, body }, from).await;
+            },
+            Body::Response(response) => {
+                if let Some(reply) = self.take_pending(&message.transaction, from) {
+                    let _ = reply.send(Ok(response));
+                }
+            },
+            Body::Error { code, message: text } => {
+                if let Some(reply) = self.take_pending(&message.transaction, from) {
+                    let _ = reply.send(Err(DhtError::ErrorResponse(from, code, text)));
+                }
+            },
+        }
+    }
+
+    fn take_pending(&self, transaction: &[u8], from: SocketAddr) -> Option<oneshot::Sender<Result<Response>>> {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.get(transaction) {
+            Some((address, _)) if *address == from => pending.remove(transaction).map(|(_, reply)| reply),
+            _ => None,
+        }
+    }
+
+    fn saw(&self, id: [u8; 20], from: SocketAddr) {
+        if let SocketAddr::V4(address) = from {
+            self.table.lock().unwrap().insert(NodeInfo { id, address });
+        }
+    }
+
+    fn answer(&self, query: Query, from: SocketAddr) -> Body {
+        let mut response = Response { id: self.id, ..Response::default() };
+        match query {
+            Query::Ping => (),
+            Query::FindNode { target } => {
+                response.nodes = self.table.lock().unwrap().closest(&target, K);
+            },
+            Query::GetPeers { info_hash } => {
+                response.token = Some(self.token(from.ip()));
+                response.values = self.storage.lock().unwrap().peers(&info_hash, MAX_VALUES);
+                if response.values.is_empty() {
+                    response.nodes = self.table.lock().unwrap().closest(&info_hash, K);
+                }
+            },
+            Query::AnnouncePeer { info_hash, port, implied_port, token } => {
+                if !self.valid_token(&token, from.ip()) {
+                    return Body::Error { code: ERROR_PROTOCOL, message: String::from("bad token") }
+                }
+                let port = if implied_port { from.port() } else { port };
+                if port == 0 {
+                    return Body::Error { code: ERROR_PROTOCOL, message: String::from("bad port") }
+                }
+                self.storage.lock().unwrap().insert(info_hash, SocketAddr::new(from.ip(), port));
+            },
+        }
+        Body::Response(response)
+    }
+
+    // tokens are a hash of a rotating secret and the querying address, so that only the node
+    // which asked for peers may later announce itself
+    fn token(&self, ip: IpAddr) -> Vec<u8> {
+        let mut tokens = self.tokens.lock().unwrap();
+        if tokens.rotated.elapsed() >= TOKEN_ROTATION {
+            tokens.previous = tokens.current;
+            tokens.current = rand::random();
+            tokens.rotated = Instant::now();
+        }
+        Self::hash_token(&tokens.current, ip)
+    }
+
+    fn valid_token(&self, token: &[u8], ip: IpAddr) -> bool {
+        let tokens = self.tokens.lock().unwrap();
+        token == Self::hash_token(&tokens.current, ip) || token == Self::hash_token(&tokens.previous, ip)
+    }
+
+    fn hash_token(secret: &[u8; 20], ip: IpAddr) -> Vec<u8> {
+        let mut bytes = secret.to_vec();
+        match ip {
+            IpAddr::V4(ip) => bytes.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => bytes.extend_from_slice(&ip.octets()),
+        }
+        sha1_hash(&bytes)[..8].to_vec()
+    }
+
+    async fn send(&self, message: &KrpcMessage, to: SocketAddr) {
+        if let Err(e) = self.socket.send_to(&message.encode(), to).await {
+            warn!("unable to send DHT message to {}: {:?}", to, e);
+        }
+    }
+
+    async fn query(&self, to: SocketAddr, query: Query) -> Result<Response> {
+        let transaction = self.next_transaction.fetch_add(1, Ordering::Relaxed).to_be_bytes().to_vec();
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(transaction.clone(), (to, reply_tx));
+
+        let message = KrpcMessage { transaction: transaction.clone(), body: Body::Query { id: self.id, query } };
+        self.send(&message, to).await;
+
+        let result = match timeout(QUERY_TIMEOUT, reply_rx).await {
+            Ok(Ok(result)) => result,
+            _ => Err(DhtError::Timeout(to, QUERY_TIMEOUT.as_secs())),
+        };
+        self.pending.lock().unwrap().remove(&transaction);
+
+        match &result {
+            Ok(response) => self.saw(response.id, to),
+            Err(_) => {
+                let mut table = self.table.lock().unwrap();
+                for node in table.nodes().iter().filter(|n| SocketAddr::V4(n.address) == to) {
+                    table.fail(&node.id);
+                }
+            },
+        }
+        result
+    }
+
+    pub async fn ping(&self, to: SocketAddr) -> Result<[u8; 20]> {
+        self.query(to, Query::Ping).await.map(|r| r.id)
+    }
+
+    pub async fn find_node(&self, to: SocketAddr, target: [u8; 20]) -> Result<Vec<NodeInfo>> {
+        self.query(to, Query::FindNode { target }).await.map(|r| r.nodes)
+    }
+
+    pub async fn get_peers(&self, to: SocketAddr, info_hash: [u8; 20]) -> Result<Response> {
+        self.query(to, Query::GetPeers { info_hash }).await
+    }
+
+    pub async fn announce_peer(&self, to: SocketAddr, info_hash: [u8; 20], port: u16, token: Vec<u8>) -> Result<()> {
+        self.query(to, Query::AnnouncePeer { info_hash, port, implied_port: false, token }).await.map(|_| ())
+    }
+
+    // joins the network through the given nodes by looking up our own id
+    pub async fn bootstrap(self: &Arc<Self>, addresses: &[SocketAddr]) -> Result<()> {
+        let mut tasks = JoinSet::new();
+        for address in addresses {
+            let dht = self.clone();
+            let address = *address;
+            tasks.spawn(async move { dht.find_node(address, dht.id).await });
+        }
+        let mut seeds: Vec<NodeInfo> = Vec::new();
+        while let Some(joined) = tasks.join_next().await {
+            if let Ok(Ok(nodes)) = joined {
+                seeds.extend(nodes);
+            }
+        }
+
+        self.lookup(self.id, seeds, false).await;
+        let num_nodes = self.num_nodes();
+        info!("DHT bootstrapped with {} nodes", num_nodes);
+        if num_nodes == 0 {
+            return Err(DhtError::NoNodes)
+        }
+        Ok(())
+    }
+
+    // refreshes buckets which have gone quiet by looking up an id in their range
+    pub async fn refresh(self: &Arc<Self>) {
+        let targets = self.table.lock().unwrap().stale_targets();
+        for target in targets {
+            self.lookup(target, Vec::new(), false).await;
+        }
+    }
+
+    // iteratively queries the closest known nodes to `target` until no closer nodes are found;
+    // `get_peers` is used instead of `find_node` when peers for an info hash are wanted
+    pub async fn lookup(self: &Arc<Self>, target: [u8; 20], seeds: Vec<NodeInfo>, get_peers: bool) -> Lookup {
+        let mut candidates: Vec<NodeInfo> = self.table.lock().unwrap().closest(&target, K);
+        let mut seen: HashSet<[u8; 20]> = candidates.iter().map(|n| n.id).collect();
+        seen.insert(self.id);
+        for node in seeds {
+            if seen.insert(node.id) {
+                candidates.push(node);
+            }
+        }
+        candidates.sort_by_key(|n| distance(&n.id, &target));
+
+        let mut queried: HashSet<[u8; 20]> = HashSet::new();
+        let mut responded: Vec<(NodeInfo, Option<Vec<u8>>)> = Vec::new();
+        let mut peers: HashSet<SocketAddr> = HashSet::new();
+        let mut tasks = JoinSet::new();
+
+        loop {
+            while tasks.len() < ALPHA {
+                let Some(node) = candidates.iter().take(K).find(|n| !queried.contains(&n.id)).copied() else {
+                    break
+                };
+                queried.insert(node.id);
+                let dht = self.clone();
+                tasks.spawn(async move {
+                    let address = SocketAddr::V4(node.address);
+                    let result = if get_peers {
+                        dht.get_peers(address, target).await
+                    } else {
+                        dht.find_node(address, target).await.map(|nodes| Response { nodes, ..Response::default() })
+                    };
+                    (node, result)
+                });
+            }
+
+            let Some(joined) = tasks.join_next().await else {
+                break
+            };
+            let Ok((node, result)) = joined else {
+                continue
+            };
+            match result {
+                Ok(response) => {
+                    for found in response.nodes {
+                        if seen.insert(found.id) {
+                            candidates.push(found);
+                        }
+                    }
+                    candidates.sort_by_key(|n| distance(&n.id, &target));
+                    peers.extend(response.values);
+                    responded.push((node, response.token));
+                },
+                Err(_) => candidates.retain(|n| n.id != node.id),
+            }
+        }
+
+        responded.sort_by_key(|(n, _)| distance(&n.id, &target));
+        responded.truncate(K);
+        Lookup { peers: peers.into_iter().collect(), closest: responded }
+    }
+
+    // finds peers for `info_hash` and announces ourselves to the closest nodes on `port`
+    pub async fn announce(self: &Arc<Self>, info_hash: [u8; 20], port: u16) -> Vec<SocketAddr> {
+        let lookup = self.lookup(info_hash, Vec::new(), true).await;
+        let mut tasks = JoinSet::new();
+        for (node, token) in lookup.closest {
+            let Some(token) = token else {
+                continue
+            };
+            let dht = self.clone();
+            tasks.spawn(async move {
+                dht.announce_peer(SocketAddr::V4(node.address), info_hash, port, token).await
+            });
+        }
+        let announced = tasks.join_all().await.into_iter().filter(|r| r.is_ok()).count();
+        info!("DHT found {} peers and announced to {} nodes", lookup.peers.len(), announced);
+        lookup.peers
+    }
+
+    // bootstraps, then periodically feeds peers for `info_hash` to the downloader
+    pub async fn search(self: Arc<Self>,
+                        bootstrap: Vec<SocketAddr>,
+                        info_hash: [u8; 20],
+                        port: u16,
+                        peers: mpsc::UnboundedSender<Vec<SocketAddr>>) {
+        if let Err(e) = self.bootstrap(&bootstrap).await {
+            warn!("DHT bootstrap failed: {}", e);
+        }
+        loop {
+            self.refresh().await;
+            let found = self.announce(info_hash, port).await;
+            if !found.is_empty() && peers.send(found).is_err() {
+                break
+            }
+            sleep(REANNOUNCE_INTERVAL).await;
+        }
+    }
+
+    // the routing table is stored as a dictionary of our id and compact node info
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut items: BTreeMap<Vec<u8>, BencodeValue> = BTreeMap::new();
+        items.insert(b"id".to_vec(), BencodeValue::ByteString(self.id.to_vec()));
+        let nodes = self.table.lock().unwrap().nodes();
+        items.insert(b"nodes".to_vec(), BencodeValue::ByteString(encode_nodes(&nodes)));
+        fs::write(path, Vec::from(&BencodeValue::Dictionary(items)))
+            .map_err(|e| DhtError::StateFileError(path.to_path_buf(), e))?;
+        info!("saved {} DHT nodes to {:?}", nodes.len(), path);
+        Ok(())
+    }
+}
+
+pub fn load_state<P: AsRef<Path>>(path: P) -> Result<([u8; 20], Vec<NodeInfo>)> {
+    let path = path.as_ref();
+    let contents = fs::read(path).map_err(|e| DhtError::StateFileError(path.to_path_buf(), e))?;
+    let malformed = || DhtError::MalformedStateFile(path.to_path_buf());
+    let Ok(BencodeValue::Dictionary(items)) = BencodeValue::try_from(contents.as_slice()) else {
+        return Err(malformed())
+    };
+    let id: [u8; 20] = match items.get(b"id".as_slice()) {
+        Some(BencodeValue::ByteString(b)) => b.as_slice().try_into().map_err(|_| malformed())?,
+        _ => return Err(malformed()),
+    };
+    let nodes = match items.get(b"nodes".as_slice()) {
+        Some(BencodeValue::ByteString(b)) => decode_nodes(b).map_err(|_| malformed())?,
+        _ => return Err(malformed()),
+    };
+    Ok((id, nodes))
+}
+
+// resolves `host:port` strings, skipping any which cannot be resolved
+pub async fn resolve(hosts: &[String]) -> Vec<SocketAddr> {
+    let mut addresses: Vec<SocketAddr> = Vec::new();
+    for host in hosts {
+        match lookup_host(host.as_str()).await {
+            Ok(resolved) => addresses.extend(resolved.filter(SocketAddr::is_ipv4)),
+            Err(e) => warn!("unable to resolve DHT node '{}': {:?}", host, e),
+        }
+    }
+    addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_DEADLINE: Duration = Duration::from_secs(20);
+
+    #[tokio::test]
+    async fn peers_announced_to_one_node_are_found_from_another() {
+        let mut nodes = Vec::new();
+        for _ in 0..4 {
+            nodes.push(Dht::bind("127.0.0.1:0".parse().unwrap(), None).await.unwrap());
+        }
+        let bootstrap = vec![nodes[0].local_addr().unwrap()];
+        let info_hash = [9; 20];
+
+        timeout(TEST_DEADLINE, async {
+            for node in &nodes[1..] {
+                node.bootstrap(&bootstrap).await.unwrap();
+            }
+            assert!(nodes[1].announce(info_hash, 6881).await.is_empty());
+            let found = nodes[3].lookup(info_hash, Vec::new(), true).await.peers;
+            assert_eq!(found, vec!["127.0.0.1:6881".parse().unwrap()]);
+        }).await.unwrap();
+
+        for node in &nodes {
+            node.shutdown();
+        }
+    }
+}
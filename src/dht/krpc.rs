@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, SocketAddrV4};
+
+use crate::dht::DhtError;
+use crate::metadata::bencode::BencodeValue;
+
+const COMPACT_NODE_LENGTH: usize = 26;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub id: [u8; 20],
+    pub address: SocketAddrV4,
+}
+
+#[derive(Debug, Clone)]
+pub enum Query {
+    Ping,
+    FindNode { target: [u8; 20] },
+    GetPeers { info_hash: [u8; 20] },
+    AnnouncePeer { info_hash: [u8; 20], port: u16, implied_port: bool, token: Vec<u8> },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Response {
+    pub id: [u8; 20],
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Body {
+    Query { id: [u8; 20], query: Query },
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct KrpcMessage {
+    pub transaction: Vec<u8>,
+    pub body: Body,
+}
+
+impl Query {
+    pub fn method(&self) -> &'static str {
+        match self {
+            Query::Ping => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+        }
+    }
+}
+
+pub fn encode_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(nodes.len() * COMPACT_NODE_LENGTH);
+    for node in nodes {
+        bytes.extend_from_slice(&node.id);
+        bytes.extend_from_slice(&node.address.ip().octets());
+        bytes.extend_from_slice(&node.address.port().to_be_bytes());
+    }
+    bytes
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> Result<Vec<NodeInfo>, DhtError> {
+    if !bytes.len().is_multiple_of(COMPACT_NODE_LENGTH) {
+        return Err(DhtError::MalformedMessage("compact node info length"));
+    }
+    Ok(bytes
+        .chunks_exact(COMPACT_NODE_LENGTH)
+        .map(|chunk| {
+            let id: [u8; 20] = chunk[0..20].try_into().expect("chunk expected to be length 26");
+            let ip: [u8; 4] = chunk[20..24].try_into().expect("chunk expected to be length 26");
+            let port = u16::from_be_bytes([chunk[24], chunk[25]]);
+            NodeInfo { id, address: SocketAddrV4::new(ip.into(), port) }
+        })
+        .collect())
+}
+
+fn encode_peer(address: &SocketAddr) -> Option<Vec<u8>> {
+    match address {
+        SocketAddr::V4(a) => {
+            let mut bytes = a.ip().octets().to_vec();
+            bytes.extend_from_slice(&a.port().to_be_bytes());
+            Some(bytes)
+        },
+        SocketAddr::V6(_) => None,
+    }
+}
+
+fn bytes(value: &[u8]) -> BencodeValue {
+    BencodeValue::ByteString(value.to_vec())
+}
+
+fn get_bytes<'a>(items: &'a BTreeMap<Vec<u8>, BencodeValue>, key: &[u8]) -> Option<&'a [u8]> {
+    match items.get(key) {
+        Some(BencodeValue::ByteString(b)) => Some(b.as_slice()),
+        _ => None,
+    }
+}
+
+fn get_id(items: &BTreeMap<Vec<u8>, BencodeValue>, key: &'static str) -> Result<[u8; 20], DhtError> {
+    get_bytes(items, key.as_bytes())
+        .and_then(|b| b.try_into().ok())
+        .ok_or(DhtError::MalformedMessage(key))
+}
+
+impl KrpcMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut items: BTreeMap<Vec<u8>, BencodeValue> = BTreeMap::new();
+        items.insert(b"t".to_vec(), bytes(&self.transaction));
+        match &self.body {
+            Body::Query { id, query } => {
+                let mut args: BTreeMap<Vec<u8>, BencodeValue> = BTreeMap::new();
+                args.insert(b"id".to_vec(), bytes(id));
+                match query {
+                    Query::Ping => (),
+                    Query::FindNode { target } => {
+                        args.insert(b"target".to_vec(), bytes(target));
+                    },
+                    Query::GetPeers { info_hash } => {
+                        args.insert(b"info_hash".to_vec(), bytes(info_hash));
+                    },
+                    Query::AnnouncePeer { info_hash, port, implied_port, token } => {
+                        args.insert(b"implied_port".to_vec(), BencodeValue::Integer(*implied_port as i64));
+                        args.insert(b"info_hash".to_vec(), bytes(info_hash));
+                        args.insert(b"port".to_vec(), BencodeValue::Integer(*port as i64));
+                        args.insert(b"token".to_vec(), bytes(token));
+                    },
+                }
+                items.insert(b"y".to_vec(), bytes(b"q"));
+                items.insert(b"q".to_vec(), bytes(query.method().as_bytes()));
+                items.insert(b"a".to_vec(), BencodeValue::Dictionary(args));
+            },
+            Body::Response(response) => {
+                let mut values: BTreeMap<Vec<u8>, BencodeValue> = BTreeMap::new();
+                values.insert(b"id".to_vec(), bytes(&response.id));
+                if !response.nodes.is_empty() {
+                    values.insert(b"nodes".to_vec(), BencodeValue::ByteString(encode_nodes(&response.nodes)));
+                }
+                if !response.values.is_empty() {
+                    let peers = response.values.iter().filter_map(encode_peer).map(BencodeValue::ByteString).collect();
+                    values.insert(b"values".to_vec(), BencodeValue::List(peers));
+                }
+                if let Some(token) = &response.token {
+                    values.insert(b"token".to_vec(), bytes(token));
+                }
+                items.insert(b"y".to_vec(), bytes(b"r"));
+                items.insert(b"r".to_vec(), BencodeValue::Dictionary(values));
+            },
+            Body::Error { code, message } => {
+                items.insert(b"y".to_vec(), bytes(b"e"));
+                items.insert(b"e".to_vec(), BencodeValue::List(vec![BencodeValue::Integer(*code), bytes(message.as_bytes())]));
+            },
+        }
+        Vec::from(&BencodeValue::Dictionary(items))
+    }
+
+    pub fn decode(packet: &[u8]) -> Result<Self, DhtError> {
+        let value = BencodeValue::try_from(packet).map_err(DhtError::NonBencodedMessage)?;
+        let BencodeValue::Dictionary(items) = value else {
+            return Err(DhtError::MalformedMessage("message is not a dictionary"));
+        };
+        let transaction = get_bytes(&items, b"t").ok_or(DhtError::MalformedMessage("t"))?.to_vec();
+
+        let body = match get_bytes(&items, b"y") {
+            Some(b"q") => {
+                let Some(BencodeValue::Dictionary(args)) = items.get(b"a".as_slice()) else {
+                    return Err(DhtError::MalformedMessage("a"));
+                };
+                let id = get_id(args, "id")?;
+                let query = match get_bytes(&items, b"q") {
+                    Some(b"ping") => Query::Ping,
+                    Some(b"find_node") => Query::FindNode { target: get_id(args, "target")? },
+                    Some(b"get_peers") => Query::GetPeers { info_hash: get_id(args, "info_hash")? },
+                    Some(b"announce_peer") => {
+                        let port = match args.get(b"port".as_slice()) {
+                            Some(BencodeValue::Integer(p)) => u16::try_from(*p).map_err(|_| DhtError::MalformedMessage("port"))?,
+                            _ => 0,
+                        };
+                        let implied_port = matches!(args.get(b"implied_port".as_slice()), Some(BencodeValue::Integer(1)));
+                        let token = get_bytes(args, b"token").ok_or(DhtError::MalformedMessage("token"))?.to_vec();
+                        Query::AnnouncePeer { info_hash: get_id(args, "info_hash")?, port, implied_port, token }
+                    },
+                    Some(method) => return Err(DhtError::UnknownMethod(String::from_utf8_lossy(method).into_owned())),
+                    None => return Err(DhtError::MalformedMessage("q")),
+                };
+                Body::Query { id, query }
+            },
+            Some(b"r") => {
+                let Some(BencodeValue::Dictionary(values)) = items.get(b"r".as_slice()) else {
+                    return Err(DhtError::MalformedMessage("r"));
+                };
+                let nodes = match get_bytes(values, b"nodes") {
+                    Some(b) => decode_nodes(b)?,
+                    None => Vec::new(),
+                };
+                let peers = match values.get(b"values".as_slice()) {
+                    Some(BencodeValue::List(list)) => list
+                        .iter()
+                        .filter_map(|v| match v {
+                            BencodeValue::ByteString(b) if b.len() == 6 => {
+                                let ip: [u8; 4] = b[0..4].try_into().ok()?;
+                                Some(SocketAddr::from((ip, u16::from_be_bytes([b[4], b[5]]))))
+                            },
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                Body::Response(Response {
+                    id: get_id(values, "id")?,
+                    nodes,
+                    values: peers,
+                    token: get_bytes(values, b"token").map(<[u8]>::to_vec),
+                })
+            },
+            Some(b"e") => {
+                let (code, message) = match items.get(b"e".as_slice()) {
+                    Some(BencodeValue::List(list)) => match list.as_slice() {
+                        [BencodeValue::Integer(code), BencodeValue::ByteString(message), ..] =>
+                            (*code, String::from_utf8_lossy(message).into_owned()),
+                        _ => return Err(DhtError::MalformedMessage("e")),
+                    },
+                    _ => return Err(DhtError::MalformedMessage("e")),
+                };
+                Body::Error { code, message }
+            },
+            _ => return Err(DhtError::MalformedMessage("y")),
+        };
+
+        Ok(KrpcMessage { transaction, body })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: &KrpcMessage) -> KrpcMessage {
+        KrpcMessage::decode(&message.encode()).unwrap()
+    }
+
+    fn query(query: Query) -> KrpcMessage {
+        KrpcMessage { transaction: b"aa".to_vec(), body: Body::Query { id: [1; 20], query } }
+    }
+
+    #[test]
+    fn queries_round_trip() {
+        let decoded = round_trip(&query(Query::Ping));
+        assert_eq!(decoded.transaction, b"aa");
+        assert!(matches!(decoded.body, Body::Query { id, query: Query::Ping } if id == [1; 20]));
+
+        let decoded = round_trip(&query(Query::FindNode { target: [2; 20] }));
+        assert!(matches!(decoded.body, Body::Query { query: Query::FindNode { target }, .. } if target == [2; 20]));
+
+        let decoded = round_trip(&query(Query::GetPeers { info_hash: [3; 20] }));
+        assert!(matches!(decoded.body, Body::Query { query: Query::GetPeers { info_hash }, .. } if info_hash == [3; 20]));
+
+        let announce = Query::AnnouncePeer { info_hash: [4; 20], port: 6881, implied_port: true, token: b"tok".to_vec() };
+        match round_trip(&query(announce)).body {
+            Body::Query { query: Query::AnnouncePeer { info_hash, port, implied_port, token }, .. } => {
+                assert_eq!(info_hash, [4; 20]);
+                assert_eq!(port, 6881);
+                assert!(implied_port);
+                assert_eq!(token, b"tok");
+            },
+            body => panic!("unexpected body {body:?}"),
+        }
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let node = NodeInfo { id: [5; 20], address: "127.0.0.1:6881".parse().unwrap() };
+        let response = Response {
+            id: [6; 20],
+            nodes: vec![node],
+            values: vec!["10.0.0.1:51413".parse().unwrap()],
+            token: Some(b"secret".to_vec()),
+        };
+        let message = KrpcMessage { transaction: b"bb".to_vec(), body: Body::Response(response) };
+        match round_trip(&message).body {
+            Body::Response(response) => {
+                assert_eq!(response.id, [6; 20]);
+                assert_eq!(response.nodes, vec![node]);
+                assert_eq!(response.values, vec!["10.0.0.1:51413".parse::<SocketAddr>().unwrap()]);
+                assert_eq!(response.token.as_deref(), Some(b"secret".as_slice()));
+            },
+            body => panic!("unexpected body {body:?}"),
+        }
+    }
+
+    #[test]
+    fn errors_round_trip() {
+        let message = KrpcMessage { transaction: b"cc".to_vec(), body: Body::Error { code: 203, message: "bad token".into() } };
+        assert!(matches!(round_trip(&message).body, Body::Error { code: 203, message } if message == "bad token"));
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        assert!(matches!(KrpcMessage::decode(b"i42e"), Err(DhtError::MalformedMessage(_))));
+        assert!(matches!(KrpcMessage::decode(b"not bencode"), Err(DhtError::NonBencodedMessage(_))));
+        // no transaction id
+        assert!(matches!(KrpcMessage::decode(b"d1:y1:qe"), Err(DhtError::MalformedMessage("t"))));
+        let unknown = b"d1:ad2:id20:aaaaaaaaaaaaaaaaaaaae1:q4:vote1:t2:aa1:y1:qe";
+        assert!(matches!(KrpcMessage::decode(unknown), Err(DhtError::UnknownMethod(m)) if m == "vote"));
+        assert!(decode_nodes(&[0; COMPACT_NODE_LENGTH + 1]).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use std::time::{Duration, Instant};
+
+use crate::dht::krpc::NodeInfo;
+
+// nodes per bucket
+pub const K: usize = 8;
+const NUM_BUCKETS: usize = 160;
+// a node which has not been heard from in this long is questionable
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+const MAX_FAILURES: u32 = 2;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    info: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+impl Entry {
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+
+    fn is_good(&self) -> bool {
+        !self.is_bad() && self.last_seen.elapsed() < QUESTIONABLE_AFTER
+    }
+}
+
+#[derive(Debug)]
+pub struct RoutingTable {
+    id: [u8; 20],
+    buckets: Vec<Vec<Entry>>,
+}
+
+pub fn distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    let mut d = [0u8; 20];
+    for (i, byte) in d.iter_mut().enumerate() {
+        *byte = a[i] ^ b[i];
+    }
+    d
+}
+
+impl RoutingTable {
+    pub fn new(id: [u8; 20]) -> Self {
+        RoutingTable { id, buckets: vec![Vec::new(); NUM_BUCKETS] }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    // buckets are indexed by the length of the prefix shared with our own id
+    fn bucket_index(&self, id: &[u8; 20]) -> Option<usize> {
+        let d = distance(&self.id, id);
+        let zeros = d.iter()
+            .position(|b| *b != 0)
+            .map(|i| i * 8 + d[i].leading_zeros() as usize)?;
+        Some(zeros.min(NUM_BUCKETS - 1))
+    }
+
+    // records that `node` was heard from; returns false if there was no room for it
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        let Some(index) = self.bucket_index(&node.id) else {
+            return false
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(pos) = bucket.iter().position(|e| e.info.id == node.id) {
+            let mut entry = bucket.remove(pos);
+            entry.info = node;
+            entry.last_seen = Instant::now();
+            entry.failures = 0;
+            bucket.push(entry);
+            return true
+        }
+
+        let entry = Entry { info: node, last_seen: Instant::now(), failures: 0 };
+        if bucket.len() < K {
+            bucket.push(entry);
+            return true
+        }
+        // buckets are kept least recently seen first, so the first bad or stale node is replaced
+        match bucket.iter().position(|e| !e.is_good()) {
+            Some(pos) => {
+                bucket.remove(pos);
+                bucket.push(entry);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn fail(&mut self, id: &[u8; 20]) {
+        if let Some(index) = self.bucket_index(id)
+            && let Some(entry) = self.buckets[index].iter_mut().find(|e| &e.info.id == id) {
+            entry.failures += 1;
+        }
+    }
+
+    pub fn closest(&self, target: &[u8; 20], count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self.buckets
+            .iter()
+            .flatten()
+            .filter(|e| !e.is_bad())
+            .map(|e| e.info)
+            .collect();
+        nodes.sort_by_key(|n| distance(&n.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets.iter().flatten().filter(|e| !e.is_bad()).map(|e| e.info).collect()
+    }
+
+    // ids in the range of each bucket that has not changed recently, to be looked up to refresh it
+    pub fn stale_targets(&self) -> Vec<[u8; 20]> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, bucket)| !bucket.is_empty() && bucket.iter().all(|e| !e.is_good()))
+            .map(|(i, _)| {
+                let mut target = self.id;
+                target[i / 8] ^= 0x80 >> (i % 8);
+                target
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWN_ID: [u8; 20] = [0; 20];
+
+    // a node sharing exactly `prefix` leading bits with our id, told apart by `n`
+    fn node(prefix: usize, n: u8) -> NodeInfo {
+        let mut id = [0u8; 20];
+        id[prefix / 8] = 0x80 >> (prefix % 8);
+        id[19] |= n;
+        NodeInfo { id, address: format!("127.0.0.1:{}", 6881 + n as u16).parse().unwrap() }
+    }
+
+    #[test]
+    fn buckets_split_by_shared_prefix() {
+        let mut table = RoutingTable::new(OWN_ID);
+        for n in 0..K as u8 {
+            assert!(table.insert(node(0, n)));
+        }
+        // the bucket of nodes sharing no prefix is full, while closer nodes land in buckets of their own
+        assert!(!table.insert(node(0, K as u8)));
+        assert!(table.insert(node(1, 0)));
+        assert!(table.insert(node(20, 0)));
+        assert_eq!(table.len(), K + 2);
+        assert_eq!(table.bucket_index(&node(20, 0).id), Some(20));
+        assert_eq!(table.bucket_index(&OWN_ID), None);
+    }
+
+    #[test]
+    fn full_buckets_replace_bad_nodes() {
+        let mut table = RoutingTable::new(OWN_ID);
+        for n in 0..K as u8 {
+            table.insert(node(0, n));
+        }
+        for _ in 0..MAX_FAILURES {
+            table.fail(&node(0, 3).id);
+        }
+        assert!(table.insert(node(0, K as u8)));
+        let nodes = table.nodes();
+        assert!(!nodes.contains(&node(0, 3)));
+        assert!(nodes.contains(&node(0, K as u8)));
+    }
+
+    #[test]
+    fn closest_orders_by_distance() {
+        let mut table = RoutingTable::new(OWN_ID);
+        for prefix in [0, 5, 10, 40] {
+            table.insert(node(prefix, 0));
+        }
+        let closest = table.closest(&node(40, 1).id, 2);
+        assert_eq!(closest, vec![node(40, 0), node(10, 0)]);
+    }
+}
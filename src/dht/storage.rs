@@ -0,0 +1,141 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// how long an announced peer is handed out before it must announce again
+const PEER_EXPIRY: Duration = Duration::from_secs(30 * 60);
+// announces come from remote nodes, so both dimensions are bounded, dropping the stalest entries first
+const MAX_PEERS_PER_TORRENT: usize = 500;
+const MAX_TORRENTS: usize = 2000;
+
+#[derive(Debug)]
+struct Torrent {
+    peers: HashMap<SocketAddr, Instant>,
+    // the same peers ordered by when they announced, so the oldest is found without a scan
+    by_age: BTreeSet<(Instant, SocketAddr)>,
+    last_announce: Instant,
+}
+
+// peers which announced themselves to us, by info hash
+#[derive(Debug, Default)]
+pub struct PeerStorage {
+    torrents: HashMap<[u8; 20], Torrent>,
+    // info hashes ordered by their latest announce
+    by_age: BTreeSet<(Instant, [u8; 20])>,
+}
+
+impl PeerStorage {
+    pub fn insert(&mut self, info_hash: [u8; 20], peer: SocketAddr) {
+        let now = Instant::now();
+        if !self.torrents.contains_key(&info_hash)
+            && self.torrents.len() >= MAX_TORRENTS
+            && let Some((_, stalest)) = self.by_age.pop_first() {
+            self.torrents.remove(&stalest);
+        }
+        let torrent = self.torrents.entry(info_hash).or_insert_with(|| Torrent {
+            peers: HashMap::new(),
+            by_age: BTreeSet::new(),
+            last_announce: now,
+        });
+        self.by_age.remove(&(torrent.last_announce, info_hash));
+        torrent.last_announce = now;
+        self.by_age.insert((now, info_hash));
+
+        match torrent.peers.insert(peer, now) {
+            Some(announced) => {
+                torrent.by_age.remove(&(announced, peer));
+            },
+            None => if torrent.peers.len() > MAX_PEERS_PER_TORRENT
+                && let Some((_, oldest)) = torrent.by_age.pop_first() {
+                torrent.peers.remove(&oldest);
+            },
+        }
+        torrent.by_age.insert((now, peer));
+    }
+
+    pub fn peers(&self, info_hash: &[u8; 20], max: usize) -> Vec<SocketAddr> {
+        self.torrents
+            .get(info_hash)
+            .map(|torrent| torrent.peers
+                .iter()
+                .filter(|(_, announced)| announced.elapsed() < PEER_EXPIRY)
+                .map(|(peer, _)| *peer)
+                .take(max)
+                .collect())
+            .unwrap_or_default()
+    }
+
+    // drops expired peers, and torrents left with none
+    pub fn expire(&mut self) {
+        let mut emptied = Vec::new();
+        for (info_hash, torrent) in self.torrents.iter_mut() {
+            while let Some(&(announced, peer)) = torrent.by_age.first()
+                && announced.elapsed() >= PEER_EXPIRY {
+                torrent.by_age.pop_first();
+                torrent.peers.remove(&peer);
+            }
+            if torrent.peers.is_empty() {
+                emptied.push((torrent.last_announce, *info_hash));
+            }
+        }
+        for (last_announce, info_hash) in emptied {
+            self.by_age.remove(&(last_announce, info_hash));
+            self.torrents.remove(&info_hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(i: usize) -> SocketAddr {
+        SocketAddr::from(([127, 0, (i >> 8) as u8, i as u8], 6881))
+    }
+
+    fn info_hash(i: usize) -> [u8; 20] {
+        let mut hash = [0u8; 20];
+        hash[..8].copy_from_slice(&(i as u64).to_be_bytes());
+        hash
+    }
+
+    #[test]
+    fn evicts_the_oldest_peer_of_a_full_torrent() {
+        let mut storage = PeerStorage::default();
+        for i in 0..=MAX_PEERS_PER_TORRENT {
+            storage.insert(info_hash(0), peer(i));
+        }
+        let peers = storage.peers(&info_hash(0), usize::MAX);
+        assert_eq!(peers.len(), MAX_PEERS_PER_TORRENT);
+        assert!(!peers.contains(&peer(0)));
+        assert!(peers.contains(&peer(MAX_PEERS_PER_TORRENT)));
+    }
+
+    #[test]
+    fn reannouncing_keeps_a_peer() {
+        let mut storage = PeerStorage::default();
+        for i in 0..MAX_PEERS_PER_TORRENT {
+            storage.insert(info_hash(0), peer(i));
+        }
+        storage.insert(info_hash(0), peer(0));
+        storage.insert(info_hash(0), peer(MAX_PEERS_PER_TORRENT));
+        let peers = storage.peers(&info_hash(0), usize::MAX);
+        assert!(peers.contains(&peer(0)));
+        assert!(!peers.contains(&peer(1)));
+    }
+
+    #[test]
+    fn evicts_the_stalest_torrent_when_full() {
+        let mut storage = PeerStorage::default();
+        for i in 0..MAX_TORRENTS {
+            storage.insert(info_hash(i), peer(0));
+        }
+        // announcing again makes the first torrent the freshest
+        storage.insert(info_hash(0), peer(1));
+        storage.insert(info_hash(MAX_TORRENTS), peer(0));
+        assert_eq!(storage.torrents.len(), MAX_TORRENTS);
+        assert!(storage.torrents.contains_key(&info_hash(0)));
+        assert!(!storage.torrents.contains_key(&info_hash(1)));
+        assert_eq!(storage.by_age.len(), MAX_TORRENTS);
+    }
+}
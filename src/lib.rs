@@ -2,15 +2,23 @@ use std::path::Path;
 use tracing::info;
 
 use crate::metadata::file::TorrentFile;
+use crate::metadata::tracker::{ScrapeStats, TrackerError};
+use crate::metadata::tracker::server::serve;
 
+mod config;
+mod dht;
+mod lsd;
 mod metadata;
 mod peer;
 mod util;
 
 //pub use peer::Bitfield;
 //pub use peer::message::Message;
-
-const PEER_ID: &[u8; 20] = b"!MySuperCoolTorrent!";
+pub use config::{ClientConfig, DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_TORRENT_CONNECTIONS};
+pub use peer::mse::EncryptionPolicy;
+pub use peer::client::ClientFilter;
+pub use dht::{Dht, DhtError};
+pub use metadata::tracker::server::TrackerServerConfig;
 
 pub fn parse_torrent<P: AsRef<Path>>(path: P) -> std::result::Result<TorrentFile, Box<dyn std::error::Error>> {
     let path = path.as_ref();
@@ -18,10 +26,25 @@ pub fn parse_torrent<P: AsRef<Path>>(path: P) -> std::result::Result<TorrentFile
     Ok(torrent_file)
 }
 
-pub async fn download_torrent<P: AsRef<Path>>(path: P) -> std::result::Result<(), Box<dyn std::error::Error>> {
+pub async fn download_torrent<P: AsRef<Path>>(path: P, config: &ClientConfig) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let torrent_file: TorrentFile = parse_torrent(path)?;
+    info!("torrent: {}", torrent_file);
+    info!("peer id: {}", String::from_utf8_lossy(&config.peer_id));
+    torrent_file.download(config).await
+}
+
+pub async fn seed_torrent<P: AsRef<Path>>(path: P, data_dir: &Path, config: &ClientConfig) -> std::result::Result<(), Box<dyn std::error::Error>> {
     let torrent_file: TorrentFile = parse_torrent(path)?;
     info!("torrent: {}", torrent_file);
-    let response = torrent_file.retrieve_peers().await?;
-    info!("{} peers available to download {}", response.peers.len(), torrent_file.filename);
-    torrent_file.download(&response.peers).await
+    info!("peer id: {}", String::from_utf8_lossy(&config.peer_id));
+    torrent_file.seed(data_dir, config).await
+}
+
+pub async fn scrape_torrent<P: AsRef<Path>>(path: P) -> std::result::Result<Vec<(String, Result<ScrapeStats, TrackerError>)>, Box<dyn std::error::Error>> {
+    let torrent_file: TorrentFile = parse_torrent(path)?;
+    Ok(torrent_file.scrape().await)
+}
+
+pub async fn serve_tracker(config: TrackerServerConfig) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    serve(config).await.map_err(|e| e.into())
 }
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::io;
+
+use socket2::{Domain, Protocol, Socket, Type};
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::util::{from_hex, to_string};
+
+const LSD_PORT: u16 = 6771;
+const MULTICAST_V4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+const MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// repeated announces of a torrent from the same host within this long are ignored
+const MIN_PEER_INTERVAL: Duration = Duration::from_secs(60);
+// keeps each announce within a single unfragmented datagram
+const MAX_HASHES_PER_MESSAGE: usize = 20;
+const MAX_PACKET_LENGTH: usize = 1500;
+
+#[derive(Debug, Error)]
+pub enum LsdError {
+    #[error("unable to join the local discovery group {0}: {1:?}")]
+    SocketError(SocketAddr, io::Error),
+}
+
+#[derive(Debug)]
+struct Group {
+    socket: UdpSocket,
+    address: SocketAddr,
+}
+
+#[derive(Debug)]
+pub struct Lsd {
+    port: u16,
+    // identifies our own announces when multicast loops them back
+    cookie: String,
+    groups: Vec<Group>,
+    torrents: Mutex<HashMap<[u8; 20], mpsc::UnboundedSender<Vec<SocketAddr>>>>,
+    recent: Mutex<HashMap<(IpAddr, [u8; 20]), Instant>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Announce {
+    port: u16,
+    info_hashes: Vec<[u8; 20]>,
+    cookie: Option<String>,
+}
+
+fn join(group: SocketAddr) -> io::Result<UdpSocket> {
+    let domain = if group.is_ipv4() { Domain::IPV4 } else { Domain::IPV6 };
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    // other clients on this host listen on the same port
+    socket.set_reuse_address(true)?;
+    match group.ip() {
+        IpAddr::V4(ip) => {
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LSD_PORT)).into())?;
+            socket.join_multicast_v4(&ip, &Ipv4Addr::UNSPECIFIED)?;
+            socket.set_multicast_loop_v4(true)?;
+        },
+        IpAddr::V6(ip) => {
+            socket.set_only_v6(true)?;
+            socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, LSD_PORT)).into())?;
+            socket.join_multicast_v6(&ip, 0)?;
+            socket.set_multicast_loop_v6(true)?;
+        },
+    }
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+impl Announce {
+    fn encode(&self, group: SocketAddr) -> Vec<u8> {
+        let mut text = format!("BT-SEARCH * HTTP/1.1\r\nHost: {group}\r\nPort: {}\r\n", self.port);
+        for hash in &self.info_hashes {
+            text.push_str(&format!("Infohash: {}\r\n", to_string(hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            text.push_str(&format!("cookie: {cookie}\r\n"));
+        }
+        text.push_str("\r\n\r\n");
+        text.into_bytes()
+    }
+
+    fn decode(packet: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(packet).ok()?;
+        let mut lines = text.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None
+        }
+
+        let mut port: Option<u16> = None;
+        let mut info_hashes: Vec<[u8; 20]> = Vec::new();
+        let mut cookie: Option<String> = None;
+        for line in lines.take_while(|l| !l.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                continue
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse().ok(),
+                "infohash" => {
+                    if let Some(hash) = from_hex(value).and_then(|b| <[u8; 20]>::try_from(b).ok()) {
+                        info_hashes.push(hash);
+                    }
+                },
+                "cookie" => cookie = Some(value.to_string()),
+                _ => (),
+            }
+        }
+
+        Some(Announce { port: port.filter(|p| *p != 0)?, info_hashes, cookie })
+    }
+}
+
+impl Lsd {
+    // joins the IPv4 group and, where available, the IPv6 group; `port` is where we accept peers
+    pub fn bind(port: u16) -> Result<Arc<Self>, LsdError> {
+        let v4 = SocketAddr::from((MULTICAST_V4, LSD_PORT));
+        let socket = join(v4).map_err(|e| LsdError::SocketError(v4, e))?;
+        let mut groups = vec![Group { socket, address: v4 }];
+
+        let v6 = SocketAddr::from((MULTICAST_V6, LSD_PORT));
+        match join(v6) {
+            Ok(socket) => groups.push(Group { socket, address: v6 }),
+            Err(e) => info!("local discovery is IPv4 only: {:?}", e),
+        }
+
+        Ok(Arc::new(Lsd {
+            port,
+            cookie: format!("{:08x}", rand::random::<u32>()),
+            groups,
+            torrents: Mutex::new(HashMap::new()),
+            recent: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    // peers announcing `info_hash` are sent to `peers`
+    pub fn add(&self, info_hash: [u8; 20], peers: mpsc::UnboundedSender<Vec<SocketAddr>>) {
+        self.torrents.lock().unwrap().insert(info_hash, peers);
+    }
+
+    pub fn remove(&self, info_hash: &[u8; 20]) {
+        self.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    // listens on every group and announces all torrents every interval
+    pub async fn run(self: Arc<Self>) {
+        let mut tasks = JoinSet::new();
+        for i in 0..self.groups.len() {
+            let lsd = self.clone();
+            tasks.spawn(async move { lsd.listen(i).await });
+        }
+        loop {
+            self.announce().await;
+            sleep(ANNOUNCE_INTERVAL).await;
+        }
+    }
+
+    async fn announce(&self) {
+        let hashes: Vec<[u8; 20]> = self.torrents.lock().unwrap().keys().copied().collect();
+        for chunk in hashes.chunks(MAX_HASHES_PER_MESSAGE) {
+            let announce = Announce { port: self.port, info_hashes: chunk.to_vec(), cookie: Some(self.cookie.clone()) };
+            for group in &self.groups {
+                if let Err(e) = group.socket.send_to(&announce.encode(group.address), group.address).await {
+                    warn!("unable to announce to local discovery group {}: {:?}", group.address, e);
+                }
+            }
+        }
+    }
+
+    async fn listen(&self, index: usize) {
+        let socket = &self.groups[index].socket;
+        let mut buf = vec![0u8; MAX_PACKET_LENGTH];
+        loop {
+            match socket.recv_from(&mut buf).await {
+                Ok((n, from)) => self.handle(&buf[..n], from),
+                Err(e) => warn!("local discovery receive failed: {:?}", e),
+            }
+        }
+    }
+
+    fn handle(&self, packet: &[u8], from: SocketAddr) {
+        let Some(announce) = Announce::decode(packet) else {
+            return
+        };
+        if announce.cookie.as_ref() == Some(&self.cookie) {
+            return
+        }
+
+        let peer = SocketAddr::new(from.ip(), announce.port);
+        let torrents = self.torrents.lock().unwrap();
+        let mut recent = self.recent.lock().unwrap();
+        recent.retain(|_, seen| seen.elapsed() < MIN_PEER_INTERVAL);
+        for hash in announce.info_hashes {
+            let Some(peers) = torrents.get(&hash) else {
+                continue
+            };
+            if recent.contains_key(&(from.ip(), hash)) {
+                continue
+            }
+            recent.insert((from.ip(), hash), Instant::now());
+            info!("local peer {} announced {}", peer, to_string(&hash));
+            let _ = peers.send(vec![peer]);
+        }
+    }
+}
@@ -1,23 +1,153 @@
-use clap::Parser;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
 use tracing_subscriber::fmt::time::LocalTime;
 use tracing_appender::non_blocking;
 use time::macros::format_description;
 
-use torrent::{parse_torrent, download_torrent};
+use torrent::{parse_torrent, download_torrent, seed_torrent, scrape_torrent, serve_tracker, ClientConfig, ClientFilter, EncryptionPolicy, TrackerServerConfig, DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_TORRENT_CONNECTIONS};
 
 #[derive(Parser, Debug)]
-#[command(name="torrentium", version)]
+#[command(name="torrentium", version, subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Args {
     #[arg(short, long, help="Print contents of torrent file")]
     inspect: bool,
 
-    file: String,
+    #[arg(required = true)]
+    file: Option<String>,
+
+    #[command(flatten)]
+    client: ClientArgs,
+
+    #[arg(long, help="Exit once the download completes instead of seeding")]
+    no_seed: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ClientArgs {
+    #[arg(short, long, default_value_t = 6881, help="Port to accept peer connections on")]
+    port: u16,
+
+    #[arg(long, help="Number of peers to request from trackers")]
+    numwant: Option<u32>,
+
+    #[arg(long, help="IP address to report to trackers")]
+    ip: Option<IpAddr>,
+
+    #[arg(long, help="Ask trackers to omit peer ids from non-compact peer lists")]
+    no_peer_id: bool,
+
+    #[arg(long, help="Do not look for peers on the local network")]
+    no_lsd: bool,
+
+    #[arg(long, help="Do not look for peers on the DHT")]
+    no_dht: bool,
+
+    #[arg(long, help="Join the DHT through this host:port instead of the default routers")]
+    dht_node: Vec<String>,
+
+    #[arg(long, help="Keep the DHT routing table in this file between runs")]
+    dht_state: Option<PathBuf>,
+
+    #[arg(long, default_value_t = 4, help="Number of peers to upload to at once")]
+    upload_slots: usize,
+
+    #[arg(long, default_value = "prefer", help="Peer connection encryption: plaintext, prefer or require")]
+    encryption: EncryptionPolicy,
+
+    #[arg(long, help="Only connect to peers over TCP")]
+    no_utp: bool,
+
+    #[arg(long, help="Only exchange pieces with peers whose client name contains this")]
+    allow_client: Vec<String>,
+
+    #[arg(long, help="Refuse peers whose client name contains this")]
+    deny_client: Vec<String>,
+
+    #[arg(long, default_value_t = DEFAULT_MAX_CONNECTIONS, help="Most peer connections open at once across all torrents")]
+    max_connections: usize,
+
+    #[arg(long, default_value_t = DEFAULT_MAX_TORRENT_CONNECTIONS, help="Most peer connections open at once for one torrent")]
+    max_torrent_connections: usize,
+
+    #[arg(long, help="Keep the list of banned peers in this file between runs")]
+    ban_list: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[command(about="Query every tracker of a torrent file for seeder and leecher counts")]
+    Scrape {
+        file: String,
+    },
+    #[command(about="Serve a torrent's files from an existing directory")]
+    Seed {
+        file: String,
+        data_dir: PathBuf,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    #[command(about="Run a tracker")]
+    Tracker {
+        #[command(subcommand)]
+        command: TrackerCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum TrackerCommand {
+    #[command(about="Serve HTTP announce and scrape requests")]
+    Serve {
+        #[arg(short, long, default_value_t = 6969, help="Port to listen on")]
+        port: u16,
+        #[arg(short, long, default_value = "0.0.0.0", help="Address to listen on")]
+        bind: IpAddr,
+        #[arg(long, default_value_t = 1800, value_parser = clap::value_parser!(u64).range(1..), help="Seconds between announces requested of peers")]
+        interval: u64,
+        #[arg(short, long, help="Only track the torrents in these files")]
+        whitelist: Vec<PathBuf>,
+        #[arg(short, long, help="Persist swarm state to this file")]
+        state_file: Option<PathBuf>,
+    },
+}
+
+impl ClientArgs {
+    fn into_config(self) -> ClientConfig {
+        let mut config = ClientConfig {
+            port: self.port,
+            numwant: self.numwant,
+            ip: self.ip,
+            no_peer_id: self.no_peer_id,
+            dht: !self.no_dht,
+            lsd: !self.no_lsd,
+            upload_slots: self.upload_slots,
+            encryption: self.encryption,
+            utp: !self.no_utp,
+            client_filter: ClientFilter { allow: self.allow_client, deny: self.deny_client },
+            max_connections: self.max_connections,
+            max_torrent_connections: self.max_torrent_connections,
+            ..ClientConfig::default()
+        };
+        if !self.dht_node.is_empty() {
+            config.dht_nodes = self.dht_node;
+        }
+        if self.dht_state.is_some() {
+            config.dht_state = self.dht_state;
+        }
+        if self.ban_list.is_some() {
+            config.ban_list = self.ban_list;
+        }
+        config
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    let filename = args.file;
 
     let file_appender = tracing_appender::rolling::never("logs", "torrent.log");
     let (non_blocking, _guard) = non_blocking(file_appender);
@@ -26,15 +156,50 @@ async fn main() {
     tracing_subscriber::fmt().with_writer(non_blocking).with_ansi(false).with_timer(timer).init();
     //tracing_subscriber::fmt().with_timer(timer).init();
 
-    if args.inspect {
-        match parse_torrent(&filename) {
-            Ok(torrent) => println!("Contents of {}:\n{}", &filename, torrent),
-            Err(e) => println!("Unable to parse file: {e:?}"),
-        }
-    } else {
-        match download_torrent(&filename).await {
-            Ok(()) => println!("Successfully downloaded file(s) from {}!", &filename),
-            Err(e) => println!("{e:?}"),
-        }
+    match args.command {
+        Some(Command::Scrape { file }) => {
+            match scrape_torrent(&file).await {
+                Ok(results) => {
+                    for (tracker, result) in results {
+                        match result {
+                            Ok(stats) => println!("{tracker}: {stats}"),
+                            Err(e) => println!("{tracker}: {e}"),
+                        }
+                    }
+                },
+                Err(e) => println!("Unable to parse file: {e:?}"),
+            }
+        },
+        Some(Command::Seed { file, data_dir, client }) => {
+            if let Err(e) = seed_torrent(&file, &data_dir, &client.into_config()).await {
+                println!("{e:?}");
+            }
+        },
+        Some(Command::Tracker { command: TrackerCommand::Serve { port, bind, interval, whitelist, state_file } }) => {
+            let config = TrackerServerConfig {
+                address: SocketAddr::new(bind, port),
+                interval,
+                whitelist,
+                state_file,
+            };
+            if let Err(e) = serve_tracker(config).await {
+                println!("{e:?}");
+            }
+        },
+        None => {
+            let filename = args.file.expect("file argument is required without a subcommand");
+            if args.inspect {
+                match parse_torrent(&filename) {
+                    Ok(torrent) => println!("Contents of {}:\n{}", &filename, torrent),
+                    Err(e) => println!("Unable to parse file: {e:?}"),
+                }
+            } else {
+                let config = ClientConfig { seed: !args.no_seed, ..args.client.into_config() };
+                match download_torrent(&filename, &config).await {
+                    Ok(()) => println!("Successfully downloaded file(s) from {}!", &filename),
+                    Err(e) => println!("{e:?}"),
+                }
+            }
+        },
     }
 }
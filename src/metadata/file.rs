@@ -1,24 +1,37 @@
 use std::collections::BTreeMap;
-use std::net::SocketAddrV4;
+use std::net::SocketAddr;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{fmt, fs};
 
 use url::Url;
-use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
 use thiserror::Error;
+use tracing::{info, warn};
 use time::{OffsetDateTime, format_description::well_known::Rfc3339};
-
-use crate::PEER_ID;
-use crate::peer::download;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::timeout;
+
+use crate::config::ClientConfig;
+use crate::dht::{Dht, load_state, resolve};
+use crate::lsd::Lsd;
+use crate::peer::{PeerError, Swarm};
+use crate::peer::utp::UtpSocket;
+use crate::peer::downloader::{FileDownloadInfo, FileDownloadState};
 use crate::util::sha1::sha1_hash;
-use crate::util::io::reconstitute_files_from_torrent;
-use crate::metadata::tracker::{TrackerError, TrackerResponse, retrieve_peers};
+use crate::util::io::{PieceSource, reconstitute_files_from_torrent, remove_piece_files};
+use crate::metadata::tracker::{AnnounceEvent, AnnounceRequest, ScrapeStats, TrackerError, scrape};
+use crate::metadata::tracker::announcer::Announcer;
+use crate::metadata::tracker::tiers::TrackerTiers;
 use crate::metadata::bencode::{BencodeError, BencodeValue};
 
 #[derive(Debug, Clone)]
 pub struct TorrentFile {
-    announce: String,
+    announce: Option<String>,
     announce_list: Vec<Vec<String>>,
+    // `host:port` of DHT nodes, for trackerless torrents
+    nodes: Vec<String>,
     creation_date: Option<u64>,
     comment: Option<String>,
     created_by: Option<String>,
@@ -31,6 +44,8 @@ pub struct TorrentFile {
     pub num_pieces: usize,
     pub piece_hashes: Vec<[u8; 20]>,
     pub hash: [u8; 20],
+    // length of the b-encoded info dictionary
+    pub metadata_size: u64,
 
     pub filename: String,
 }
@@ -86,12 +101,18 @@ pub enum TorrentFileError {
     InvalidString(Vec<u8>),
     #[error("unable to parse `announce` URL '{0}'")]
     InvalidAnnounceUrl(String),
+    #[error("`nodes` expected to map to a list of [host, port] pairs")]
+    InvalidNodesElement,
     #[error("file length totals {0} do not align with piece totals {1}")]
     LengthMismatch(u64, u64),
 }
 
 type Result<T> = std::result::Result<T, TorrentFileError>;
 
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(30);
+// the `stopped` announce is best effort, so a shutdown waits no longer than this for it
+const STOPPED_TIMEOUT: Duration = Duration::from_secs(5);
+
 impl fmt::Display for FileModeInfo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -114,12 +135,17 @@ impl fmt::Display for FileModeInfo {
 
 impl fmt::Display for TorrentFile {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "announce: {}", self.announce)?;
+        if let Some(url) = &self.announce {
+            writeln!(f, "announce: {url}")?;
+        }
         writeln!(f, "announce list: [{}]", self.announce_list
             .iter()
             .map(|v| format!("[{}]", v.join(", ")))
             .collect::<Vec<_>>()
             .join(", "))?;
+        if !self.nodes.is_empty() {
+            writeln!(f, "nodes: [{}]", self.nodes.join(", "))?;
+        }
         if let Some(seconds) = &self.creation_date {
             let created_str = OffsetDateTime::from_unix_timestamp(*seconds as i64)
                 .ok()
@@ -148,6 +174,7 @@ impl fmt::Display for TorrentFile {
 
 const ANNOUNCE: &[u8] = b"announce";
 const ANNOUNCE_LIST: &[u8] = b"announce-list";
+const NODES: &[u8] = b"nodes";
 const CREATION_DATE: &[u8] = b"creation date";
 const COMMENT: &[u8] = b"comment";
 const CREATED_BY: &[u8] = b"created by";
@@ -187,24 +214,125 @@ impl TorrentFile {
         }
     }
 
-    pub async fn retrieve_peers(&self) -> std::result::Result<TrackerResponse, TrackerError> {
-        let url = self.get_announce_url(self.total_num_bytes, PEER_ID, 12345);
-        retrieve_peers(url).await
+    pub fn trackers(&self) -> TrackerTiers {
+        TrackerTiers::new(self.announce.as_deref(), &self.announce_list)
+    }
+
+    pub fn tracker_urls(&self) -> Vec<String> {
+        let mut urls: Vec<String> = self.announce.iter().cloned().collect();
+        for url in self.announce_list.iter().flatten() {
+            if !urls.contains(url) {
+                urls.push(url.clone());
+            }
+        }
+        urls
+    }
+
+    pub async fn scrape(&self) -> Vec<(String, std::result::Result<ScrapeStats, TrackerError>)> {
+        let mut tasks = JoinSet::new();
+        for (i, url_str) in self.tracker_urls().into_iter().enumerate() {
+            let hash = self.hash;
+            tasks.spawn(async move {
+                let result = match Url::parse(&url_str) {
+                    Ok(url) => timeout(SCRAPE_TIMEOUT, scrape(&url, &[hash]))
+                        .await
+                        .unwrap_or(Err(TrackerError::ResponseTimeout(SCRAPE_TIMEOUT.as_secs())))
+                        .and_then(|mut stats| stats.remove(&hash).ok_or(TrackerError::MalformedScrapeFiles)),
+                    Err(e) => Err(TrackerError::InvalidUrl(url_str.clone(), e)),
+                };
+                (i, url_str, result)
+            });
+        }
+
+        let mut results = tasks.join_all().await;
+        results.sort_by_key(|(i, _, _)| *i);
+        results.into_iter().map(|(_, url, result)| (url, result)).collect()
     }
 
-    pub async fn download(&self, peers: &[SocketAddrV4]) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    pub async fn download(&self, config: &ClientConfig) -> std::result::Result<(), Box<dyn std::error::Error>> {
         let dir = tempfile::TempDir::new().expect("should be able to construct temporary directory");
         let dir_path = dir.path();
 
-        download(peers, self, dir_path).await?;
+        let source = PieceSource::Pieces(dir_path.to_path_buf());
+        let state = Arc::new(Mutex::new(FileDownloadState::new(self.num_pieces, self.total_num_bytes, source)));
+        self.share(config, dir_path, state).await
+    }
+
+    // serves the torrent's files found under `data_dir` until interrupted
+    pub async fn seed(&self, data_dir: &Path, config: &ClientConfig) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let info = FileDownloadInfo::new(self, config);
+        let state = FileDownloadState::from_existing(&info, PieceSource::files(self, data_dir)).await;
+        if state.num_complete() == 0 {
+            return Err(PeerError::NoDataToSeed(data_dir.display().to_string()).into())
+        }
+        info!("seeding {}/{} pieces of {}", state.num_complete(), self.num_pieces, self.filename);
+        self.share(config, data_dir, Arc::new(Mutex::new(state))).await
+    }
+
+    // downloads whatever pieces are missing, then seeds if configured to
+    async fn share(&self, config: &ClientConfig, dir_path: &Path, state: Arc<Mutex<FileDownloadState>>)
+        -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let (peers_tx, mut peers_rx) = mpsc::unbounded_channel();
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let downloading = !state.lock().await.todo_is_empty();
+        let mut swarm = Swarm::new(self, config, dir_path, state.clone(), peers_tx.clone()).await?;
+        let dht = self.start_dht(config, peers_tx.clone(), swarm.utp()).await;
+        let lsd = self.start_lsd(config, peers_tx.clone());
+        let announcer = Announcer::new(self.trackers(), self.announce_request(config), state.clone());
+        let mut announcer_task = tokio::spawn(announcer.run(events_rx, peers_tx.clone()));
+
+        let mut result: std::result::Result<(), Box<dyn std::error::Error>> = Ok(());
+        if downloading {
+            result = tokio::select! {
+                result = swarm.download(&mut peers_rx, &events_tx) => result,
+                _ = tokio::signal::ctrl_c() => Err(PeerError::Interrupted),
+            }.map_err(|e| e.into());
+            if result.is_ok() {
+                let _ = events_tx.send(AnnounceEvent::Completed);
+                // peers are served from the reconstituted files from here on
+                result = reconstitute_files_from_torrent(self, dir_path).map_err(|e| e.into());
+                if result.is_ok() {
+                    swarm.set_source(PieceSource::files(self, Path::new(""))).await;
+                    if let Err(e) = remove_piece_files(self, dir_path) {
+                        warn!("unable to remove piece files: {}", e);
+                    }
+                }
+            }
+        }
+        if result.is_ok() && (config.seed || !downloading) {
+            tokio::select! {
+                _ = swarm.seed(&mut peers_rx) => (),
+                _ = tokio::signal::ctrl_c() => info!("seeding stopped"),
+            }
+        }
 
-        reconstitute_files_from_torrent(self, dir_path).map_err(|e| e.into())
+        let _ = events_tx.send(AnnounceEvent::Stopped);
+        if let Some((lsd, task)) = lsd {
+            lsd.remove(&self.hash);
+            task.abort();
+        }
+        if let Some((dht, search)) = dht {
+            search.abort();
+            dht.shutdown();
+            if let Some(path) = &config.dht_state
+                && let Err(e) = dht.save(path) {
+                warn!("{}", e);
+            }
+        }
+        if timeout(STOPPED_TIMEOUT, &mut announcer_task).await.is_err() {
+            warn!("trackers did not acknowledge stopping within {} seconds", STOPPED_TIMEOUT.as_secs());
+            announcer_task.abort();
+        }
+        result
     }
 
     fn extract(filename: &str, items: &BTreeMap<Vec<u8>, BencodeValue>) -> Result<Self> {
-        let announce = Self::extract_string(items.get(ANNOUNCE), "announce", true)?.unwrap();
-        let _ = Url::parse(&announce).map_err(|_| TorrentFileError::InvalidAnnounceUrl(announce.to_string()))?;
+        let announce = Self::extract_string(items.get(ANNOUNCE), "announce", false)?;
+        if let Some(url) = &announce {
+            let _ = Url::parse(url).map_err(|_| TorrentFileError::InvalidAnnounceUrl(url.to_string()))?;
+        }
         let announce_list = Self::extract_announce_list(items.get(ANNOUNCE_LIST))?;
+        let nodes = Self::extract_nodes(items.get(NODES))?;
         let creation_date = Self::extract_uint(items.get(CREATION_DATE), "creation date", false)?;
         let comment = Self::extract_string(items.get(COMMENT), "comment", false)?;
         let created_by = Self::extract_string(items.get(CREATED_BY), "created by", false)?;
@@ -222,7 +350,7 @@ impl TorrentFile {
         let private_int = Self::extract_uint(info_items.get(PRIVATE), "private", false)?;
         let private = if let Some(v) = private_int {
             if v <= 1 {
-                v == 0
+                v == 1
             } else {
                 return Err(TorrentFileError::InvalidPrivateValue(v));
             }
@@ -231,7 +359,8 @@ impl TorrentFile {
         };
         let piece_hashes = Self::extract_pieces(info_items.get(PIECES))?;
         let num_pieces = piece_hashes.len();
-        let hash = sha1_hash(Vec::from(items.get(INFO).unwrap()).as_slice());
+        let info_bytes = Vec::from(items.get(INFO).unwrap());
+        let hash = sha1_hash(&info_bytes);
         let name = Self::extract_string(info_items.get(NAME), "name", true)?.unwrap();
         let (info, total_num_bytes) = if info_items.contains_key(FILES) {
             let mut files = Vec::new();
@@ -276,6 +405,7 @@ impl TorrentFile {
         Ok(TorrentFile {
             announce,
             announce_list,
+            nodes,
             creation_date,
             comment,
             created_by,
@@ -286,6 +416,7 @@ impl TorrentFile {
             num_pieces,
             piece_hashes,
             hash,
+            metadata_size: info_bytes.len() as u64,
             private,
             filename: filename.to_owned()
         })
@@ -401,6 +532,34 @@ impl TorrentFile {
         Ok(announce_list)
     }
 
+    fn extract_nodes(value: Option<&BencodeValue>) -> Result<Vec<String>> {
+        let mut nodes = Vec::new();
+        match value {
+            Some(BencodeValue::List(elements)) => {
+                for element in elements {
+                    match element {
+                        BencodeValue::List(pair) => match pair.as_slice() {
+                            [host @ BencodeValue::ByteString(_), BencodeValue::Integer(port)] => {
+                                let host = Self::convert_string(host).ok_or(TorrentFileError::InvalidNodesElement)?;
+                                let port = u16::try_from(*port).map_err(|_| TorrentFileError::InvalidNodesElement)?;
+                                if host.contains(':') {
+                                    nodes.push(format!("[{host}]:{port}"));
+                                } else {
+                                    nodes.push(format!("{host}:{port}"));
+                                }
+                            },
+                            _ => return Err(TorrentFileError::InvalidNodesElement),
+                        },
+                        _ => return Err(TorrentFileError::InvalidNodesElement),
+                    }
+                }
+            },
+            Some(_) => return Err(TorrentFileError::KeyDoesNotMapToList("nodes")),
+            None => (),
+        }
+        Ok(nodes)
+    }
+
     fn extract_pieces(value: Option<&BencodeValue>) -> Result<Vec<[u8; 20]>> {
         match value {
             Some(v) => {
@@ -423,21 +582,81 @@ impl TorrentFile {
         }
     }
 
-    fn get_announce_url(&self, length: u64, peer_id: &[u8;20], port: u16) -> Url {
-        let mut url = Url::parse(&self.announce).expect("announce URL verified on parse");
+    // the DHT shares uTP's socket when there is one, since both use the client's port
+    async fn start_dht(&self, config: &ClientConfig, peers: mpsc::UnboundedSender<Vec<SocketAddr>>, utp: Option<Arc<UtpSocket>>)
+        -> Option<(Arc<Dht>, AbortHandle)> {
+        if !config.dht || !self.shares_peers() {
+            return None
+        }
+
+        let (id, mut bootstrap) = match config.dht_state.as_ref().map(load_state) {
+            Some(Ok((id, nodes))) => (Some(id), nodes.into_iter().map(|n| SocketAddr::V4(n.address)).collect()),
+            Some(Err(e)) => {
+                info!("starting with an empty DHT routing table: {}", e);
+                (None, Vec::new())
+            },
+            None => (None, Vec::new()),
+        };
+        bootstrap.extend(resolve(&self.nodes).await);
+        bootstrap.extend(resolve(&config.dht_nodes).await);
+
+        let dht = match utp {
+            Some(utp) => {
+                let (socket, datagrams) = utp.share();
+                Ok(Dht::attach(socket, datagrams, id))
+            },
+            None => Dht::bind(SocketAddr::from(([0, 0, 0, 0], config.port)), id).await,
+        };
+        match dht {
+            Ok(dht) => {
+                let search = tokio::spawn(dht.clone().search(bootstrap, self.hash, config.port, peers));
+                Some((dht, search.abort_handle()))
+            },
+            Err(e) => {
+                warn!("unable to start DHT: {}", e);
+                None
+            },
+        }
+    }
 
-        let encoded_hash = percent_encode(self.hash.as_slice(), NON_ALPHANUMERIC).to_string();
-        let encoded_id = percent_encode(peer_id, NON_ALPHANUMERIC).to_string();
+    // private torrents only get peers from their trackers, never from the DHT, local discovery or peer exchange
+    pub fn shares_peers(&self) -> bool {
+        !self.private
+    }
 
-        url.query_pairs_mut()
-            .append_pair("port", &port.to_string())
-            .append_pair("uploaded", "0")
-            .append_pair("downloaded", "0")
-            .append_pair("compact", "1")
-            .append_pair("left", &length.to_string());
+    fn start_lsd(&self, config: &ClientConfig, peers: mpsc::UnboundedSender<Vec<SocketAddr>>)
+        -> Option<(Arc<Lsd>, AbortHandle)> {
+        if !config.lsd || !self.shares_peers() {
+            return None
+        }
+        match Lsd::bind(config.port) {
+            Ok(lsd) => {
+                lsd.add(self.hash, peers);
+                let task = tokio::spawn(lsd.clone().run());
+                Some((lsd, task.abort_handle()))
+            },
+            Err(e) => {
+                warn!("unable to start local service discovery: {}", e);
+                None
+            },
+        }
+    }
 
-        let new_url_str = format!("{url}&info_hash={encoded_hash}&peer_id={encoded_id}");
-        Url::parse(&new_url_str).expect("internally formed URL expected to be valid")
+    pub fn announce_request(&self, config: &ClientConfig) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: self.hash,
+            peer_id: config.peer_id,
+            port: config.port,
+            uploaded: 0,
+            downloaded: 0,
+            left: self.total_num_bytes,
+            event: AnnounceEvent::None,
+            numwant: config.numwant,
+            key: config.key,
+            ip: config.ip,
+            tracker_id: None,
+            no_peer_id: config.no_peer_id,
+        }
     }
 }
 
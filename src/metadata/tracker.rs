@@ -1,18 +1,65 @@
+pub mod announcer;
+pub mod server;
+pub mod tiers;
+pub mod udp;
+
 use core::fmt;
+use std::collections::{BTreeMap, HashMap};
 use std::result::Result;
-use std::net::{SocketAddrV4};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
 use reqwest::get;
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
 use thiserror::Error;
+use tracing::warn;
 use url::Url;
 
 use crate::metadata::file::{TorrentFileError, TorrentFile};
-use crate::metadata::bencode::{BencodeValue, BencodeError};
+use crate::metadata::bencode::{BencodeValue, BencodeError, write_byte_string};
 
 #[derive(Debug, Clone)]
 pub struct TrackerResponse {
     pub interval: u64,
-    pub peers: Vec<SocketAddrV4>,
+    pub min_interval: Option<u64>,
+    pub warning: Option<String>,
+    pub tracker_id: Option<Vec<u8>>,
+    pub complete: Option<u64>,
+    pub incomplete: Option<u64>,
+    pub peers: Vec<SocketAddr>,
+    pub peer_ids: HashMap<SocketAddr, [u8; 20]>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: AnnounceEvent,
+    pub numwant: Option<u32>,
+    pub key: u32,
+    pub ip: Option<IpAddr>,
+    pub tracker_id: Option<Vec<u8>>,
+    pub no_peer_id: bool,
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AnnounceEvent {
+    #[default]
+    None      = 0,
+    Completed = 1,
+    Started   = 2,
+    Stopped   = 3,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32,
 }
 
 #[derive(Debug, Error)]
@@ -21,53 +68,155 @@ pub enum TrackerError {
     NonBencodedTrackerResponse(BencodeError),
     #[error("tracker response is not a bencoded dictionary")]
     TrackerResponseNotADictionary,
-    #[error("peers list byte length ({0}) is not a multiple of 6")]
-    IllegalPeersLength(usize),
+    #[error("peers list byte length ({0}) is not a multiple of {1}")]
+    IllegalPeersLength(usize, usize),
     #[error("tracker response missing interval key")]
     MissingInterval,
     #[error("tracker response interval malformed: {0:?}")]
     MalformedInterval(TorrentFileError),
     #[error("tracker repsonse missing peers key")]
     MissingPeers,
-    #[error("tracker response peers list is not a byte string")]
+    #[error("tracker response peers list is neither a byte string nor a list of dictionaries")]
     MalformedPeersList,
+    #[error("tracker response peer entry is malformed: {0}")]
+    MalformedPeerEntry(&'static str),
+    #[error("tracker response `{0}` key is malformed: {1:?}")]
+    MalformedKey(&'static str, TorrentFileError),
     #[error("no response received from tracker: {0:?}")]
     NoTrackerResponse(reqwest::Error),
     #[error("tracker response contains no body: {0:?}")]
     NoTrackerResponseBody(reqwest::Error),
+    #[error("tracker reported failure: {0}")]
+    TrackerFailure(String),
+
+    #[error("tracker did not respond within {0} seconds")]
+    ResponseTimeout(u64),
+    #[error("torrent does not list any usable trackers")]
+    NoTrackers,
+
+    #[error("scrape URL cannot be derived from announce URL '{0}'")]
+    ScrapeUnsupported(String),
+    #[error("tracker scrape response `files` key is malformed")]
+    MalformedScrapeFiles,
+
+    #[error("unable to parse tracker URL '{0}': {1}")]
+    InvalidUrl(String, url::ParseError),
+    #[error("tracker URL scheme `{0}` is not supported")]
+    UnsupportedScheme(String),
+    #[error("tracker URL '{0}' does not specify a host and port")]
+    MissingHostOrPort(String),
+    #[error("unable to resolve tracker host {0}: {1:?}")]
+    UnresolvableHost(String, Option<std::io::Error>),
+    #[error("UDP tracker socket error: {0:?}")]
+    UdpSocketError(std::io::Error),
+    #[error("UDP tracker did not respond after {0} attempts")]
+    UdpTimeout(u32),
+    #[error("UDP tracker response for action {0} is too short ({1} bytes)")]
+    UdpResponseTooShort(u32, usize),
+    #[error("UDP tracker responded with action {1} to a request for action {0}")]
+    UdpUnexpectedAction(u32, u32),
 }
 
+const FAILURE_REASON: &[u8] = b"failure reason";
+const WARNING_MESSAGE: &[u8] = b"warning message";
 const INTERVAL: &[u8] = b"interval";
+const MIN_INTERVAL: &[u8] = b"min interval";
+const TRACKER_ID: &[u8] = b"tracker id";
+const COMPLETE: &[u8] = b"complete";
+const INCOMPLETE: &[u8] = b"incomplete";
 const PEERS: &[u8] = b"peers";
+const PEERS6: &[u8] = b"peers6";
+const PEER_ID: &[u8] = b"peer id";
+const FILES: &[u8] = b"files";
+const DOWNLOADED: &[u8] = b"downloaded";
+const IP: &[u8] = b"ip";
+const PORT: &[u8] = b"port";
+
+pub(crate) fn parse_compact_peers(bytes: &[u8]) -> Result<Vec<SocketAddr>, TrackerError> {
+    if !bytes.len().is_multiple_of(6) {
+        return Err(TrackerError::IllegalPeersLength(bytes.len(), 6));
+    }
+    let count: usize = bytes.len() / 6;
+    let mut v: Vec<SocketAddr> = Vec::with_capacity(count);
+    for i in 0..count {
+        let start = i*6;
+        let end_ip = i*6 + 4;
+        let ip: [u8; 4] = bytes[start..end_ip]
+            .try_into()
+            .expect("slice expected to be length 4");
+        let port_bytes: [u8; 2] = bytes[end_ip..end_ip+2]
+            .try_into()
+            .expect("slice expected to be length 2");
+        let port: u16 = u16::from_be_bytes(port_bytes);
+        v.push(SocketAddrV4::new(ip.into(), port).into());
+    }
+    Ok(v)
+}
+
+pub(crate) fn parse_compact_peers6(bytes: &[u8]) -> Result<Vec<SocketAddr>, TrackerError> {
+    if !bytes.len().is_multiple_of(18) {
+        return Err(TrackerError::IllegalPeersLength(bytes.len(), 18));
+    }
+    let mut v: Vec<SocketAddr> = Vec::with_capacity(bytes.len() / 18);
+    for chunk in bytes.chunks_exact(18) {
+        let ip: [u8; 16] = chunk[0..16].try_into().expect("chunk expected to be length 18");
+        let port: u16 = u16::from_be_bytes([chunk[16], chunk[17]]);
+        v.push(SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0).into());
+    }
+    Ok(v)
+}
+
+fn extract_dictionary_peer(items: &BTreeMap<Vec<u8>, BencodeValue>) -> Result<(SocketAddr, Option<[u8; 20]>), TrackerError> {
+    let ip: IpAddr = match items.get(IP) {
+        Some(BencodeValue::ByteString(bytes)) => std::str::from_utf8(bytes)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or(TrackerError::MalformedPeerEntry("`ip` is not an IP address"))?,
+        _ => return Err(TrackerError::MalformedPeerEntry("missing `ip`")),
+    };
+    let port: u16 = match items.get(PORT) {
+        Some(BencodeValue::Integer(port)) => u16::try_from(*port)
+            .map_err(|_| TrackerError::MalformedPeerEntry("`port` is out of range"))?,
+        _ => return Err(TrackerError::MalformedPeerEntry("missing `port`")),
+    };
+    let peer_id: Option<[u8; 20]> = match items.get(PEER_ID) {
+        Some(BencodeValue::ByteString(bytes)) => bytes.as_slice().try_into().ok(),
+        _ => None,
+    };
+    Ok((SocketAddr::new(ip, port), peer_id))
+}
 
-fn extract_peers(value: Option<&BencodeValue>) -> Result<Vec<SocketAddrV4>, TrackerError> {
+fn extract_peers(value: Option<&BencodeValue>, peer_ids: &mut HashMap<SocketAddr, [u8; 20]>) -> Result<Vec<SocketAddr>, TrackerError> {
     match value {
-        Some(bencoded_value) => {
-            match bencoded_value {
-                BencodeValue::ByteString(bytes) => {
-                    if bytes.len() % 6 != 0 {
-                        return Err(TrackerError::IllegalPeersLength(bytes.len()));
-                    }
-                    let count: usize = bytes.len() / 6;
-                    let mut v: Vec<SocketAddrV4> = Vec::with_capacity(count);
-                    for i in 0..count {
-                        let start = i*6;
-                        let end_ip = i*6 + 4;
-                        let ip: [u8; 4] = bytes[start..end_ip]
-                            .try_into()
-                            .expect("slice expected to be length 4");
-                        let port_bytes: [u8; 2] = bytes[end_ip..end_ip+2]
-                            .try_into()
-                            .expect("slice expected to be length 2");
-                        let port: u16 = u16::from_be_bytes(port_bytes);
-                        v.push(SocketAddrV4::new(ip.into(), port));
-                    }
-                    Ok(v)
-                },
-                _ => Err(TrackerError::MalformedPeersList),
+        Some(BencodeValue::ByteString(bytes)) => parse_compact_peers(bytes),
+        Some(BencodeValue::List(elements)) => {
+            let mut peers: Vec<SocketAddr> = Vec::with_capacity(elements.len());
+            for element in elements {
+                let BencodeValue::Dictionary(items) = element else {
+                    return Err(TrackerError::MalformedPeersList)
+                };
+                // hostnames are permitted here but not worth a DNS lookup per peer
+                match extract_dictionary_peer(items) {
+                    Ok((address, peer_id)) => {
+                        if let Some(id) = peer_id {
+                            peer_ids.insert(address, id);
+                        }
+                        peers.push(address);
+                    },
+                    Err(e) => warn!("skipping tracker peer entry: {}", e),
+                }
             }
+            Ok(peers)
         },
-        None => Err(TrackerError::MissingPeers),
+        Some(_) => Err(TrackerError::MalformedPeersList),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn extract_text(value: Option<&BencodeValue>) -> Option<String> {
+    match value {
+        Some(BencodeValue::ByteString(bytes)) => Some(String::from_utf8_lossy(bytes).into_owned()),
+        _ => None,
     }
 }
 
@@ -77,6 +226,10 @@ impl TryFrom<&BencodeValue> for TrackerResponse {
     fn try_from(value: &BencodeValue) -> Result<Self, TrackerError> {
         match value {
             BencodeValue::Dictionary(items) => {
+                if let Some(reason) = extract_text(items.get(FAILURE_REASON)) {
+                    return Err(TrackerError::TrackerFailure(reason));
+                }
+                let warning = extract_text(items.get(WARNING_MESSAGE));
                 let interval: u64 = TorrentFile::extract_uint(items.get(INTERVAL), "interval", true)
                     .map_err(|e| {
                         match e {
@@ -84,8 +237,29 @@ impl TryFrom<&BencodeValue> for TrackerResponse {
                             _ => TrackerError::MalformedInterval(e),
                         }
                     })?.unwrap();
-                let peers = extract_peers(items.get(PEERS))?;
-                Ok(TrackerResponse { interval, peers })
+                let min_interval: Option<u64> = TorrentFile::extract_uint(items.get(MIN_INTERVAL), "min interval", false)
+                    .map_err(TrackerError::MalformedInterval)?;
+                let tracker_id: Option<Vec<u8>> = match items.get(TRACKER_ID) {
+                    Some(BencodeValue::ByteString(bytes)) => Some(bytes.clone()),
+                    _ => None,
+                };
+                let complete = TorrentFile::extract_uint(items.get(COMPLETE), "complete", false)
+                    .map_err(|e| TrackerError::MalformedKey("complete", e))?;
+                let incomplete = TorrentFile::extract_uint(items.get(INCOMPLETE), "incomplete", false)
+                    .map_err(|e| TrackerError::MalformedKey("incomplete", e))?;
+
+                if !items.contains_key(PEERS) && !items.contains_key(PEERS6) {
+                    return Err(TrackerError::MissingPeers);
+                }
+                let mut peer_ids = HashMap::new();
+                let mut peers = extract_peers(items.get(PEERS), &mut peer_ids)?;
+                match items.get(PEERS6) {
+                    Some(BencodeValue::ByteString(bytes)) => peers.extend(parse_compact_peers6(bytes)?),
+                    Some(_) => return Err(TrackerError::MalformedPeersList),
+                    None => (),
+                }
+
+                Ok(TrackerResponse { interval, min_interval, warning, tracker_id, complete, incomplete, peers, peer_ids })
             },
             _ => Err(TrackerError::TrackerResponseNotADictionary),
         }
@@ -95,14 +269,156 @@ impl TryFrom<&BencodeValue> for TrackerResponse {
 impl fmt::Display for TrackerResponse {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "Interval (s): {}", self.interval)?;
+        if let Some(min_interval) = self.min_interval {
+            writeln!(f, "Min interval (s): {min_interval}")?;
+        }
+        if let Some(warning) = &self.warning {
+            writeln!(f, "Warning: {warning}")?;
+        }
+        if let Some(id) = &self.tracker_id {
+            write!(f, "Tracker id: ")?;
+            write_byte_string(id, f)?;
+            writeln!(f)?;
+        }
+        if let (Some(complete), Some(incomplete)) = (self.complete, self.incomplete) {
+            writeln!(f, "Seeders: {complete}, leechers: {incomplete}")?;
+        }
         for (i, socket) in self.peers.iter().enumerate() {
-            writeln!(f, "{i:03}: {socket}\n")?;
+            write!(f, "{i:03}: {socket}")?;
+            if let Some(id) = self.peer_ids.get(socket) {
+                write!(f, " (")?;
+                write_byte_string(id, f)?;
+                write!(f, ")")?;
+            }
+            writeln!(f)?;
         }
         Ok(())
     }
 }
 
-pub async fn retrieve_peers(url: Url) -> Result<TrackerResponse, TrackerError> {      
+impl AnnounceEvent {
+    fn as_str(&self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+}
+
+impl fmt::Display for ScrapeStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} seeders, {} leechers, {} completed", self.seeders, self.leechers, self.completed)
+    }
+}
+
+pub fn get_announce_url(url: &Url, request: &AnnounceRequest) -> Url {
+    let mut url = url.clone();
+
+    let encoded_hash = percent_encode(request.info_hash.as_slice(), NON_ALPHANUMERIC).to_string();
+    let encoded_id = percent_encode(&request.peer_id, NON_ALPHANUMERIC).to_string();
+
+    url.query_pairs_mut()
+        .append_pair("port", &request.port.to_string())
+        .append_pair("uploaded", &request.uploaded.to_string())
+        .append_pair("downloaded", &request.downloaded.to_string())
+        .append_pair("compact", "1")
+        .append_pair("left", &request.left.to_string());
+    if let Some(event) = request.event.as_str() {
+        url.query_pairs_mut().append_pair("event", event);
+    }
+    if let Some(numwant) = request.numwant {
+        url.query_pairs_mut().append_pair("numwant", &numwant.to_string());
+    }
+    url.query_pairs_mut().append_pair("key", &format!("{:08x}", request.key));
+    if let Some(ip) = request.ip {
+        url.query_pairs_mut().append_pair("ip", &ip.to_string());
+    }
+    if request.no_peer_id {
+        url.query_pairs_mut().append_pair("no_peer_id", "1");
+    }
+
+    let mut new_url_str = format!("{url}&info_hash={encoded_hash}&peer_id={encoded_id}");
+    if let Some(tracker_id) = &request.tracker_id {
+        new_url_str.push_str(&format!("&trackerid={}", percent_encode(tracker_id, NON_ALPHANUMERIC)));
+    }
+    Url::parse(&new_url_str).expect("internally formed URL expected to be valid")
+}
+
+pub async fn retrieve_peers(url: &Url, request: &AnnounceRequest) -> Result<TrackerResponse, TrackerError> {
+    match url.scheme() {
+        "http" | "https" => retrieve_http_peers(get_announce_url(url, request)).await,
+        "udp" => udp::announce(url, request).await,
+        scheme => Err(TrackerError::UnsupportedScheme(scheme.to_owned())),
+    }
+}
+
+pub async fn scrape(url: &Url, info_hashes: &[[u8; 20]]) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+    match url.scheme() {
+        "http" | "https" => scrape_http(get_scrape_url(url, info_hashes)?).await,
+        "udp" => udp::scrape(url, info_hashes).await,
+        scheme => Err(TrackerError::UnsupportedScheme(scheme.to_owned())),
+    }
+}
+
+// by convention the scrape URL replaces a final path component beginning with `announce`
+pub fn get_scrape_url(url: &Url, info_hashes: &[[u8; 20]]) -> Result<Url, TrackerError> {
+    let path = url.path();
+    let start = path.rfind('/').map(|i| i + 1).unwrap_or(0);
+    let Some(suffix) = path[start..].strip_prefix("announce") else {
+        return Err(TrackerError::ScrapeUnsupported(url.to_string()));
+    };
+    let mut scrape_url = url.clone();
+    scrape_url.set_path(&format!("{}scrape{}", &path[..start], suffix));
+
+    let encoded_hashes = info_hashes
+        .iter()
+        .map(|hash| format!("info_hash={}", percent_encode(hash, NON_ALPHANUMERIC)))
+        .collect::<Vec<_>>()
+        .join("&");
+    let separator = if scrape_url.query().is_some() { "&" } else { "?" };
+    let new_url_str = format!("{scrape_url}{separator}{encoded_hashes}");
+    Ok(Url::parse(&new_url_str).expect("internally formed URL expected to be valid"))
+}
+
+async fn scrape_http(url: Url) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+    let response = get(url).await.map_err(TrackerError::NoTrackerResponse)?;
+    let response_bytes: &[u8] = &response.bytes().await.map_err(TrackerError::NoTrackerResponseBody)?;
+
+    let bencoded_response = BencodeValue::try_from(response_bytes)
+        .map_err(TrackerError::NonBencodedTrackerResponse)?;
+
+    let BencodeValue::Dictionary(items) = &bencoded_response else {
+        return Err(TrackerError::TrackerResponseNotADictionary);
+    };
+    if let Some(reason) = extract_text(items.get(FAILURE_REASON)) {
+        return Err(TrackerError::TrackerFailure(reason));
+    }
+    let Some(BencodeValue::Dictionary(files)) = items.get(FILES) else {
+        return Err(TrackerError::MalformedScrapeFiles);
+    };
+
+    let mut stats: HashMap<[u8; 20], ScrapeStats> = HashMap::with_capacity(files.len());
+    for (hash, value) in files {
+        let (Ok(hash), BencodeValue::Dictionary(entry)) = (<[u8; 20]>::try_from(hash.as_slice()), value) else {
+            return Err(TrackerError::MalformedScrapeFiles);
+        };
+        let count = |key: &[u8], name: &'static str| -> Result<u32, TrackerError> {
+            TorrentFile::extract_uint(entry.get(key), name, false)
+                .map(|n| n.unwrap_or(0).min(u32::MAX as u64) as u32)
+                .map_err(|e| TrackerError::MalformedKey(name, e))
+        };
+        stats.insert(hash, ScrapeStats {
+            seeders: count(COMPLETE, "complete")?,
+            completed: count(DOWNLOADED, "downloaded")?,
+            leechers: count(INCOMPLETE, "incomplete")?,
+        });
+    }
+    Ok(stats)
+}
+
+async fn retrieve_http_peers(url: Url) -> Result<TrackerResponse, TrackerError> {
     let response = get(url).await.map_err(TrackerError::NoTrackerResponse)?;
     let response_bytes: &[u8] = &response.bytes().await.map_err(TrackerError::NoTrackerResponseBody)?;
 
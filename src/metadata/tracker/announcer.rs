@@ -0,0 +1,124 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{sleep_until, Instant};
+use tracing::{info, warn};
+
+use crate::metadata::tracker::{AnnounceEvent, AnnounceRequest, TrackerResponse};
+use crate::metadata::tracker::tiers::TrackerTiers;
+use crate::peer::downloader::FileDownloadState;
+
+// used until a tracker tells us otherwise
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+const INITIAL_RETRY: Duration = Duration::from_secs(15);
+// guards against trackers that answer with an interval of zero
+const MIN_INTERVAL_FLOOR: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct Announcer {
+    tiers: TrackerTiers,
+    request: AnnounceRequest,
+    state: Arc<Mutex<FileDownloadState>>,
+    interval: Duration,
+    min_interval: Duration,
+    last_announce: Option<Instant>,
+    failures: u32,
+}
+
+impl Announcer {
+    pub fn new(tiers: TrackerTiers, request: AnnounceRequest, state: Arc<Mutex<FileDownloadState>>) -> Self {
+        Announcer {
+            tiers,
+            request,
+            state,
+            interval: DEFAULT_INTERVAL,
+            min_interval: Duration::ZERO,
+            last_announce: None,
+            failures: 0,
+        }
+    }
+
+    // announces `started`, then re-announces every interval until `Stopped` is received or the
+    // sender is dropped; `Completed` is announced immediately and `None` requests an early
+    // re-announce, which is deferred until the tracker's minimum interval has passed
+    pub async fn run(mut self,
+                     mut events: mpsc::UnboundedReceiver<AnnounceEvent>,
+                     peers: mpsc::UnboundedSender<Vec<SocketAddr>>) {
+        // trackerless torrents rely on the DHT alone
+        if self.tiers.is_empty() {
+            info!("no trackers to announce to");
+            while let Some(event) = events.recv().await {
+                if event == AnnounceEvent::Stopped {
+                    break
+                }
+            }
+            return
+        }
+
+        let mut next = self.announce(AnnounceEvent::Started, &peers).await;
+
+        loop {
+            tokio::select! {
+                _ = sleep_until(next) => {
+                    next = self.announce(AnnounceEvent::None, &peers).await;
+                },
+                event = events.recv() => match event {
+                    Some(AnnounceEvent::None) => {
+                        let earliest = self.last_announce
+                            .map(|t| t + self.min_interval)
+                            .unwrap_or_else(Instant::now);
+                        next = next.min(earliest.max(Instant::now()));
+                    },
+                    Some(AnnounceEvent::Completed) => {
+                        next = self.announce(AnnounceEvent::Completed, &peers).await;
+                    },
+                    Some(AnnounceEvent::Started) => (),
+                    Some(AnnounceEvent::Stopped) | None => {
+                        self.announce(AnnounceEvent::Stopped, &peers).await;
+                        break
+                    },
+                },
+            }
+        }
+    }
+
+    // returns when the next regular announce is due
+    async fn announce(&mut self, event: AnnounceEvent, peers: &mpsc::UnboundedSender<Vec<SocketAddr>>) -> Instant {
+        {
+            let guard = self.state.lock().await;
+            self.request.uploaded = guard.uploaded;
+            self.request.downloaded = guard.downloaded;
+            self.request.left = guard.left;
+        }
+        self.request.event = event;
+
+        info!("announcing {:?} (uploaded {}, downloaded {}, left {})",
+            event, self.request.uploaded, self.request.downloaded, self.request.left);
+        self.last_announce = Some(Instant::now());
+
+        match self.tiers.announce(&self.request).await {
+            Ok(response) => {
+                self.failures = 0;
+                self.update_intervals(&response);
+                if !response.peers.is_empty() {
+                    let _ = peers.send(response.peers);
+                }
+                Instant::now() + self.interval
+            },
+            Err(e) => {
+                let retry = (INITIAL_RETRY * 2u32.pow(self.failures.min(16))).min(self.interval);
+                self.failures += 1;
+                warn!("announce failed ({}); retrying in {} seconds", e, retry.as_secs());
+                Instant::now() + retry
+            },
+        }
+    }
+
+    fn update_intervals(&mut self, response: &TrackerResponse) {
+        self.min_interval = Duration::from_secs(response.min_interval.unwrap_or(0));
+        self.interval = Duration::from_secs(response.interval).max(self.min_interval).max(MIN_INTERVAL_FLOOR);
+        info!("re-announcing every {} seconds (minimum {} seconds)", self.interval.as_secs(), self.min_interval.as_secs());
+    }
+}
@@ -0,0 +1,554 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use percent_encoding::percent_decode_str;
+use rand::seq::SliceRandom;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time::{interval, timeout};
+use tracing::{info, warn, error};
+
+use crate::metadata::bencode::{BencodeError, BencodeValue};
+use crate::metadata::file::{TorrentFile, TorrentFileError};
+use crate::util::to_string;
+
+const DEFAULT_NUMWANT: usize = 50;
+const MAX_NUMWANT: usize = 200;
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// announces beyond these are refused, so that clients cannot exhaust memory with made up info hashes or peer ids
+const MAX_SWARMS: usize = 10_000;
+const MAX_PEERS_PER_SWARM: usize = 5_000;
+
+#[derive(Debug, Clone)]
+pub struct TrackerServerConfig {
+    pub address: SocketAddr,
+    pub interval: u64,
+    pub whitelist: Vec<PathBuf>,
+    pub state_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Error)]
+pub enum TrackerServerError {
+    #[error("unable to listen on {0}: {1:?}")]
+    BindError(SocketAddr, std::io::Error),
+    #[error("unable to load whitelisted torrent: {0:?}")]
+    WhitelistError(TorrentFileError),
+    #[error("unable to access state file {0}: {1:?}")]
+    StateFileError(String, std::io::Error),
+    #[error("state file {0} is not valid bencode: {1:?}")]
+    StateFileBencodeError(String, BencodeError),
+    #[error("state file {0} is malformed")]
+    MalformedStateFile(String),
+}
+
+#[derive(Debug, Clone)]
+struct PeerEntry {
+    address: SocketAddr,
+    left: u64,
+    last_seen: u64,
+    // whether the peer's `completed` event has been counted
+    completed: bool,
+}
+
+#[derive(Debug, Default)]
+struct Swarm {
+    peers: HashMap<[u8; 20], PeerEntry>,
+    completed: u64,
+}
+
+#[derive(Debug)]
+pub struct TrackerServer {
+    interval: u64,
+    whitelist: Option<HashSet<[u8; 20]>>,
+    state_file: Option<PathBuf>,
+    swarms: Mutex<HashMap<[u8; 20], Swarm>>,
+}
+
+#[derive(Debug)]
+struct Announce {
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    address: SocketAddr,
+    left: u64,
+    event: Option<String>,
+    compact: bool,
+    no_peer_id: bool,
+    numwant: usize,
+}
+
+impl Swarm {
+    fn seeders(&self) -> usize {
+        self.peers.values().filter(|p| p.left == 0).count()
+    }
+
+    fn leechers(&self) -> usize {
+        self.peers.len() - self.seeders()
+    }
+}
+
+pub async fn serve(config: TrackerServerConfig) -> Result<(), TrackerServerError> {
+    let listener = TcpListener::bind(config.address)
+        .await
+        .map_err(|e| TrackerServerError::BindError(config.address, e))?;
+    let server = Arc::new(TrackerServer::new(&config)?);
+    info!("tracker listening on {}", config.address);
+
+    tokio::select! {
+        _ = server.clone().run(listener) => (),
+        _ = tokio::signal::ctrl_c() => info!("tracker shutting down"),
+    }
+    server.save().await
+}
+
+impl TrackerServer {
+    pub fn new(config: &TrackerServerConfig) -> Result<Self, TrackerServerError> {
+        let whitelist = if config.whitelist.is_empty() {
+            None
+        } else {
+            let mut hashes = HashSet::new();
+            for path in &config.whitelist {
+                let torrent = TorrentFile::new(path).map_err(TrackerServerError::WhitelistError)?;
+                info!("whitelisting {} ({})", torrent.filename, to_string(&torrent.hash));
+                hashes.insert(torrent.hash);
+            }
+            Some(hashes)
+        };
+
+        let swarms = match &config.state_file {
+            Some(path) if path.exists() => load_state(path)?,
+            _ => HashMap::new(),
+        };
+
+        Ok(TrackerServer {
+            // a zero period would stop the sweeper and invite peers to announce continuously
+            interval: config.interval.max(1),
+            whitelist,
+            state_file: config.state_file.clone(),
+            swarms: Mutex::new(swarms),
+        })
+    }
+
+    pub async fn run(self: Arc<Self>, listener: TcpListener) {
+        let sweeper = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(sweeper.interval));
+            loop {
+                ticker.tick().await;
+                sweeper.expire_peers().await;
+                if let Err(e) = sweeper.save().await {
+                    error!("unable to save tracker state: {:?}", e);
+                }
+            }
+        });
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, address)) => {
+                    let server = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = server.handle_connection(stream, address).await {
+                            warn!("error serving tracker request from {}: {:?}", address, e);
+                        }
+                    });
+                },
+                Err(e) => error!("unable to accept tracker connection: {:?}", e),
+            }
+        }
+    }
+
+    async fn handle_connection(&self, mut stream: TcpStream, address: SocketAddr) -> std::io::Result<()> {
+        let request = match timeout(REQUEST_TIMEOUT, read_request_target(&mut stream)).await {
+            Ok(result) => result?,
+            Err(_) => return Ok(()),
+        };
+        let (status, body) = match request {
+            Some(target) => {
+                let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+                let params = parse_query(query);
+                match path.rsplit('/').next().unwrap_or("") {
+                    "announce" => (200, self.announce(&params, address).await),
+                    "scrape" => (200, self.scrape(&params).await),
+                    _ => (404, failure("not found")),
+                }
+            },
+            None => (400, failure("malformed request")),
+        };
+
+        let reason = match status { 200 => "OK", 404 => "Not Found", _ => "Bad Request" };
+        let header = format!("HTTP/1.1 {status} {reason}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+        stream.write_all(header.as_bytes()).await?;
+        stream.write_all(&body).await?;
+        stream.shutdown().await
+    }
+
+    async fn announce(&self, params: &HashMap<String, Vec<Vec<u8>>>, remote: SocketAddr) -> Vec<u8> {
+        let announce = match Announce::parse(params, remote) {
+            Ok(a) => a,
+            Err(reason) => return failure(reason),
+        };
+        if let Some(whitelist) = &self.whitelist && !whitelist.contains(&announce.info_hash) {
+            return failure("torrent is not tracked");
+        }
+
+        let mut guard = self.swarms.lock().await;
+        if !guard.contains_key(&announce.info_hash) && guard.len() >= MAX_SWARMS {
+            return failure("tracker is tracking too many torrents");
+        }
+        let swarm = guard.entry(announce.info_hash).or_default();
+        match announce.event.as_deref() {
+            Some("stopped") => {
+                swarm.peers.remove(&announce.peer_id);
+            },
+            event => {
+                if !swarm.peers.contains_key(&announce.peer_id) && swarm.peers.len() >= MAX_PEERS_PER_SWARM {
+                    return failure("torrent has too many peers");
+                }
+                let peer = swarm.peers.entry(announce.peer_id).or_insert(PeerEntry {
+                    address: announce.address,
+                    left: announce.left,
+                    last_seen: 0,
+                    completed: false,
+                });
+                peer.address = announce.address;
+                peer.left = announce.left;
+                peer.last_seen = now();
+                // repeated `completed` events from the same peer are counted once
+                if event == Some("completed") && !peer.completed {
+                    peer.completed = true;
+                    swarm.completed += 1;
+                }
+            },
+        }
+
+        let mut others: Vec<(&[u8; 20], &PeerEntry)> = swarm.peers
+            .iter()
+            .filter(|(id, _)| **id != announce.peer_id)
+            .collect();
+        others.shuffle(&mut rand::rng());
+        others.truncate(announce.numwant);
+
+        let mut items: BTreeMap<Vec<u8>, BencodeValue> = BTreeMap::new();
+        items.insert(b"interval".to_vec(), BencodeValue::Integer(self.interval as i64));
+        items.insert(b"min interval".to_vec(), BencodeValue::Integer((self.interval / 2).max(1) as i64));
+        items.insert(b"complete".to_vec(), BencodeValue::Integer(swarm.seeders() as i64));
+        items.insert(b"incomplete".to_vec(), BencodeValue::Integer(swarm.leechers() as i64));
+        if announce.compact {
+            let mut peers: Vec<u8> = Vec::new();
+            let mut peers6: Vec<u8> = Vec::new();
+            for (_, peer) in &others {
+                match peer.address {
+                    SocketAddr::V4(a) => {
+                        peers.extend_from_slice(&a.ip().octets());
+                        peers.extend_from_slice(&a.port().to_be_bytes());
+                    },
+                    SocketAddr::V6(a) => {
+                        peers6.extend_from_slice(&a.ip().octets());
+                        peers6.extend_from_slice(&a.port().to_be_bytes());
+                    },
+                }
+            }
+            items.insert(b"peers".to_vec(), BencodeValue::ByteString(peers));
+            if !peers6.is_empty() {
+                items.insert(b"peers6".to_vec(), BencodeValue::ByteString(peers6));
+            }
+        } else {
+            let peers = others
+                .iter()
+                .map(|(id, peer)| {
+                    let mut entry: BTreeMap<Vec<u8>, BencodeValue> = BTreeMap::new();
+                    entry.insert(b"ip".to_vec(), BencodeValue::ByteString(peer.address.ip().to_string().into_bytes()));
+                    entry.insert(b"port".to_vec(), BencodeValue::Integer(peer.address.port() as i64));
+                    if !announce.no_peer_id {
+                        entry.insert(b"peer id".to_vec(), BencodeValue::ByteString(id.to_vec()));
+                    }
+                    BencodeValue::Dictionary(entry)
+                })
+                .collect();
+            items.insert(b"peers".to_vec(), BencodeValue::List(peers));
+        }
+
+        info!("peer {} announced {} for {} ({:?}); returning {} peers",
+            announce.address, to_string(&announce.info_hash), announce.left, announce.event, others.len());
+        Vec::from(&BencodeValue::Dictionary(items))
+    }
+
+    async fn scrape(&self, params: &HashMap<String, Vec<Vec<u8>>>) -> Vec<u8> {
+        let guard = self.swarms.lock().await;
+        let requested: Vec<[u8; 20]> = match params.get("info_hash") {
+            Some(values) => values.iter().filter_map(|v| v.as_slice().try_into().ok()).collect(),
+            None => guard.keys().copied().collect(),
+        };
+
+        let mut files: BTreeMap<Vec<u8>, BencodeValue> = BTreeMap::new();
+        for hash in requested {
+            if let Some(swarm) = guard.get(&hash) {
+                let mut entry: BTreeMap<Vec<u8>, BencodeValue> = BTreeMap::new();
+                entry.insert(b"complete".to_vec(), BencodeValue::Integer(swarm.seeders() as i64));
+                entry.insert(b"downloaded".to_vec(), BencodeValue::Integer(swarm.completed as i64));
+                entry.insert(b"incomplete".to_vec(), BencodeValue::Integer(swarm.leechers() as i64));
+                files.insert(hash.to_vec(), BencodeValue::Dictionary(entry));
+            }
+        }
+
+        let mut items: BTreeMap<Vec<u8>, BencodeValue> = BTreeMap::new();
+        items.insert(b"files".to_vec(), BencodeValue::Dictionary(files));
+        Vec::from(&BencodeValue::Dictionary(items))
+    }
+
+    // peers that have missed two consecutive announces are presumed gone
+    async fn expire_peers(&self) {
+        let cutoff = now().saturating_sub(2 * self.interval);
+        let mut guard = self.swarms.lock().await;
+        for (hash, swarm) in guard.iter_mut() {
+            let before = swarm.peers.len();
+            swarm.peers.retain(|_, peer| peer.last_seen >= cutoff);
+            if swarm.peers.len() != before {
+                info!("expired {} peers from {}", before - swarm.peers.len(), to_string(hash));
+            }
+        }
+        guard.retain(|_, swarm| !swarm.peers.is_empty() || swarm.completed > 0);
+    }
+
+    async fn save(&self) -> Result<(), TrackerServerError> {
+        let Some(path) = &self.state_file else {
+            return Ok(())
+        };
+        let guard = self.swarms.lock().await;
+        let mut items: BTreeMap<Vec<u8>, BencodeValue> = BTreeMap::new();
+        for (hash, swarm) in guard.iter() {
+            let peers = swarm.peers
+                .iter()
+                .map(|(id, peer)| {
+                    let mut entry: BTreeMap<Vec<u8>, BencodeValue> = BTreeMap::new();
+                    entry.insert(b"ip".to_vec(), BencodeValue::ByteString(peer.address.ip().to_string().into_bytes()));
+                    entry.insert(b"completed".to_vec(), BencodeValue::Integer(peer.completed as i64));
+                    entry.insert(b"last seen".to_vec(), BencodeValue::Integer(peer.last_seen as i64));
+                    entry.insert(b"left".to_vec(), BencodeValue::Integer(peer.left as i64));
+                    entry.insert(b"peer id".to_vec(), BencodeValue::ByteString(id.to_vec()));
+                    entry.insert(b"port".to_vec(), BencodeValue::Integer(peer.address.port() as i64));
+                    BencodeValue::Dictionary(entry)
+                })
+                .collect();
+            let mut entry: BTreeMap<Vec<u8>, BencodeValue> = BTreeMap::new();
+            entry.insert(b"completed".to_vec(), BencodeValue::Integer(swarm.completed as i64));
+            entry.insert(b"peers".to_vec(), BencodeValue::List(peers));
+            items.insert(hash.to_vec(), BencodeValue::Dictionary(entry));
+        }
+        let bytes = Vec::from(&BencodeValue::Dictionary(items));
+        tokio::fs::write(path, bytes)
+            .await
+            .map_err(|e| TrackerServerError::StateFileError(path.to_string_lossy().into(), e))
+    }
+}
+
+impl Announce {
+    fn parse(params: &HashMap<String, Vec<Vec<u8>>>, remote: SocketAddr) -> Result<Self, &'static str> {
+        let first = |key: &str| params.get(key).and_then(|values| values.first());
+        let number = |key: &str| first(key)
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .and_then(|s| s.parse::<u64>().ok());
+
+        let info_hash: [u8; 20] = first("info_hash")
+            .and_then(|v| v.as_slice().try_into().ok())
+            .ok_or("missing or malformed info_hash")?;
+        let peer_id: [u8; 20] = first("peer_id")
+            .and_then(|v| v.as_slice().try_into().ok())
+            .ok_or("missing or malformed peer_id")?;
+        let port = number("port")
+            .and_then(|p| u16::try_from(p).ok())
+            .ok_or("missing or malformed port")?;
+        let left = number("left").ok_or("missing or malformed left")?;
+        // only peers on this machine or network may name another address, so others cannot add third parties to a swarm
+        let remote_ip = remote.ip().to_canonical();
+        let ip: IpAddr = first("ip")
+            .filter(|_| is_local(remote_ip))
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(remote_ip);
+        let event = first("event")
+            .map(|v| String::from_utf8_lossy(v).into_owned())
+            .filter(|e| !e.is_empty());
+        let compact = number("compact").is_none_or(|c| c == 1);
+        let no_peer_id = number("no_peer_id") == Some(1);
+        let numwant = number("numwant").map_or(DEFAULT_NUMWANT, |n| (n as usize).min(MAX_NUMWANT));
+
+        Ok(Announce {
+            info_hash,
+            peer_id,
+            address: SocketAddr::new(ip, port),
+            left,
+            event,
+            compact,
+            no_peer_id,
+            numwant,
+        })
+    }
+}
+
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local(),
+    }
+}
+
+// returns the request target of an HTTP GET request, or `None` if the request is malformed
+async fn read_request_target(stream: &mut TcpStream) -> std::io::Result<Option<String>> {
+    let mut buf: Vec<u8> = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let head = String::from_utf8_lossy(&buf);
+    let mut parts = head.lines().next().unwrap_or("").split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => Ok(Some(target.to_owned())),
+        _ => Ok(None),
+    }
+}
+
+// values are kept as raw bytes since `info_hash` and `peer_id` are binary
+fn parse_query(query: &str) -> HashMap<String, Vec<Vec<u8>>> {
+    let mut params: HashMap<String, Vec<Vec<u8>>> = HashMap::new();
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let key = percent_decode_str(key).decode_utf8_lossy().into_owned();
+        let value: Vec<u8> = percent_decode_str(value).collect();
+        params.entry(key).or_default().push(value);
+    }
+    params
+}
+
+fn failure(reason: &str) -> Vec<u8> {
+    let mut items: BTreeMap<Vec<u8>, BencodeValue> = BTreeMap::new();
+    items.insert(b"failure reason".to_vec(), BencodeValue::ByteString(reason.as_bytes().to_vec()));
+    Vec::from(&BencodeValue::Dictionary(items))
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn load_state(path: &Path) -> Result<HashMap<[u8; 20], Swarm>, TrackerServerError> {
+    let path_str: String = path.to_string_lossy().into();
+    let malformed = || TrackerServerError::MalformedStateFile(path_str.clone());
+    let bytes = std::fs::read(path).map_err(|e| TrackerServerError::StateFileError(path_str.clone(), e))?;
+    let value = BencodeValue::try_from(bytes.as_slice())
+        .map_err(|e| TrackerServerError::StateFileBencodeError(path_str.clone(), e))?;
+    let BencodeValue::Dictionary(items) = value else {
+        return Err(malformed());
+    };
+
+    let integer = |entry: &BTreeMap<Vec<u8>, BencodeValue>, key: &[u8]| match entry.get(key) {
+        Some(BencodeValue::Integer(i)) if *i >= 0 => Some(*i as u64),
+        _ => None,
+    };
+
+    let mut swarms: HashMap<[u8; 20], Swarm> = HashMap::new();
+    for (hash, value) in items {
+        let (Ok(hash), BencodeValue::Dictionary(entry)) = (<[u8; 20]>::try_from(hash.as_slice()), value) else {
+            return Err(malformed());
+        };
+        let mut swarm = Swarm { peers: HashMap::new(), completed: integer(&entry, b"completed").unwrap_or(0) };
+        if let Some(BencodeValue::List(peers)) = entry.get(b"peers".as_slice()) {
+            for peer in peers {
+                let BencodeValue::Dictionary(peer) = peer else {
+                    return Err(malformed());
+                };
+                let id: Option<[u8; 20]> = match peer.get(b"peer id".as_slice()) {
+                    Some(BencodeValue::ByteString(id)) => id.as_slice().try_into().ok(),
+                    _ => None,
+                };
+                let ip: Option<IpAddr> = match peer.get(b"ip".as_slice()) {
+                    Some(BencodeValue::ByteString(ip)) => std::str::from_utf8(ip).ok().and_then(|s| s.parse().ok()),
+                    _ => None,
+                };
+                let port = integer(peer, b"port").and_then(|p| u16::try_from(p).ok());
+                let (Some(id), Some(ip), Some(port)) = (id, ip, port) else {
+                    return Err(malformed());
+                };
+                swarm.peers.insert(id, PeerEntry {
+                    address: SocketAddr::new(ip, port),
+                    left: integer(peer, b"left").unwrap_or(0),
+                    last_seen: integer(peer, b"last seen").unwrap_or(0),
+                    completed: integer(peer, b"completed") == Some(1),
+                });
+            }
+        }
+        swarms.insert(hash, swarm);
+    }
+    info!("loaded {} swarms from {}", swarms.len(), path_str);
+    Ok(swarms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> TrackerServer {
+        let config = TrackerServerConfig {
+            address: "127.0.0.1:0".parse().unwrap(),
+            interval: 1800,
+            whitelist: Vec::new(),
+            state_file: None,
+        };
+        TrackerServer::new(&config).unwrap()
+    }
+
+    fn params(info_hash: [u8; 20], peer_id: [u8; 20], event: Option<&str>) -> HashMap<String, Vec<Vec<u8>>> {
+        let mut params: HashMap<String, Vec<Vec<u8>>> = HashMap::new();
+        params.insert("info_hash".into(), vec![info_hash.to_vec()]);
+        params.insert("peer_id".into(), vec![peer_id.to_vec()]);
+        params.insert("port".into(), vec![b"6881".to_vec()]);
+        params.insert("left".into(), vec![b"0".to_vec()]);
+        if let Some(event) = event {
+            params.insert("event".into(), vec![event.as_bytes().to_vec()]);
+        }
+        params
+    }
+
+    fn is_failure(response: &[u8]) -> bool {
+        matches!(BencodeValue::try_from(response), Ok(BencodeValue::Dictionary(items)) if items.contains_key(b"failure reason".as_slice()))
+    }
+
+    #[tokio::test]
+    async fn counts_each_peer_completing_once() {
+        let server = server();
+        let remote: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        for _ in 0..3 {
+            server.announce(&params([1; 20], [2; 20], Some("completed")), remote).await;
+        }
+        server.announce(&params([1; 20], [3; 20], Some("completed")), remote).await;
+        assert_eq!(server.swarms.lock().await[&[1; 20]].completed, 2);
+    }
+
+    #[tokio::test]
+    async fn refuses_peers_beyond_the_swarm_limit() {
+        let server = server();
+        let remote: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let peer_id = |i: usize| {
+            let mut id = [0u8; 20];
+            id[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            id
+        };
+        for i in 0..MAX_PEERS_PER_SWARM {
+            assert!(!is_failure(&server.announce(&params([1; 20], peer_id(i), None), remote).await));
+        }
+        assert!(is_failure(&server.announce(&params([1; 20], peer_id(MAX_PEERS_PER_SWARM), None), remote).await));
+        // peers already in the swarm may still re-announce
+        assert!(!is_failure(&server.announce(&params([1; 20], peer_id(0), None), remote).await));
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use rand::seq::SliceRandom;
+use tokio::time::timeout;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::metadata::tracker::{AnnounceRequest, TrackerError, TrackerResponse, retrieve_peers};
+
+// bounds how long a single unresponsive tracker can hold up the rest of its tier
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// BEP 12 tiers of trackers; the first tracker of each tier is the one that last answered
+#[derive(Debug, Clone)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<Url>>,
+    tracker_ids: HashMap<Url, Vec<u8>>,
+}
+
+impl TrackerTiers {
+    pub fn new(announce: Option<&str>, announce_list: &[Vec<String>]) -> Self {
+        let mut rng = rand::rng();
+        let mut tiers: Vec<Vec<Url>> = announce_list
+            .iter()
+            .map(|tier| {
+                let mut urls: Vec<Url> = tier
+                    .iter()
+                    .filter_map(|s| Url::parse(s)
+                        .inspect_err(|e| warn!("ignoring malformed tracker URL '{}': {:?}", s, e))
+                        .ok())
+                    .collect();
+                urls.shuffle(&mut rng);
+                urls
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect();
+
+        // `announce` is only consulted when there is no usable `announce-list`
+        if tiers.is_empty() && let Some(Ok(url)) = announce.map(Url::parse) {
+            tiers.push(vec![url]);
+        }
+
+        TrackerTiers { tiers, tracker_ids: HashMap::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    // asks the trackers of tier 0 in order until one answers, promoting it to the front of its
+    // tier, and moves on to the next tier only when every tracker of this one has failed
+    pub async fn announce(&mut self, request: &AnnounceRequest) -> Result<TrackerResponse, TrackerError> {
+        let mut last_error: Option<TrackerError> = None;
+
+        for (t, tier) in self.tiers.iter_mut().enumerate() {
+            for i in 0..tier.len() {
+                let url = &tier[i];
+                // a tracker that handed out a tracker id expects it back on subsequent announces
+                let mut request = request.clone();
+                request.tracker_id = self.tracker_ids.get(url).cloned();
+                let result = timeout(ANNOUNCE_TIMEOUT, retrieve_peers(url, &request))
+                    .await
+                    .unwrap_or(Err(TrackerError::ResponseTimeout(ANNOUNCE_TIMEOUT.as_secs())));
+
+                match result {
+                    Ok(response) => {
+                        info!("tracker {} (tier {}) returned {} peers", url, t, response.peers.len());
+                        if let Some(warning) = &response.warning {
+                            warn!("tracker {} warns: {}", url, warning);
+                        }
+                        if let Some(id) = &response.tracker_id {
+                            self.tracker_ids.insert(url.clone(), id.clone());
+                        }
+                        let answered = tier.remove(i);
+                        tier.insert(0, answered);
+                        return Ok(response)
+                    },
+                    Err(e) => {
+                        warn!("tracker {} (tier {}) failed: {}", url, t, e);
+                        last_error = Some(e);
+                    },
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or(TrackerError::NoTrackers))
+    }
+}
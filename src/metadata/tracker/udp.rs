@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::{timeout_at, Instant};
+use tracing::{info, warn};
+use url::{Host, Url};
+
+use crate::metadata::tracker::{AnnounceRequest, ScrapeStats, TrackerError, TrackerResponse, parse_compact_peers, parse_compact_peers6};
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+// BEP 15: retransmit after 15 * 2^n seconds, n increasing up to 8; announces through the tiers
+// are cut off by their ANNOUNCE_TIMEOUT and scrapes by SCRAPE_TIMEOUT well before the schedule
+// runs out, so in practice a tracker has two attempts to answer an announce and one a scrape
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_RETRANSMISSIONS: u32 = 8;
+
+// a connection ID may be used by the client for one minute after it is received
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+// keeps a scrape request within the 1500 byte MTU most trackers expect
+const MAX_SCRAPE_HASHES: usize = 74;
+
+const MAX_PACKET_SIZE: usize = 2048;
+
+static CONNECTION_IDS: LazyLock<Mutex<HashMap<SocketAddr, (u64, Instant)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Connect  = 0,
+    Announce = 1,
+    Scrape   = 2,
+    Error    = 3,
+}
+
+#[derive(Debug)]
+struct UdpTracker {
+    socket: UdpSocket,
+    address: SocketAddr,
+    // the first retransmission timeout, doubling with each attempt
+    base_timeout: Duration,
+}
+
+pub async fn announce(url: &Url, request: &AnnounceRequest) -> Result<TrackerResponse, TrackerError> {
+    let tracker = UdpTracker::connect(url).await?;
+    info!("announcing to UDP tracker {} ({})", url, tracker.address);
+    tracker.announce(request).await
+}
+
+pub async fn scrape(url: &Url, info_hashes: &[[u8; 20]]) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+    let tracker = UdpTracker::connect(url).await?;
+    info!("scraping {} info hash(es) from UDP tracker {} ({})", info_hashes.len(), url, tracker.address);
+    tracker.scrape(info_hashes).await
+}
+
+impl UdpTracker {
+    async fn connect(url: &Url) -> Result<Self, TrackerError> {
+        let address = resolve(url).await?;
+        let local: SocketAddr = if address.is_ipv4() {
+            (IpAddr::from([0u8; 4]), 0).into()
+        } else {
+            (IpAddr::from([0u16; 8]), 0).into()
+        };
+        let socket = UdpSocket::bind(local).await.map_err(TrackerError::UdpSocketError)?;
+        socket.connect(address).await.map_err(TrackerError::UdpSocketError)?;
+        Ok(UdpTracker { socket, address, base_timeout: BASE_TIMEOUT })
+    }
+
+    async fn announce(&self, request: &AnnounceRequest) -> Result<TrackerResponse, TrackerError> {
+        let response = self.transact(Action::Announce, 20, |connection_id, transaction_id| {
+            let mut buf: Vec<u8> = Vec::with_capacity(98);
+            encode_header(connection_id, Action::Announce, transaction_id, &mut buf);
+            buf.extend_from_slice(&request.info_hash);
+            buf.extend_from_slice(&request.peer_id);
+            buf.extend_from_slice(&request.downloaded.to_be_bytes());
+            buf.extend_from_slice(&request.left.to_be_bytes());
+            buf.extend_from_slice(&request.uploaded.to_be_bytes());
+            buf.extend_from_slice(&(request.event as u32).to_be_bytes());
+            let ip = match request.ip {
+                Some(IpAddr::V4(ip)) => ip.to_bits(),
+                _ => 0, // use the sender's address
+            };
+            buf.extend_from_slice(&ip.to_be_bytes());
+            buf.extend_from_slice(&request.key.to_be_bytes());
+            let numwant: i32 = request.numwant.map_or(-1, |n| n.min(i32::MAX as u32) as i32);
+            buf.extend_from_slice(&numwant.to_be_bytes());
+            buf.extend_from_slice(&request.port.to_be_bytes());
+            buf
+        }).await?;
+
+        let interval = read_u32(&response, 8) as u64;
+        let incomplete = read_u32(&response, 12) as u64;
+        let complete = read_u32(&response, 16) as u64;
+        // the address family of the tracker determines the size of each peer entry
+        let peers = if self.address.is_ipv4() {
+            parse_compact_peers(&response[20..])?
+        } else {
+            parse_compact_peers6(&response[20..])?
+        };
+        Ok(TrackerResponse {
+            interval,
+            min_interval: None,
+            warning: None,
+            tracker_id: None,
+            complete: Some(complete),
+            incomplete: Some(incomplete),
+            peers,
+            peer_ids: HashMap::new(),
+        })
+    }
+
+    async fn scrape(&self, info_hashes: &[[u8; 20]]) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+        let mut stats: HashMap<[u8; 20], ScrapeStats> = HashMap::with_capacity(info_hashes.len());
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let response = self.transact(Action::Scrape, 8 + 12 * chunk.len(), |connection_id, transaction_id| {
+                let mut buf: Vec<u8> = Vec::with_capacity(16 + 20 * chunk.len());
+                encode_header(connection_id, Action::Scrape, transaction_id, &mut buf);
+                for hash in chunk {
+                    buf.extend_from_slice(hash);
+                }
+                buf
+            }).await?;
+
+            for (hash, entry) in chunk.iter().zip(response[8..].chunks_exact(12)) {
+                stats.insert(*hash, ScrapeStats {
+                    seeders: read_u32(entry, 0),
+                    completed: read_u32(entry, 4),
+                    leechers: read_u32(entry, 8),
+                });
+            }
+        }
+        Ok(stats)
+    }
+
+    // sends the request built by `encode` until a response of at least `min_length` bytes
+    // with the matching transaction ID arrives, backing off per BEP 15 between attempts
+    async fn transact<F>(&self, action: Action, min_length: usize, encode: F) -> Result<Vec<u8>, TrackerError>
+    where F: Fn(u64, u32) -> Vec<u8> {
+        for n in 0..=MAX_RETRANSMISSIONS {
+            let deadline = Instant::now() + self.base_timeout * 2u32.pow(n);
+
+            let connection_id = match self.cached_connection_id() {
+                Some(id) => id,
+                None => match self.request_connection_id(deadline).await? {
+                    Some(id) => id,
+                    None => {
+                        warn!("UDP tracker {} did not answer connect request (attempt {})", self.address, n + 1);
+                        continue
+                    },
+                },
+            };
+
+            let transaction_id: u32 = rand::random();
+            self.send(&encode(connection_id, transaction_id)).await?;
+            match self.receive(action, transaction_id, min_length, deadline).await? {
+                Some(response) => return Ok(response),
+                None => warn!("UDP tracker {} did not answer {:?} request (attempt {})", self.address, action, n + 1),
+            }
+        }
+        Err(TrackerError::UdpTimeout(MAX_RETRANSMISSIONS + 1))
+    }
+
+    fn cached_connection_id(&self) -> Option<u64> {
+        let mut guard = CONNECTION_IDS.lock().expect("connection ID cache lock poisoned");
+        match guard.get(&self.address) {
+            Some(&(id, received)) if received.elapsed() < CONNECTION_ID_LIFETIME => Some(id),
+            Some(_) => {
+                guard.remove(&self.address);
+                None
+            },
+            None => None,
+        }
+    }
+
+    async fn request_connection_id(&self, deadline: Instant) -> Result<Option<u64>, TrackerError> {
+        let transaction_id: u32 = rand::random();
+        let mut buf: Vec<u8> = Vec::with_capacity(16);
+        encode_header(PROTOCOL_ID, Action::Connect, transaction_id, &mut buf);
+        self.send(&buf).await?;
+
+        let Some(response) = self.receive(Action::Connect, transaction_id, 16, deadline).await? else {
+            return Ok(None)
+        };
+        let connection_id = u64::from_be_bytes(response[8..16].try_into().expect("response verified to be at least 16 bytes"));
+        CONNECTION_IDS.lock()
+            .expect("connection ID cache lock poisoned")
+            .insert(self.address, (connection_id, Instant::now()));
+        Ok(Some(connection_id))
+    }
+
+    async fn send(&self, bytes: &[u8]) -> Result<(), TrackerError> {
+        self.socket.send(bytes).await.map(|_| ()).map_err(TrackerError::UdpSocketError)
+    }
+
+    // returns `None` if the deadline passes before a matching response arrives
+    async fn receive(&self, action: Action, transaction_id: u32, min_length: usize, deadline: Instant) -> Result<Option<Vec<u8>>, TrackerError> {
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+        loop {
+            let n = match timeout_at(deadline, self.socket.recv(&mut buf)).await {
+                Ok(result) => result.map_err(TrackerError::UdpSocketError)?,
+                Err(_) => return Ok(None),
+            };
+            if n < 8 || read_u32(&buf, 4) != transaction_id {
+                continue
+            }
+            let received_action = read_u32(&buf, 0);
+            if received_action == Action::Error as u32 {
+                let message = String::from_utf8_lossy(&buf[8..n]).into_owned();
+                return Err(TrackerError::TrackerFailure(message));
+            }
+            if received_action != action as u32 {
+                return Err(TrackerError::UdpUnexpectedAction(action as u32, received_action));
+            }
+            if n < min_length {
+                return Err(TrackerError::UdpResponseTooShort(received_action, n));
+            }
+            buf.truncate(n);
+            return Ok(Some(buf))
+        }
+    }
+}
+
+async fn resolve(url: &Url) -> Result<SocketAddr, TrackerError> {
+    let port = url.port().ok_or_else(|| TrackerError::MissingHostOrPort(url.to_string()))?;
+    match url.host() {
+        Some(Host::Ipv4(ip)) => Ok((ip, port).into()),
+        Some(Host::Ipv6(ip)) => Ok((ip, port).into()),
+        Some(Host::Domain(domain)) => {
+            let addresses: Vec<SocketAddr> = lookup_host((domain, port))
+                .await
+                .map_err(|e| TrackerError::UnresolvableHost(domain.to_owned(), Some(e)))?
+                .collect();
+            // trackers reached over IPv4 return IPv4 peers, which are far more plentiful
+            addresses
+                .iter()
+                .find(|a| a.is_ipv4())
+                .or(addresses.first())
+                .copied()
+                .ok_or_else(|| TrackerError::UnresolvableHost(domain.to_owned(), None))
+        },
+        None => Err(TrackerError::MissingHostOrPort(url.to_string())),
+    }
+}
+
+fn encode_header(connection_id: u64, action: Action, transaction_id: u32, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&connection_id.to_be_bytes());
+    buf.extend_from_slice(&(action as u32).to_be_bytes());
+    buf.extend_from_slice(&transaction_id.to_be_bytes());
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset+4].try_into().expect("offset verified to be in range"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::tracker::AnnounceEvent;
+
+    // short enough that a retransmission test finishes quickly
+    const TEST_TIMEOUT: Duration = Duration::from_millis(200);
+    const TEST_DEADLINE: Duration = Duration::from_secs(5);
+    const INFO_HASH: [u8; 20] = [7; 20];
+
+    fn request() -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: INFO_HASH,
+            peer_id: *b"-TU0100-000000000000",
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 1000,
+            event: AnnounceEvent::Started,
+            numwant: Some(50),
+            key: 1,
+            ip: None,
+            tracker_id: None,
+            no_peer_id: false,
+        }
+    }
+
+    async fn stand_in() -> (UdpSocket, Url) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("udp://{}", socket.local_addr().unwrap())).unwrap();
+        (socket, url)
+    }
+
+    async fn tracker(url: &Url) -> UdpTracker {
+        let mut tracker = UdpTracker::connect(url).await.unwrap();
+        tracker.base_timeout = TEST_TIMEOUT;
+        tracker
+    }
+
+    // the connection ID, action and transaction ID of the next request, and who sent it
+    async fn receive(socket: &UdpSocket) -> (u64, u32, u32, Vec<u8>, SocketAddr) {
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+        let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+        buf.truncate(n);
+        let connection_id = u64::from_be_bytes(buf[0..8].try_into().unwrap());
+        (connection_id, read_u32(&buf, 8), read_u32(&buf, 12), buf, from)
+    }
+
+    async fn answer_connect(socket: &UdpSocket, connection_id: u64) {
+        let (protocol_id, action, transaction_id, _, from) = receive(socket).await;
+        assert_eq!(protocol_id, PROTOCOL_ID);
+        assert_eq!(action, Action::Connect as u32);
+        let mut reply = Vec::new();
+        reply.extend_from_slice(&(Action::Connect as u32).to_be_bytes());
+        reply.extend_from_slice(&transaction_id.to_be_bytes());
+        reply.extend_from_slice(&connection_id.to_be_bytes());
+        socket.send_to(&reply, from).await.unwrap();
+    }
+
+    fn announce_reply(transaction_id: u32, interval: u32) -> Vec<u8> {
+        let mut reply = Vec::new();
+        for field in [Action::Announce as u32, transaction_id, interval, 2, 3] {
+            reply.extend_from_slice(&field.to_be_bytes());
+        }
+        reply.extend_from_slice(&[127, 0, 0, 1, 0x1A, 0xE1]);
+        reply
+    }
+
+    // receives an announce made with `connection_id`, returning its transaction ID and sender
+    async fn receive_announce(socket: &UdpSocket, connection_id: u64) -> (u32, SocketAddr) {
+        let (received_id, action, transaction_id, request, from) = receive(socket).await;
+        assert_eq!(received_id, connection_id);
+        assert_eq!(action, Action::Announce as u32);
+        assert_eq!(request[16..36], INFO_HASH);
+        (transaction_id, from)
+    }
+
+    #[tokio::test]
+    async fn connects_and_announces() {
+        let (socket, url) = stand_in().await;
+        let tracker = tracker(&url).await;
+        let request = request();
+        let stand_in = async {
+            answer_connect(&socket, 0xC0FFEE).await;
+            let (transaction_id, from) = receive_announce(&socket, 0xC0FFEE).await;
+            socket.send_to(&announce_reply(transaction_id, 1800), from).await.unwrap();
+        };
+        let (response, _) = tokio::time::timeout(TEST_DEADLINE, async { tokio::join!(tracker.announce(&request), stand_in) })
+            .await
+            .unwrap();
+        let response = response.unwrap();
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.incomplete, Some(2));
+        assert_eq!(response.complete, Some(3));
+        assert_eq!(response.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn ignores_replies_to_other_transactions() {
+        let (socket, url) = stand_in().await;
+        let tracker = tracker(&url).await;
+        let request = request();
+        let stand_in = async {
+            answer_connect(&socket, 1).await;
+            let (transaction_id, from) = receive_announce(&socket, 1).await;
+            socket.send_to(&announce_reply(transaction_id.wrapping_add(1), 60), from).await.unwrap();
+            socket.send_to(&announce_reply(transaction_id, 1800), from).await.unwrap();
+        };
+        let (response, _) = tokio::time::timeout(TEST_DEADLINE, async { tokio::join!(tracker.announce(&request), stand_in) })
+            .await
+            .unwrap();
+        assert_eq!(response.unwrap().interval, 1800);
+    }
+
+    #[tokio::test]
+    async fn reconnects_once_the_connection_id_expires() {
+        let (socket, url) = stand_in().await;
+        let tracker = tracker(&url).await;
+        let expired = Instant::now().checked_sub(CONNECTION_ID_LIFETIME * 2).unwrap();
+        CONNECTION_IDS.lock().unwrap().insert(tracker.address, (1, expired));
+        let request = request();
+
+        // the expired ID is replaced by a fresh one, which the next announce reuses without connecting
+        let stand_in = async {
+            answer_connect(&socket, 2).await;
+            let (transaction_id, from) = receive_announce(&socket, 2).await;
+            socket.send_to(&announce_reply(transaction_id, 1800), from).await.unwrap();
+            let (transaction_id, from) = receive_announce(&socket, 2).await;
+            socket.send_to(&announce_reply(transaction_id, 1800), from).await.unwrap();
+        };
+        let announces = async {
+            tracker.announce(&request).await.unwrap();
+            tracker.announce(&request).await.unwrap();
+        };
+        tokio::time::timeout(TEST_DEADLINE, async { tokio::join!(announces, stand_in) }).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn retransmits_when_a_reply_is_lost() {
+        let (socket, url) = stand_in().await;
+        let tracker = tracker(&url).await;
+        let request = request();
+        let stand_in = async {
+            answer_connect(&socket, 3).await;
+            // the first announce goes unanswered, as if its reply were dropped
+            receive_announce(&socket, 3).await;
+            let (transaction_id, from) = receive_announce(&socket, 3).await;
+            socket.send_to(&announce_reply(transaction_id, 1800), from).await.unwrap();
+        };
+        let (response, _) = tokio::time::timeout(TEST_DEADLINE, async { tokio::join!(tracker.announce(&request), stand_in) })
+            .await
+            .unwrap();
+        assert_eq!(response.unwrap().interval, 1800);
+    }
+}
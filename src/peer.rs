@@ -1,17 +1,78 @@
+pub mod ban;
 pub mod handshake;
 pub mod message;
 pub mod downloader;
+pub mod extension;
+pub mod fast;
+pub mod mse;
+pub mod pex;
+pub mod picker;
+pub mod pool;
+pub mod choker;
+pub mod client;
+pub mod utp;
 
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
-use std::{net::SocketAddrV4, sync::Arc};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
+use crate::config::ClientConfig;
 use crate::metadata::file::TorrentFile;
-use crate::peer::downloader::{FileDownloadInfo, FileDownloadState, Downloader};
+use crate::metadata::tracker::AnnounceEvent;
+use crate::peer::ban::BanList;
+use crate::peer::choker::Choker;
+use crate::peer::downloader::{FileDownloadInfo, FileDownloadState, Downloader, Session};
+use crate::peer::message::MessageId;
+use crate::peer::pool::PeerPool;
+use crate::peer::utp::UtpSocket;
+use crate::util::io::PieceSource;
 
-use tokio::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, Mutex, Notify};
+use tokio::task::{AbortHandle, JoinError, JoinSet};
+use tokio::time::{interval, timeout, MissedTickBehavior};
 use thiserror::Error;
 use indicatif::{ProgressBar, ProgressStyle};
-use tracing::{info, error};
+use tracing::{info, error, warn};
+
+// Have and endgame block announcements a slow connection can fall behind by before missing some
+const HAVE_BUFFER: usize = 1024;
+// how long connecting, negotiating encryption, and shaking hands may take together
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+// how often peers whose backoff has passed are tried
+const FILL_INTERVAL: Duration = Duration::from_secs(5);
+// how long a download goes on with no candidates and no connections before giving up
+const NO_PEERS_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+// connections open across every torrent, held to `ClientConfig::max_connections`
+static OPEN_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+// one of the connections counted in `OPEN_CONNECTIONS`, given back when the connection's task ends or is aborted
+struct ConnectionSlot;
+
+impl ConnectionSlot {
+    fn acquire(limit: usize) -> Option<Self> {
+        OPEN_CONNECTIONS
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| (open < limit).then_some(open + 1))
+            .ok()
+            .map(|_| ConnectionSlot)
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// a connection to a peer over any transport the wire protocol can run on
+pub trait PeerStream: AsyncRead + AsyncWrite + Send + Sync + Unpin + std::fmt::Debug + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin + std::fmt::Debug + 'static> PeerStream for T {}
 
 #[derive(Debug, Error)]
 pub enum PeerError {
@@ -31,22 +92,64 @@ pub enum PeerError {
     #[error("peer file hash ({0:?}) does not match requested file hash ({1:?})")]
     MismatchedHash([u8; 20], [u8; 20]),
 
+    #[error("encrypted handshake failed: {0}")]
+    EncryptionHandshakeFailed(&'static str),
+    #[error("error during encrypted handshake: {0:?}")]
+    EncryptionIoError(std::io::Error),
+
     #[error("unknown message id {0}")]
     UnknownMessageId(u8),
     #[error("error encountered while reading {1} bytes: {0:?}")]
     MessageReceiveError(tokio::io::Error, usize),
     #[error("error encountered while sending {1} bytes: {0:?}")]
     MessageTransmitError(tokio::io::Error, usize),
-    #[error("expected Piece message to have at least 8 bytes but only received {0} bytes")]
-    PieceMessageTooSmall(usize),
+    #[error("{0:?} message payload is too short at {1} bytes")]
+    MessageTooSmall(MessageId, usize),
+    #[error("{0:?} message payload exceeds the limit at {1} bytes")]
+    MessageTooLarge(MessageId, usize),
+    #[error("malformed extension message: {0}")]
+    MalformedExtensionMessage(&'static str),
+    #[error("malformed Bitfield or Have message: {0}")]
+    MalformedBitfield(BitfieldError),
+    #[error("piece {0} is beyond the end of the torrent")]
+    PieceOutOfRange(u32),
+    #[error("block of {2} bytes at offset {1} is not within piece {0}")]
+    InvalidBlock(u32, u32, u32),
 
-    #[error("peer {0} has no more pieces available")]
-    Exhausted(String),
+    #[error("peer runs {0}, which the client filter rejects")]
+    ClientRejected(String),
+    #[error("peer is banned")]
+    Banned,
+    #[error("connected to ourselves")]
+    SelfConnection,
+    #[error("already connected to this peer as {0}")]
+    DuplicateConnection(SocketAddr),
+    #[error("no peers could be found")]
+    NoPeers,
+    #[error("download interrupted")]
+    Interrupted,
+    #[error("no pieces of the torrent were found in {0}")]
+    NoDataToSeed(String),
+    #[error("unable to listen for peers on port {0}: {1:?}")]
+    ListenError(u16, std::io::Error),
 
     #[error("unable to save piece {0} to disk: {1:?}")]
     DiskError(u32, tokio::io::Error),
 }
 
+impl PeerError {
+    // errors only a misbehaving peer causes, for which it is not connected to again
+    pub fn is_protocol_violation(&self) -> bool {
+        matches!(self,
+            PeerError::MessageTooSmall(..)
+            | PeerError::MessageTooLarge(..)
+            | PeerError::MalformedExtensionMessage(_)
+            | PeerError::MalformedBitfield(_)
+            | PeerError::PieceOutOfRange(_)
+            | PeerError::InvalidBlock(..))
+    }
+}
+
 #[derive(Debug)]
 pub struct Bitfield {
     masks: Vec<u8>,
@@ -56,7 +159,8 @@ pub struct Bitfield {
 
 impl From<Vec<u8>> for Bitfield {
     fn from(v: Vec<u8>) -> Self {
-        let num: usize = 8 * (v.len() - 1) + v.last().unwrap().count_ones() as usize;
+        // the piece count is unknown until checked with `try_from_vec`
+        let num: usize = 8 * v.len();
         Bitfield::from_vec(v, num)
     }
 }
@@ -65,6 +169,10 @@ impl From<Vec<u8>> for Bitfield {
 pub enum BitfieldError {
     #[error("cannot represent {num_fields} bits with only {num_elements} bytes")]
     Unrepresentible{num_fields: usize, num_elements: usize},
+    #[error("{num_elements} bytes is more than needed for {num_fields} bits")]
+    Oversized{num_fields: usize, num_elements: usize},
+    #[error("spare bits after the last of {0} pieces are set")]
+    SpareBitsSet(usize),
     #[error("piece {0} is out of range of this Bitfield")]
     PieceOutOfRange(usize),
 }
@@ -81,6 +189,13 @@ impl Bitfield {
         if v.len() < num_elements {
             return Err(BitfieldError::Unrepresentible { num_fields: num, num_elements: v.len() });
         }
+        if v.len() > num_elements {
+            return Err(BitfieldError::Oversized { num_fields: num, num_elements: v.len() });
+        }
+        let extra = num % 8;
+        if extra != 0 && v.last().is_some_and(|&last| last & (0xFF >> extra) != 0) {
+            return Err(BitfieldError::SpareBitsSet(num));
+        }
         Ok(Self::from_vec(v, num))
     }
 
@@ -143,66 +258,298 @@ impl Bitfield {
     }
 
     pub fn all(&self) -> bool {
-        let n = self.masks.len() - 1;
-        self.masks[n] == self.last_mask && self.masks[..n].iter().all(|&e| e == 0xFF)
+        let Some((&last, rest)) = self.masks.split_last() else {
+            return self.num == 0
+        };
+        last == self.last_mask && rest.iter().all(|&e| e == 0xFF)
     }
 
     pub fn none(&self) -> bool {
         self.masks.iter().all(|&e| e == 0x00)
     }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.masks
+    }
 }
 
-pub async fn download(
-    peers: &[SocketAddrV4],
-    file: &TorrentFile,
-    dir_path: &Path,
-    ) -> Result<(), PeerError> {
-    let mut tasks = Vec::with_capacity(peers.len());
-    let dir_arc = Arc::new(dir_path.to_path_buf());
-    let info = FileDownloadInfo::from(file);
-    let info_arc: Arc<FileDownloadInfo> = Arc::new(info);
-    let state = FileDownloadState::new(file.num_pieces);
-    let state_arc = Arc::new(Mutex::new(state));
-
-    let pb = ProgressBar::new(file.total_num_bytes);
-
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("[{elapsed_precise}] (ETA: {eta}) [{bar:40.cyan/blue}] ({percent}%) {bytes}/{total_bytes} ({bytes_per_sec})")
-            .unwrap(),
-    );
-
-    for peer in peers {
-        let peer_copy = *peer;
-        let info_clone = info_arc.clone();
-        let state_clone = state_arc.clone();        
-        let dir_clone = dir_arc.clone();
-        let pb_clone = pb.clone();
-        
-        info!("spawning task to collaboratively download '{}' from {}", &file.filename, peer_copy);
-
-        tasks.push(tokio::spawn(async move {
-            let mut downloader = Downloader::new(
-                peer_copy,
-                info_clone,
-                state_clone,
-                dir_clone,
-                pb_clone
-            ).await.map_err(|e| PeerError::ConnectionError(peer_copy.to_string(), e))?;
-            downloader.download_pieces().await
-        }));
-    }
-
-    for (i, task) in tasks.into_iter().enumerate() {
-        match task.await {
-            Ok(Ok(())) => info!("... exiting"),
-            Ok(Err(e)) => error!("peer {} took error {:?}", peers[i], e),
-            Err(e) => error!("peer {} took error {:?}", peers[i], e),
+// the connections of a single torrent, both those we make and those we accept
+pub struct Swarm {
+    session: Arc<Session>,
+    listener: Option<TcpListener>,
+    tasks: JoinSet<(SocketAddr, Result<(), PeerError>)>,
+    active: HashSet<SocketAddr>,
+    pool: PeerPool,
+    max_connections: usize,
+    max_torrent_connections: usize,
+    // addresses of peers whose client the filter rejects, which are not connected to again
+    rejected: HashSet<IpAddr>,
+    // addresses of peers that broke the protocol, shunned for this session only; the ban list is kept for corrupt data
+    banned: HashSet<IpAddr>,
+    choker: AbortHandle,
+}
+
+// the next peer to connect over either transport
+async fn accept(listener: &Option<TcpListener>, utp: Option<&UtpSocket>) -> std::io::Result<(Box<dyn PeerStream>, SocketAddr)> {
+    let tcp = async {
+        match listener {
+            Some(listener) => listener.accept().await.map(|(c, peer)| (Box::new(c) as Box<dyn PeerStream>, peer)),
+            None => std::future::pending().await,
         }
+    };
+    let utp = async {
+        match utp {
+            Some(utp) => utp.accept().await.map(|(c, peer)| (Box::new(c) as Box<dyn PeerStream>, peer)),
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        accepted = tcp => accepted,
+        accepted = utp => accepted,
     }
+}
 
-    info!("download of {} complete", file.filename);
-    pb.finish();
+impl Drop for Swarm {
+    fn drop(&mut self) {
+        self.choker.abort();
+    }
+}
+
+impl Swarm {
+    pub async fn new(
+        file: &TorrentFile,
+        config: &ClientConfig,
+        dir_path: &Path,
+        state: Arc<Mutex<FileDownloadState>>,
+        discovered: mpsc::UnboundedSender<Vec<SocketAddr>>,
+        ) -> Result<Self, PeerError> {
+        let pb = ProgressBar::new(file.total_num_bytes);
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] (ETA: {eta}) [{bar:40.cyan/blue}] ({percent}%) {bytes}/{total_bytes} ({bytes_per_sec})")
+                .unwrap(),
+        );
+        pb.set_position(file.total_num_bytes - state.lock().await.left);
+
+        let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], config.port)))
+            .await
+            .map_err(|e| PeerError::ListenError(config.port, e))?;
+        info!("accepting peers on port {}", config.port);
+
+        let utp = match config.utp {
+            true => match UtpSocket::bind(SocketAddr::from(([0, 0, 0, 0], config.port))).await {
+                Ok(socket) => {
+                    info!("accepting uTP peers on port {}", config.port);
+                    Some(Arc::new(socket))
+                },
+                Err(e) => {
+                    warn!("unable to accept uTP peers on port {}: {:?}", config.port, e);
+                    None
+                },
+            },
+            false => None,
+        };
+
+        let session = Session {
+            info: FileDownloadInfo::new(file, config),
+            state,
+            dir: dir_path.to_path_buf(),
+            pb,
+            discovered,
+            haves: broadcast::channel(HAVE_BUFFER).0,
+            blocks: broadcast::channel(HAVE_BUFFER).0,
+            peers: std::sync::Mutex::new(HashMap::new()),
+            rechoke: Notify::new(),
+            utp,
+            peer_ids: std::sync::Mutex::new(HashMap::new()),
+            bans: std::sync::Mutex::new(BanList::load(config.ban_list.clone())),
+        };
+        let session = Arc::new(session);
+        let choker = tokio::spawn(Choker::new(config.upload_slots).run(session.clone())).abort_handle();
+        Ok(Swarm {
+            session,
+            listener: Some(listener),
+            tasks: JoinSet::new(),
+            active: HashSet::new(),
+            pool: PeerPool::default(),
+            max_connections: config.max_connections,
+            max_torrent_connections: config.max_torrent_connections,
+            rejected: HashSet::new(),
+            banned: HashSet::new(),
+            choker,
+        })
+    }
 
-    Ok(())
+    // the socket uTP connections share with the DHT, if uTP is on
+    pub fn utp(&self) -> Option<Arc<UtpSocket>> {
+        self.session.utp.clone()
+    }
+
+    // switches where pieces are served from, e.g. once they are written out as the torrent's files
+    pub async fn set_source(&self, source: PieceSource) {
+        self.session.state.lock().await.source = Arc::new(source);
+    }
+
+    fn offer(&mut self, batch: Vec<SocketAddr>) {
+        for peer in batch {
+            self.pool.add(peer, |peer| self.active.contains(peer));
+        }
+        self.fill();
+    }
+
+    fn excluded(&self, ip: &IpAddr) -> bool {
+        self.rejected.contains(ip) || self.banned.contains(ip) || self.session.bans.lock().unwrap().contains(ip)
+    }
+
+    fn slot(&self) -> Option<ConnectionSlot> {
+        if self.tasks.len() >= self.max_torrent_connections {
+            return None
+        }
+        ConnectionSlot::acquire(self.max_connections)
+    }
+
+    // connects to waiting peers until out of peers or up against a connection limit
+    fn fill(&mut self) {
+        while let Some(peer) = self.pool.next(|peer| self.active.contains(peer) || self.excluded(&peer.ip())) {
+            let Some(slot) = self.slot() else {
+                return
+            };
+            self.active.insert(peer);
+            info!("spawning task to exchange pieces with {}", peer);
+            let session = self.session.clone();
+            self.tasks.spawn(async move {
+                let _slot = slot;
+                let result = async {
+                    let mut connected = timeout(CONNECT_TIMEOUT, Downloader::connect(peer, session)).await
+                        .map_err(|_| PeerError::ConnectionError(peer.to_string(), std::io::ErrorKind::TimedOut.into()))??;
+                    connected.run().await
+                }.await;
+                (peer, result)
+            });
+        }
+    }
+
+    fn admit(&mut self, connection: Box<dyn PeerStream>, peer: SocketAddr) {
+        if self.excluded(&peer.ip()) || self.active.contains(&peer) {
+            return
+        }
+        let Some(slot) = self.slot() else {
+            info!("turning away peer {}: at the connection limit", peer);
+            return
+        };
+        self.active.insert(peer);
+        info!("peer {} connected to us", peer);
+        let session = self.session.clone();
+        self.tasks.spawn(async move {
+            let _slot = slot;
+            let result = async {
+                let mut accepted = timeout(CONNECT_TIMEOUT, Downloader::accept(peer, connection, session)).await
+                    .map_err(|_| PeerError::ConnectionError(peer.to_string(), std::io::ErrorKind::TimedOut.into()))??;
+                accepted.run().await
+            }.await;
+            (peer, result)
+        });
+    }
+
+    async fn reap(&mut self, joined: Result<(SocketAddr, Result<(), PeerError>), JoinError>) {
+        match joined {
+            Ok((peer, result)) => {
+                match result {
+                    Ok(()) => {
+                        info!("peer {} exiting", peer);
+                        self.pool.closed(peer);
+                    },
+                    Err(e) if e.is_protocol_violation() => {
+                        error!("peer {} violated the protocol and is banned for this session: {}", peer, e);
+                        self.banned.insert(peer.ip());
+                        self.pool.forget(peer);
+                    },
+                    // the filter will not change its mind, so the peer is not tried again
+                    Err(e @ PeerError::ClientRejected(_)) => {
+                        info!("disconnected from peer {}: {}", peer, e);
+                        self.rejected.insert(peer.ip());
+                        self.pool.forget(peer);
+                    },
+                    Err(PeerError::Banned) => {
+                        info!("disconnected from banned peer {}", peer);
+                        self.pool.forget(peer);
+                    },
+                    // trackers hand us back our own address among the peers
+                    Err(PeerError::SelfConnection) => {
+                        info!("peer {} is ourselves; not connecting to it again", peer);
+                        self.pool.mark_own(peer);
+                    },
+                    Err(e @ PeerError::DuplicateConnection(_)) => {
+                        info!("dropped connection to peer {}: {}", peer, e);
+                        self.pool.closed(peer);
+                    },
+                    Err(e) => {
+                        error!("peer {} took error {:?}", peer, e);
+                        self.pool.failed(peer);
+                    },
+                }
+                self.active.remove(&peer);
+                self.session.state.lock().await.connected.remove(&peer);
+            },
+            Err(e) => error!("peer task took error {:?}", e),
+        }
+    }
+
+    // exchanges pieces until every piece has been downloaded
+    pub async fn download(
+        &mut self,
+        peers: &mut mpsc::UnboundedReceiver<Vec<SocketAddr>>,
+        announcer: &mpsc::UnboundedSender<AnnounceEvent>,
+        ) -> Result<(), PeerError> {
+        let mut haves = self.session.haves.subscribe();
+        let mut fill = interval(FILL_INTERVAL);
+        fill.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut idle_since: Option<Instant> = None;
+        while !self.session.state.lock().await.is_complete() {
+            tokio::select! {
+                Some(batch) = peers.recv() => self.offer(batch),
+                Ok((connection, peer)) = accept(&self.listener, self.session.utp.as_deref()) => self.admit(connection, peer),
+                Some(joined) = self.tasks.join_next() => {
+                    self.reap(joined).await;
+                    self.fill();
+                    if self.tasks.is_empty() {
+                        info!("no peers remain; requesting more from trackers");
+                        let _ = announcer.send(AnnounceEvent::None);
+                    }
+                },
+                _ = haves.recv() => (),
+                _ = fill.tick() => {
+                    self.fill();
+                    if !self.pool.is_empty() || !self.tasks.is_empty() {
+                        idle_since = None;
+                    } else if idle_since.get_or_insert_with(Instant::now).elapsed() >= NO_PEERS_TIMEOUT {
+                        return Err(PeerError::NoPeers)
+                    }
+                },
+            }
+        }
+
+        info!("download complete");
+        self.session.pb.finish();
+        Ok(())
+    }
+
+    // serves pieces to peers until cancelled
+    pub async fn seed(&mut self, peers: &mut mpsc::UnboundedReceiver<Vec<SocketAddr>>) {
+        self.session.pb.println("seeding until interrupted");
+        let mut fill = interval(FILL_INTERVAL);
+        fill.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                Some(batch) = peers.recv() => self.offer(batch),
+                Ok((connection, peer)) = accept(&self.listener, self.session.utp.as_deref()) => self.admit(connection, peer),
+                Some(joined) = self.tasks.join_next() => {
+                    self.reap(joined).await;
+                    self.fill();
+                },
+                _ = fill.tick() => self.fill(),
+                else => (),
+            }
+        }
+    }
 }
@@ -0,0 +1,96 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use tracing::{info, warn};
+
+use crate::metadata::bencode::BencodeValue;
+use crate::util::sha1::sha1_hash;
+
+// addresses never connected to again, kept between runs when given a file
+#[derive(Debug, Default)]
+pub struct BanList {
+    banned: HashSet<IpAddr>,
+    path: Option<PathBuf>,
+}
+
+impl BanList {
+    // the file is a dictionary whose `banned` list holds each address as a string
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let mut bans = BanList { banned: HashSet::new(), path };
+        let Some(path) = &bans.path else {
+            return bans
+        };
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                info!("starting with no banned peers: unable to read {:?}: {:?}", path, e);
+                return bans
+            },
+        };
+        match BencodeValue::try_from(contents.as_slice()) {
+            Ok(BencodeValue::Dictionary(items)) => if let Some(BencodeValue::List(addresses)) = items.get(b"banned".as_slice()) {
+                bans.banned = addresses
+                    .iter()
+                    .filter_map(|address| match address {
+                        BencodeValue::ByteString(s) => std::str::from_utf8(s).ok()?.parse().ok(),
+                        _ => None,
+                    })
+                    .collect();
+            },
+            _ => warn!("ignoring malformed ban list {:?}", path),
+        }
+        info!("loaded {} banned peers from {:?}", bans.banned.len(), path);
+        bans
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.banned.contains(ip)
+    }
+
+    // bans the address, saving the list if it is new
+    pub fn ban(&mut self, ip: IpAddr) {
+        if self.banned.insert(ip) {
+            self.save();
+        }
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return
+        };
+        let mut addresses: Vec<String> = self.banned.iter().map(IpAddr::to_string).collect();
+        addresses.sort();
+        let list = addresses.into_iter().map(|a| BencodeValue::ByteString(a.into_bytes())).collect();
+        let items = BTreeMap::from([(b"banned".to_vec(), BencodeValue::List(list))]);
+        if let Err(e) = fs::write(path, Vec::from(&BencodeValue::Dictionary(items))) {
+            warn!("unable to save ban list to {:?}: {:?}", path, e);
+        }
+    }
+}
+
+// the blocks of a piece that failed its hash check, with who sent each, kept until the piece verifies
+#[derive(Debug, Default)]
+pub struct SuspectBlocks {
+    blocks: Vec<(u32, IpAddr, [u8; 20])>,
+}
+
+impl SuspectBlocks {
+    pub fn record(&mut self, begin: u32, from: IpAddr, block: &[u8]) {
+        self.blocks.push((begin, from, sha1_hash(block)));
+    }
+
+    // senders of blocks that differ from the same block of the verified piece
+    pub fn culprits(&self, piece: &[u8], block_size: usize) -> HashSet<IpAddr> {
+        self.blocks
+            .iter()
+            .filter(|(begin, _, hash)| {
+                let begin = *begin as usize;
+                let block = &piece[begin..(begin + block_size).min(piece.len())];
+                sha1_hash(block) != *hash
+            })
+            .map(|(_, from, _)| *from)
+            .collect()
+    }
+}
@@ -0,0 +1,113 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::info;
+
+use crate::peer::downloader::Session;
+
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+const UNCHOKE_INTERVAL: Duration = Duration::from_secs(10);
+// the optimistic unchoke moves to another peer every third round, i.e. every 30 seconds
+const OPTIMISTIC_ROUNDS: u32 = 3;
+// a peer that sends no blocks for this long while we wait on requests is snubbing us
+pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+// what a connection reports to the choker
+#[derive(Debug, Default)]
+pub struct PeerCounters {
+    // the client the peer runs, as its peer id or extended handshake names it
+    pub client: std::sync::Mutex<String>,
+    pub downloaded: AtomicU64,
+    pub uploaded: AtomicU64,
+    pub interested: AtomicBool,
+    pub snubbed: AtomicBool,
+}
+
+// how the choker reaches a connection: its counters, and a channel taking whether it should choke
+#[derive(Debug)]
+pub struct PeerLink {
+    pub counters: Arc<PeerCounters>,
+    pub choke: mpsc::UnboundedSender<bool>,
+}
+
+#[derive(Debug)]
+pub struct Choker {
+    slots: usize,
+    round: u32,
+    optimistic: Option<SocketAddr>,
+    // bytes transferred with each peer over the last round
+    rates: HashMap<SocketAddr, u64>,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Choker { slots: slots.max(1), round: 0, optimistic: None, rates: HashMap::new() }
+    }
+
+    // re-evaluates every interval, and whenever a peer's interest changes
+    pub async fn run(mut self, session: Arc<Session>) {
+        let mut ticks = interval(UNCHOKE_INTERVAL);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            let rotate = tokio::select! {
+                _ = ticks.tick() => true,
+                _ = session.rechoke.notified() => false,
+            };
+            let seeding = session.state.lock().await.is_complete();
+            let peers = session.peers.lock().unwrap();
+            let unchoked = self.choose(&peers, seeding, rotate);
+            for (address, link) in peers.iter() {
+                let _ = link.choke.send(!unchoked.contains(address));
+            }
+        }
+    }
+
+    // the peers to unchoke: the fastest interested peers by download rate (upload rate when seeding),
+    // leaving out those snubbing us, plus one chosen at random
+    fn choose(&mut self, peers: &HashMap<SocketAddr, PeerLink>, seeding: bool, rotate: bool) -> HashSet<SocketAddr> {
+        if rotate {
+            self.rates = peers
+                .iter()
+                .map(|(address, link)| {
+                    let downloaded = link.counters.downloaded.swap(0, Ordering::Relaxed);
+                    let uploaded = link.counters.uploaded.swap(0, Ordering::Relaxed);
+                    (*address, if seeding { uploaded } else { downloaded })
+                })
+                .collect();
+            self.round += 1;
+        }
+
+        let interested = |link: &PeerLink| link.counters.interested.load(Ordering::Relaxed);
+        let mut candidates: Vec<SocketAddr> = peers
+            .iter()
+            .filter(|(_, link)| interested(link) && (seeding || !link.counters.snubbed.load(Ordering::Relaxed)))
+            .map(|(address, _)| *address)
+            .collect();
+        candidates.sort_by_key(|address| Reverse(self.rates.get(address).copied().unwrap_or(0)));
+        let mut unchoked: HashSet<SocketAddr> = candidates.into_iter().take(self.slots - 1).collect();
+
+        let keep = self.optimistic.is_some_and(|address| {
+            !unchoked.contains(&address) && peers.get(&address).is_some_and(interested)
+        });
+        if !keep || (rotate && self.round.is_multiple_of(OPTIMISTIC_ROUNDS)) {
+            let choked: Vec<SocketAddr> = peers
+                .iter()
+                .filter(|(address, link)| interested(link) && !unchoked.contains(address))
+                .map(|(address, _)| *address)
+                .collect();
+            self.optimistic = (!choked.is_empty()).then(|| choked[rand::random_range(0..choked.len())]);
+            if let Some(address) = self.optimistic {
+                let client = peers[&address].counters.client.lock().unwrap().clone();
+                info!("optimistically unchoking peer {} ({})", address, client);
+            }
+        }
+        unchoked.extend(self.optimistic);
+        unchoked
+    }
+}
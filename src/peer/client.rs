@@ -0,0 +1,260 @@
+use std::fmt;
+
+// two letter codes of Azureus-style ids, `-` + code + four version characters + `-`
+const AZUREUS_CLIENTS: &[(&[u8; 2], &str)] = &[
+    (b"7T", "aTorrent"),
+    (b"AG", "Ares"),
+    (b"A~", "Ares"),
+    (b"AR", "Arctic"),
+    (b"AT", "Artemis"),
+    (b"AX", "BitPump"),
+    (b"AZ", "Vuze"),
+    (b"BB", "BitBuddy"),
+    (b"BC", "BitComet"),
+    (b"BE", "BitTorrent SDK"),
+    (b"BF", "Bitflu"),
+    (b"BI", "BiglyBT"),
+    (b"BL", "BitCometLite"),
+    (b"BR", "BitRocket"),
+    (b"BT", "BitTorrent"),
+    (b"BW", "BitWombat"),
+    (b"BX", "Bittorrent X"),
+    (b"CD", "Enhanced CTorrent"),
+    (b"CT", "CTorrent"),
+    (b"DE", "Deluge"),
+    (b"DP", "Propagate Data Client"),
+    (b"EB", "EBit"),
+    (b"ES", "electric sheep"),
+    (b"FC", "FileCroc"),
+    (b"FD", "Free Download Manager"),
+    (b"FT", "FoxTorrent"),
+    (b"FW", "FrostWire"),
+    (b"FX", "Freebox BitTorrent"),
+    (b"GS", "GSTorrent"),
+    (b"HK", "Hekate"),
+    (b"HL", "Halite"),
+    (b"HN", "Hydranode"),
+    (b"KG", "KGet"),
+    (b"KT", "KTorrent"),
+    (b"LC", "LeechCraft"),
+    (b"LH", "LH-ABC"),
+    (b"LP", "Lphant"),
+    (b"LT", "libtorrent"),
+    (b"lt", "libTorrent"),
+    (b"LW", "LimeWire"),
+    (b"MO", "MonoTorrent"),
+    (b"MP", "MooPolice"),
+    (b"MR", "Miro"),
+    (b"MT", "MoonlightTorrent"),
+    (b"NX", "Net Transport"),
+    (b"OS", "OneSwarm"),
+    (b"OT", "OmegaTorrent"),
+    (b"PB", "Protocol::BitTorrent"),
+    (b"PD", "Pando"),
+    (b"PI", "PicoTorrent"),
+    (b"PT", "PHPTracker"),
+    (b"qB", "qBittorrent"),
+    (b"QD", "QQDownload"),
+    (b"QT", "Qt 4 Torrent example"),
+    (b"RT", "Retriever"),
+    (b"RZ", "RezTorrent"),
+    (b"S~", "Shareaza alpha/beta"),
+    (b"SB", "Swiftbit"),
+    (b"SD", "Thunder"),
+    (b"SM", "SoMud"),
+    (b"SP", "BitSpirit"),
+    (b"SS", "SwarmScope"),
+    (b"ST", "SymTorrent"),
+    (b"st", "sharktorrent"),
+    (b"SZ", "Shareaza"),
+    (b"TB", "Torch"),
+    (b"TE", "terasaur Seed Bank"),
+    (b"TL", "Tribler"),
+    (b"TN", "TorrentDotNET"),
+    (b"TR", "Transmission"),
+    (b"TS", "Torrentstorm"),
+    (b"TT", "TuoTu"),
+    (b"TU", "Torrentium"),
+    (b"UL", "uLeecher!"),
+    (b"UM", "µTorrent for Mac"),
+    (b"UT", "µTorrent"),
+    (b"UW", "µTorrent Web"),
+    (b"VG", "Vagaa"),
+    (b"WD", "WebTorrent Desktop"),
+    (b"WT", "BitLet"),
+    (b"WW", "WebTorrent"),
+    (b"WY", "FireTorrent"),
+    (b"XF", "Xfplay"),
+    (b"XL", "Xunlei"),
+    (b"XS", "XSwifter"),
+    (b"XT", "XanTorrent"),
+    (b"XX", "Xtorrent"),
+    (b"ZT", "ZipTorrent"),
+];
+
+// the leading character of Shadow-style ids, followed by up to five version characters and dashes
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow's client"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+// the leading character of Mainline-style ids, such as `M4-3-6--`
+const MAINLINE_CLIENTS: &[(u8, &str)] = &[
+    (b'M', "Mainline"),
+    (b'Q', "Queen Bee"),
+];
+
+// clients that put their own marker at the start of the id
+const PREFIXED_CLIENTS: &[(&[u8], &str)] = &[
+    (b"exbc", "BitComet"),
+    (b"FUTB", "FuTorrent"),
+    (b"-BOW", "BitsOnWheels"),
+    (b"-G3", "G3 Torrent"),
+    (b"-ML", "MLDonkey"),
+    (b"-FG", "FlashGet"),
+    (b"XBT", "XBT Client"),
+    (b"OP", "Opera"),
+    (b"btpd", "BT Protocol Daemon"),
+    (b"Plus", "Plus!"),
+    (b"turbobt", "TurboBT"),
+    (b"BTDWV-", "Deadman Walking"),
+    (b"Deadman Walking-", "Deadman"),
+    (b"eX", "eXeem"),
+    (b"346-", "TorrenTopia"),
+    (b"LIME", "Limewire"),
+    (b"Pando", "Pando"),
+    (b"martini", "Martini Man"),
+    (b"a00---0", "Swarmy"),
+    (b"a02---0", "Swarmy"),
+    (b"T00---0", "Teeweety"),
+    (b"-WS", "HTTP seed"),
+];
+
+// the client a peer id names, by the convention it follows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientId {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{} {}", self.name, version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+impl ClientId {
+    pub fn decode(peer_id: &[u8; 20]) -> Option<Self> {
+        decode_azureus(peer_id)
+            .or_else(|| decode_mainline(peer_id))
+            .or_else(|| decode_prefixed(peer_id))
+            .or_else(|| decode_shadow(peer_id))
+    }
+}
+
+// e.g. `-qB4650-` is qBittorrent 4.6.5 and `-UT355W-` µTorrent 3.5.5
+fn decode_azureus(peer_id: &[u8; 20]) -> Option<ClientId> {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return None
+    }
+    let code: &[u8; 2] = peer_id[1..3].try_into().expect("peer id is 20 bytes");
+    let &(_, name) = AZUREUS_CLIENTS.iter().find(|(c, _)| *c == code)?;
+    let digits = &peer_id[3..7];
+    if !digits.iter().all(u8::is_ascii_alphanumeric) {
+        return None
+    }
+    let version = match code {
+        // the last character is a release type rather than part of the version
+        b"UT" | b"UM" | b"UW" | b"UL" => join_version(&digits[..3], |c| (c as char).to_digit(16)),
+        _ => join_version(digits, |c| (c as char).to_digit(36)),
+    };
+    Some(ClientId { name: name.to_string(), version })
+}
+
+// e.g. `M4-3-6--` is Mainline 4.3.6 and `M7-10-4-` Mainline 7.10.4
+fn decode_mainline(peer_id: &[u8; 20]) -> Option<ClientId> {
+    let &(_, name) = MAINLINE_CLIENTS.iter().find(|(c, _)| *c == peer_id[0])?;
+    let rest = &peer_id[1..8];
+    let mut fields = rest.split(|&b| b == b'-');
+    let version: Vec<&[u8]> = fields.by_ref().take(3).collect();
+    if version.len() != 3 || version.iter().any(|f| f.is_empty() || !f.iter().all(u8::is_ascii_digit)) {
+        return None
+    }
+    let version = version.iter().map(|f| String::from_utf8_lossy(f)).collect::<Vec<_>>().join(".");
+    Some(ClientId { name: name.to_string(), version: Some(version) })
+}
+
+fn decode_prefixed(peer_id: &[u8; 20]) -> Option<ClientId> {
+    let &(_, name) = PREFIXED_CLIENTS.iter().find(|(prefix, _)| peer_id.starts_with(prefix))?;
+    Some(ClientId { name: name.to_string(), version: None })
+}
+
+// e.g. `S58B-----` is Shadow's client 5.8.11, each character a base 64 digit
+fn decode_shadow(peer_id: &[u8; 20]) -> Option<ClientId> {
+    let &(_, name) = SHADOW_CLIENTS.iter().find(|(c, _)| *c == peer_id[0])?;
+    let end = peer_id[1..7].iter().position(|&b| b == b'-')? + 1;
+    if end == 1 || !peer_id[end..9].iter().all(|&b| b == b'-') {
+        return None
+    }
+    let version = join_version(&peer_id[1..end], shadow_digit);
+    version.is_some().then(|| ClientId { name: name.to_string(), version })
+}
+
+fn shadow_digit(c: u8) -> Option<u32> {
+    match c {
+        b'0'..=b'9' => Some((c - b'0') as u32),
+        b'A'..=b'Z' => Some((c - b'A') as u32 + 10),
+        b'a'..=b'z' => Some((c - b'a') as u32 + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
+
+// version components, dropping trailing zeros past the minor version
+fn join_version(digits: &[u8], digit: impl Fn(u8) -> Option<u32>) -> Option<String> {
+    let mut parts = digits.iter().map(|&c| digit(c)).collect::<Option<Vec<u32>>>()?;
+    while parts.len() > 2 && parts.last() == Some(&0) {
+        parts.pop();
+    }
+    Some(parts.iter().map(u32::to_string).collect::<Vec<_>>().join("."))
+}
+
+// what to call a peer's client: the extended handshake's `v` where sent, since peer ids are easily mimicked,
+// otherwise whatever the peer id names
+pub fn describe(peer_id: Option<&ClientId>, v: Option<&str>) -> String {
+    match (v, peer_id) {
+        (Some(v), _) => v.to_string(),
+        (None, Some(client)) => client.to_string(),
+        (None, None) => "unknown client".to_string(),
+    }
+}
+
+// which clients may connect, each entry matched case insensitively against a client's name
+#[derive(Debug, Clone, Default)]
+pub struct ClientFilter {
+    // when not empty, only clients matching one of these
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl ClientFilter {
+    // every name the peer goes by must pass; a peer that names no client passes only when nothing is allow-listed
+    pub fn permits(&self, names: &[&str]) -> bool {
+        let matches = |patterns: &[String], name: &str| {
+            let name = name.to_lowercase();
+            patterns.iter().any(|p| name.contains(&p.to_lowercase()))
+        };
+        if names.iter().any(|name| matches(&self.deny, name)) {
+            return false
+        }
+        self.allow.is_empty() || (!names.is_empty() && names.iter().all(|name| matches(&self.allow, name)))
+    }
+}
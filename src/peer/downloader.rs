@@ -1,121 +1,377 @@
-use std::collections::HashSet;
-use std::net::SocketAddrV4;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::Entry;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 
 use indicatif::ProgressBar;
 use tokio::net::TcpStream;
 use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
-use tokio::sync::Mutex;
+use tokio::io::{AsyncWriteExt, WriteHalf};
+use tokio::sync::{broadcast, mpsc, Mutex, Notify};
+use tokio::task::AbortHandle;
+use tokio::time::{interval, MissedTickBehavior};
 use std::path::{Path, PathBuf};
-use tracing::{info, error};
+use tracing::{info, error, warn};
 
+use crate::config::ClientConfig;
 use crate::metadata::file::TorrentFile;
-use crate::peer::{Bitfield, PeerError};
-use crate::peer::handshake::handshake;
-use crate::peer::message::Message;
+use crate::peer::{Bitfield, PeerError, PeerStream};
+use crate::peer::ban::{BanList, SuspectBlocks};
+use crate::peer::choker::{PeerCounters, PeerLink, SNUB_TIMEOUT};
+use crate::peer::client::{self, ClientFilter, ClientId};
+use crate::config::CLIENT_NAME;
+use crate::peer::extension::{self, ExtendedHandshake, ExtensionRegistry};
+use crate::peer::fast;
+use crate::peer::handshake::{accept_handshake, handshake};
+use crate::peer::message::{Message, MessageReader, MAX_BLOCK_LENGTH};
+use crate::peer::mse::{self, EncryptionPolicy};
+use crate::peer::pex::{FLAG_REACHABLE, FLAG_SEED, PexState};
+use crate::peer::picker::PiecePicker;
+use crate::peer::utp::UtpSocket;
+use crate::util::io::PieceSource;
 use crate::util::sha1::sha1_hash;
 use crate::util::to_string;
 
 const BLOCK_SIZE: u32 = 16 * 1024;
+// requests queued from a peer beyond this are dropped; advertised as `reqq`
+const MAX_QUEUED_UPLOADS: usize = 250;
+// suggestions remembered from a peer, newest replacing oldest
+const MAX_SUGGESTED: usize = 16;
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+// how often PEX is sent and interest is re-checked, since pieces requeued by other connections are not announced
+const TICK_INTERVAL: Duration = Duration::from_secs(10);
+// messages read ahead of the connection's event loop
+const MESSAGE_BUFFER: usize = 16;
+
+// outstanding requests are sized to keep this much transfer queued at the peer's measured rate
+const QUEUE_TIME: Duration = Duration::from_secs(3);
+const MIN_QUEUE_DEPTH: usize = 4;
+const MAX_QUEUE_DEPTH: usize = 250;
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+// weight of each new sample in the smoothed rate and latency
+const SMOOTHING: f64 = 0.25;
+
+// everything the connections of a torrent share
+#[derive(Debug)]
+pub struct Session {
+    pub info: FileDownloadInfo,
+    pub state: Arc<Mutex<FileDownloadState>>,
+    pub dir: PathBuf,
+    pub pb: ProgressBar,
+    // peers learned from other peers
+    pub discovered: mpsc::UnboundedSender<Vec<SocketAddr>>,
+    // pieces completed by any connection, to be announced to every peer
+    pub haves: broadcast::Sender<u32>,
+    // blocks received during endgame, as (index, begin), for other connections to cancel
+    pub blocks: broadcast::Sender<(u32, u32)>,
+    // connections the choker decides for
+    pub peers: std::sync::Mutex<HashMap<SocketAddr, PeerLink>>,
+    // asks the choker to re-evaluate early, e.g. when a peer's interest changes
+    pub rechoke: Notify,
+    // carries uTP connections, which are tried before TCP
+    pub utp: Option<Arc<UtpSocket>>,
+    // connections past their handshake by peer id, so a peer reached both ways is only kept once
+    pub peer_ids: std::sync::Mutex<HashMap<[u8; 20], RegisteredPeer>>,
+    // peers that broke the protocol or sent corrupt data
+    pub bans: std::sync::Mutex<BanList>,
+}
 
+#[derive(Debug)]
+pub struct RegisteredPeer {
+    address: SocketAddr,
+    outgoing: bool,
+    // tells the connection that another to the same peer replaced it
+    superseded: Arc<Notify>,
+}
+
+// a connection to a single peer, downloading from it and serving its requests
 #[derive(Debug)]
 pub struct Downloader {
-    pub address: SocketAddrV4,
-    connection: TcpStream,
-    info: Arc<FileDownloadInfo>,
-    shared_state: Arc<Mutex<FileDownloadState>>,
-    skip_set: HashSet<u32>,
-    state: State,
-    dir: Arc<PathBuf>,
-    pb: ProgressBar,
-    progress: PieceDownloadProgress,
+    pub address: SocketAddr,
+    writer: WriteHalf<Box<dyn PeerStream>>,
+    messages: mpsc::Receiver<Result<Message, PeerError>>,
+    reader: AbortHandle,
+    session: Arc<Session>,
+    haves: broadcast::Receiver<u32>,
+    blocks: broadcast::Receiver<(u32, u32)>,
+    counters: Arc<PeerCounters>,
+    // the choker's decisions
+    choke: mpsc::UnboundedReceiver<bool>,
+    // when a block last arrived, or requests started waiting on one
+    last_block: Instant,
+    supports_extensions: bool,
+    // both sides support the fast extension, so requests are rejected rather than silently dropped
+    fast: bool,
+    am_choking: bool,
+    am_interested: bool,
+    peer_choking: bool,
+    peer_interested: bool,
+    peer_pieces: Option<Bitfield>,
+    // pieces being downloaded from this peer, oldest first
+    pieces: Vec<u32>,
+    // blocks awaiting a response, keyed by (index, begin), with their length and when they were requested
+    requested: HashMap<(u32, u32), (u32, Instant)>,
+    throughput: Throughput,
+    // blocks the peer asked for, as (index, begin, length)
+    uploads: VecDeque<(u32, u32, u32)>,
+    extensions: ExtensionRegistry,
+    // the most requests the peer queues, from its extended handshake
+    peer_reqq: Option<u32>,
+    // pieces the peer lets us request while it chokes us
+    allowed_fast: HashSet<u32>,
+    // pieces we serve to the peer while choking it
+    allowed_fast_out: HashSet<u32>,
+    // pieces the peer suggested we download, oldest first
+    suggested: VecDeque<u32>,
+    peer_id: [u8; 20],
+    // the client the peer id names
+    client: Option<ClientId>,
+    superseded: Arc<Notify>,
 }
 
 #[derive(Debug, Clone)]
 pub struct FileDownloadInfo {
     bytes_per_piece: usize,
+    total_bytes: u64,
     piece_hashes: Vec<[u8; 20]>,
     hash: [u8; 20],
+    peer_id: [u8; 20],
+    shares_peers: bool,
+    port: u16,
+    metadata_size: u64,
+    encryption: EncryptionPolicy,
+    client_filter: ClientFilter,
 }
 
 #[derive(Debug)]
 pub struct FileDownloadState {
     done: Bitfield,
-    todo: HashSet<u32>,
+    picker: PiecePicker,
+    // started pieces, including those left unfinished by closed connections so their blocks need not be downloaded again
+    downloading: HashMap<u32, PieceDownloadProgress>,
+    // pieces that failed verification with blocks from several peers, to be downloaded again from a single peer
+    suspects: HashMap<u32, SuspectBlocks>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    // peers we have shaken hands with, with their PEX flags
+    pub connected: HashMap<SocketAddr, u8>,
+    pub source: Arc<PieceSource>,
 }
 
 #[derive(Debug)]
 struct PieceDownloadProgress {
-    offset: u32,
+    piece: u32,
     data: Vec<u8>,
-    length: u32
+    length: u32,
+    // offsets of blocks not yet requested
+    unrequested: VecDeque<u32>,
+    // offsets of blocks received, which may arrive in any order
+    received: HashSet<u32>,
+    // connections downloading this piece; more than one only in endgame
+    active: usize,
+    // who sent each block received, by offset
+    sources: HashMap<u32, IpAddr>,
 }
 
-impl From<&TorrentFile> for FileDownloadInfo {
-    fn from(file: &TorrentFile) -> Self {
+// download rate and request latency measured from a peer
+#[derive(Debug, Default)]
+struct Throughput {
+    // bytes per second
+    rate: f64,
+    latency: Duration,
+    window_start: Option<Instant>,
+    window_bytes: u64,
+}
+
+impl FileDownloadInfo {
+    pub fn new(file: &TorrentFile, config: &ClientConfig) -> Self {
         FileDownloadInfo {
             bytes_per_piece: file.num_bytes_per_piece as usize,
+            total_bytes: file.total_num_bytes,
             piece_hashes: file.piece_hashes.clone(),
-            hash: file.hash
+            hash: file.hash,
+            peer_id: config.peer_id,
+            shares_peers: file.shares_peers(),
+            port: config.port,
+            metadata_size: file.metadata_size,
+            encryption: config.encryption,
+            client_filter: config.client_filter.clone(),
         }
     }
+
+    pub fn num_pieces(&self) -> usize {
+        self.piece_hashes.len()
+    }
+
+    // the last piece is usually shorter than the rest
+    pub fn piece_length(&self, piece: u32) -> u32 {
+        let start = piece as u64 * self.bytes_per_piece as u64;
+        (self.total_bytes - start).min(self.bytes_per_piece as u64) as u32
+    }
 }
 
 impl FileDownloadState {
-    pub fn new(num_pieces: usize) -> Self {
+    pub fn new(num_pieces: usize, num_bytes: u64, source: PieceSource) -> Self {
         FileDownloadState {
             done: Bitfield::new(num_pieces, false),
-            todo: (0..num_pieces as u32).collect()
+            picker: PiecePicker::new(num_pieces),
+            downloading: HashMap::new(),
+            suspects: HashMap::new(),
+            uploaded: 0,
+            downloaded: 0,
+            left: num_bytes,
+            connected: HashMap::new(),
+            source: Arc::new(source),
         }
     }
 
-    pub fn complete(&mut self, piece_index: u32) {
+    // hash checks existing data for seeding; missing pieces are not downloaded
+    pub async fn from_existing(info: &FileDownloadInfo, source: PieceSource) -> Self {
+        let mut state = FileDownloadState::new(info.num_pieces(), info.total_bytes, source);
+        state.picker.clear();
+        for piece in 0..info.num_pieces() as u32 {
+            let length = info.piece_length(piece);
+            match state.source.read(piece, 0, length).await {
+                Ok(data) if sha1_hash(&data) == info.piece_hashes[piece as usize] => state.complete(piece, length as u64),
+                Ok(_) => warn!("piece {} does not match its hash", piece),
+                Err(e) => warn!("unable to read piece {}: {:?}", piece, e),
+            }
+        }
+        state
+    }
+
+    pub fn complete(&mut self, piece_index: u32, num_bytes: u64) {
         self.done.mark_piece(piece_index as usize).unwrap();
+        self.left = self.left.saturating_sub(num_bytes);
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.done.all()
+    }
+
+    pub fn todo_is_empty(&self) -> bool {
+        self.picker.is_empty()
+    }
+
+    pub fn num_complete(&self) -> usize {
+        self.done.num_set()
     }
 
     pub fn requeue(&mut self, piece_index: u32) {
-        self.todo.insert(piece_index);
+        self.picker.requeue(piece_index);
+    }
+
+    // every remaining piece has been handed to a connection, so its blocks may be requested from several peers
+    fn in_endgame(&self) -> bool {
+        self.picker.is_empty() && !self.downloading.is_empty()
+    }
+
+    // a connection stopped downloading `piece`; once no connection is, it goes back to be picked again
+    fn release(&mut self, piece: u32) {
+        if let Some(progress) = self.downloading.get_mut(&piece) {
+            progress.active = progress.active.saturating_sub(1);
+            if progress.active == 0 {
+                progress.reset_requests();
+                // a suspect piece starts over so that the next peer supplies all of it
+                if self.suspects.contains_key(&piece) {
+                    self.downloading.remove(&piece);
+                }
+                self.picker.requeue(piece);
+            }
+        }
     }
 }
 
 impl PieceDownloadProgress {
-    pub fn new(piece_size: usize) -> Self {
-        PieceDownloadProgress { offset: 0, data: Vec::with_capacity(piece_size), length: piece_size as u32 }
+    pub fn new(piece: u32, piece_size: u32) -> Self {
+        PieceDownloadProgress {
+            piece,
+            data: vec![0; piece_size as usize],
+            length: piece_size,
+            unrequested: (0..piece_size).step_by(BLOCK_SIZE as usize).collect(),
+            received: HashSet::new(),
+            active: 0,
+            sources: HashMap::new(),
+        }
+    }
+
+    pub fn block_size(&self, begin: u32) -> u32 {
+        (self.length - begin).min(BLOCK_SIZE)
     }
 
-    pub fn remaining(&self) -> u32 {
-        self.length - self.offset
+    // the next block to request, as (begin, length)
+    pub fn next_block(&mut self) -> Option<(u32, u32)> {
+        let begin = self.unrequested.pop_front()?;
+        Some((begin, self.block_size(begin)))
     }
 
-    fn reset(&mut self) {
-        self.offset = 0;
-        self.data.clear();
+    // every block not yet received is to be requested again
+    pub fn reset_requests(&mut self) {
+        self.unrequested = (0..self.length)
+            .step_by(BLOCK_SIZE as usize)
+            .filter(|b| !self.received.contains(b))
+            .collect();
     }
 
-    pub fn get_next_block_size(&self) -> u32 {
-        self.remaining().min(BLOCK_SIZE)
+    // offsets of blocks not yet received
+    pub fn missing(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.length).step_by(BLOCK_SIZE as usize).filter(|b| !self.received.contains(b))
+    }
+
+    // a block whose request was dropped goes back to be requested again
+    pub fn unrequest(&mut self, begin: u32) {
+        if !self.received.contains(&begin) {
+            self.unrequested.push_front(begin);
+        }
     }
 
     pub fn complete(&self) -> bool {
-        self.get_next_block_size() == 0
+        self.received.len() == self.length.div_ceil(BLOCK_SIZE) as usize
     }
 
-    pub fn add_block(&mut self, mut block: Vec<u8>) {
-        let n = block.len() as u32;
-        self.data.append(&mut block);
-        self.offset += n;
-    }   
+    // false if the block is not one of this piece's or was already received
+    pub fn add_block(&mut self, begin: u32, block: &[u8], from: IpAddr) -> bool {
+        if !begin.is_multiple_of(BLOCK_SIZE) || begin >= self.length || block.len() != self.block_size(begin) as usize {
+            return false
+        }
+        if !self.received.insert(begin) {
+            return false
+        }
+        self.data[begin as usize..begin as usize + block.len()].copy_from_slice(block);
+        self.unrequested.retain(|&b| b != begin);
+        self.sources.insert(begin, from);
+        true
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
-enum State {
-    Curious, // waiting to see what pieces peer offers (via BitField response)
-    Interested, // confirmed peer offers all pieces, waiting for unchoke response
-    NotInterested, // peer does not offer all pieces, quit
-    Choked, // waiting for unchoke to be able to request pieces
-    Unchoked, // able to request if interested
-    
+impl Throughput {
+    fn record(&mut self, bytes: usize, latency: Duration) {
+        self.latency = if self.latency.is_zero() {
+            latency
+        } else {
+            self.latency.mul_f64(1.0 - SMOOTHING) + latency.mul_f64(SMOOTHING)
+        };
+
+        let start = *self.window_start.get_or_insert_with(Instant::now);
+        self.window_bytes += bytes as u64;
+        let elapsed = start.elapsed();
+        if elapsed >= RATE_WINDOW {
+            let sample = self.window_bytes as f64 / elapsed.as_secs_f64();
+            self.rate = if self.rate == 0.0 { sample } else { self.rate * (1.0 - SMOOTHING) + sample * SMOOTHING };
+            self.window_start = Some(Instant::now());
+            self.window_bytes = 0;
+        }
+    }
+
+    // how many requests to keep outstanding: enough for `QUEUE_TIME` of transfer, and at least two round trips' worth
+    fn depth(&self) -> usize {
+        let horizon = QUEUE_TIME.max(self.latency * 2);
+        let depth = (self.rate * horizon.as_secs_f64() / BLOCK_SIZE as f64).ceil() as usize;
+        depth.clamp(MIN_QUEUE_DEPTH, MAX_QUEUE_DEPTH)
+    }
 }
 
 #[macro_export]
@@ -126,189 +382,828 @@ macro_rules! piece_filename {
 }
 
 impl Downloader {
-    pub async fn new(address: SocketAddrV4,
-               info: Arc<FileDownloadInfo>,
-               state: Arc<Mutex<FileDownloadState>>,
-               dir: Arc<PathBuf>,
-               pb: ProgressBar
-               ) -> std::io::Result<Self> {
+    pub async fn connect(address: SocketAddr, session: Arc<Session>) -> Result<Self, PeerError> {
+        let policy = session.info.encryption;
+        let stream = Downloader::open(address, &session).await?;
+        let mut connection: Box<dyn PeerStream> = match policy {
+            EncryptionPolicy::Plaintext => stream,
+            _ => match mse::initiate(stream, &session.info.hash, policy).await {
+                Ok(stream) => {
+                    info!("negotiated {} connection with peer {}", if stream.is_encrypted() { "an encrypted" } else { "a plaintext" }, address);
+                    Box::new(stream)
+                },
+                // peers without encryption drop the connection on seeing the key exchange
+                Err(e) if policy == EncryptionPolicy::Prefer => {
+                    warn!("encrypted handshake with peer {} failed ({}); retrying in plaintext", address, e);
+                    Downloader::open(address, &session).await?
+                },
+                Err(e) => return Err(e),
+            },
+        };
+
+        info!("reaching out to handshake with peer {} (info hash = {})", address, to_string(&session.info.hash));
+        let (reserved, peer_id) = handshake(&address, &mut connection, &session.info.hash, &session.info.peer_id).await?;
+        let (client, superseded) = Downloader::identify(address, &peer_id, true, &session)?;
+        session.state.lock().await.connected.insert(address, FLAG_REACHABLE);
+        Ok(Downloader::new(address, connection, reserved, peer_id, client, superseded, session))
+    }
+
+    async fn open(address: SocketAddr, session: &Session) -> Result<Box<dyn PeerStream>, PeerError> {
         info!("connecting to peer {} ...", address);
-        let connection = match TcpStream::connect(address).await {
+        if let Some(utp) = &session.utp {
+            match utp.connect(address).await {
+                Ok(stream) => {
+                    info!("connected to peer {} over uTP", address);
+                    return Ok(Box::new(stream))
+                },
+                Err(e) => info!("no uTP connection to peer {} ({:?}); trying TCP", address, e),
+            }
+        }
+        match TcpStream::connect(address).await {
             Ok(c) => {
                 info!("connected to peer {}", address);
-                c
+                Ok(Box::new(c))
             },
             Err(e) => {
                 error!("error connecting to peer {}: {:?}", address, e);
-                return Err(e);
+                Err(PeerError::ConnectionError(address.to_string(), e))
             }
-        };
+        }
+    }
 
-        let piece_size = info.bytes_per_piece;
-        Ok(Downloader {
-            address,
-            connection,
-            info,
-            shared_state: state,
-            skip_set: HashSet::new(),
-            state: State::Curious,
-            dir,
-            pb,
-            progress: PieceDownloadProgress::new(piece_size),
-        })
+    pub async fn accept(address: SocketAddr, connection: Box<dyn PeerStream>, session: Arc<Session>) -> Result<Self, PeerError> {
+        let stream = mse::respond(connection, &session.info.hash, session.info.encryption).await?;
+        if stream.is_encrypted() {
+            info!("peer {} connected with encryption", address);
+        }
+        let mut connection: Box<dyn PeerStream> = Box::new(stream);
+        let (reserved, peer_id) = accept_handshake(&address, &mut connection, &session.info.hash, &session.info.peer_id).await?;
+        let (client, superseded) = Downloader::identify(address, &peer_id, false, &session)?;
+        Ok(Downloader::new(address, connection, reserved, peer_id, client, superseded, session))
     }
 
-    async fn get_message(&mut self) -> Result<Message, PeerError> {
-        Message::read_message(&mut self.connection).await
+    // checks the peer is someone new whose client we accept, registering its peer id;
+    // when two peers connect to each other at once, both keep the connection opened by the lower peer id
+    fn identify(address: SocketAddr, peer_id: &[u8; 20], outgoing: bool, session: &Session) -> Result<(Option<ClientId>, Arc<Notify>), PeerError> {
+        if *peer_id == session.info.peer_id {
+            return Err(PeerError::SelfConnection)
+        }
+        let client = ClientId::decode(peer_id);
+        let name = client.as_ref().map(ClientId::to_string);
+        if !session.info.client_filter.permits(&name.as_deref().into_iter().collect::<Vec<_>>()) {
+            return Err(PeerError::ClientRejected(client::describe(client.as_ref(), None)))
+        }
+        let superseded = Arc::new(Notify::new());
+        let registered = RegisteredPeer { address, outgoing, superseded: superseded.clone() };
+        match session.peer_ids.lock().unwrap().entry(*peer_id) {
+            Entry::Occupied(mut entry) => {
+                let existing = entry.get();
+                let keep_outgoing = session.info.peer_id < *peer_id;
+                if existing.outgoing == outgoing || outgoing != keep_outgoing {
+                    return Err(PeerError::DuplicateConnection(existing.address))
+                }
+                existing.superseded.notify_one();
+                entry.insert(registered);
+            },
+            Entry::Vacant(entry) => {
+                entry.insert(registered);
+            },
+        }
+        Ok((client, superseded))
     }
 
-    pub async fn download_pieces(&mut self) -> Result<(), PeerError> {
-        info!("reaching out to handshake with peer {} (info hash = {})", self.address, to_string(&self.info.hash));
-        handshake(&self.address, &mut self.connection, &self.info.hash).await?;
+    fn new(address: SocketAddr, connection: Box<dyn PeerStream>, reserved: [u8; 8], peer_id: [u8; 20], client: Option<ClientId>, superseded: Arc<Notify>, session: Arc<Session>) -> Self {
+        let (reader, writer) = tokio::io::split(connection);
+        let mut reader = MessageReader::new(reader);
+        let (tx, messages) = mpsc::channel(MESSAGE_BUFFER);
+        // reading a message is not cancel safe, so it happens apart from the event loop
+        let reader = tokio::spawn(async move {
+            loop {
+                let result = reader.read().await;
+                let failed = result.is_err();
+                if tx.send(result).await.is_err() || failed {
+                    break
+                }
+            }
+        }).abort_handle();
+
+        let mut extensions = ExtensionRegistry::default();
+        if session.info.shares_peers {
+            extensions.register(Box::new(PexState::default()));
+        }
+        let fast = fast::supports_fast(&reserved);
+        let allowed_fast_out = if fast {
+            fast::allowed_fast_set(address.ip(), &session.info.hash, session.info.num_pieces())
+        } else {
+            HashSet::new()
+        };
+        let counters = Arc::new(PeerCounters::default());
+        *counters.client.lock().unwrap() = client::describe(client.as_ref(), None);
+        let (choke_tx, choke) = mpsc::unbounded_channel();
+        session.peers.lock().unwrap().insert(address, PeerLink { counters: counters.clone(), choke: choke_tx });
+        Downloader {
+            address,
+            writer,
+            messages,
+            reader,
+            haves: session.haves.subscribe(),
+            blocks: session.blocks.subscribe(),
+            counters,
+            choke,
+            last_block: Instant::now(),
+            session,
+            supports_extensions: extension::supports_extensions(&reserved),
+            fast,
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            peer_pieces: None,
+            pieces: Vec::new(),
+            requested: HashMap::new(),
+            throughput: Throughput::default(),
+            uploads: VecDeque::new(),
+            extensions,
+            peer_reqq: None,
+            allowed_fast: HashSet::new(),
+            allowed_fast_out,
+            suggested: VecDeque::new(),
+            peer_id,
+            client,
+            superseded,
+        }
+    }
+
+    pub async fn run(&mut self) -> Result<(), PeerError> {
+        let result = self.exchange().await;
+        self.reader.abort();
+        self.session.peers.lock().unwrap().remove(&self.address);
+        {
+            let mut peer_ids = self.session.peer_ids.lock().unwrap();
+            if peer_ids.get(&self.peer_id).is_some_and(|registered| registered.address == self.address) {
+                peer_ids.remove(&self.peer_id);
+            }
+        }
+        // unfinished pieces go back for other peers, and this peer's pieces no longer count toward availability
+        let mut guard = self.session.state.lock().await;
+        for piece in self.pieces.drain(..) {
+            guard.release(piece);
+        }
+        if let Some(pieces) = &self.peer_pieces {
+            guard.picker.remove_bitfield(pieces);
+        }
+        drop(guard);
+        match result {
+            Err(PeerError::MessageReceiveError(e, _)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                info!("peer {} closed the connection", self.address);
+                Ok(())
+            },
+            result => result,
+        }
+    }
 
-        let num_pieces = self.info.piece_hashes.len();
-        let bitfield_len = (num_pieces + 7) / 8;
-        let empty = vec![0u8; bitfield_len];
+    async fn exchange(&mut self) -> Result<(), PeerError> {
+        let (bitfield, all, none) = {
+            let guard = self.session.state.lock().await;
+            (guard.done.as_bytes().to_vec(), guard.done.all(), guard.done.none())
+        };
+        if self.fast && all {
+            info!("sending Have All message to peer {}", self.address);
+            Message::HaveAll.send(&mut self.writer).await?;
+        } else if self.fast && none {
+            info!("sending Have None message to peer {}", self.address);
+            Message::HaveNone.send(&mut self.writer).await?;
+        } else {
+            info!("sending Bitfield message to peer {}", self.address);
+            Message::Bitfield { bitfield: Bitfield::from(bitfield) }.send(&mut self.writer).await?;
+        }
+        let mut allowed_fast: Vec<u32> = self.allowed_fast_out.iter().copied().collect();
+        allowed_fast.sort_unstable();
+        for index in allowed_fast {
+            Message::AllowedFast { index }.send(&mut self.writer).await?;
+        }
+        if self.supports_extensions {
+            self.send_extended_handshake().await?;
+        }
 
-        info!("sending empty Bitfield message to peer {}", self.address);
-        Message::send_bitfield(&mut self.connection, &empty).await?;
-    
-        self.state = State::Curious;
+        let mut keep_alive = interval(KEEP_ALIVE_INTERVAL);
+        keep_alive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        keep_alive.tick().await;
+        let mut tick = interval(TICK_INTERVAL);
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
         loop {
-            match self.state {
-                State::Curious => {
-                    info!("waiting for Bitfield response from peer {} ...", self.address);
-                    let msg = self.get_message().await?;
-                    if let Message::Bitfield { mut bitfield } = msg {
-                        bitfield.num = self.info.piece_hashes.len();
-                        if bitfield.all() {
-                            self.state = State::Interested;
-                            Message::send_interested(&mut self.connection).await?;
-                            info!("peer {} is a seed; interest expressed", self.address);
-                        } else {
-                            // TODO handle incomplete peers
-                            self.state = State::NotInterested;
-                            info!("peer {} is not fully seeded ({}/{}); abandoning download", self.address, bitfield.num_set(), bitfield.num);
+            if self.peer_is_seed() && self.session.state.lock().await.is_complete() {
+                info!("peer {} and we both have every piece; disconnecting", self.address);
+                return Ok(())
+            }
+            self.request_blocks().await?;
+
+            // while choking, only requests for allowed fast pieces are kept
+            let can_upload = !self.uploads.is_empty();
+            tokio::select! {
+                message = self.messages.recv() => match message {
+                    Some(Ok(message)) => self.handle(message).await?,
+                    Some(Err(e)) => return Err(e),
+                    None => return Ok(()),
+                },
+                have = self.haves.recv() => match have {
+                    Ok(index) => {
+                        Message::Have { index }.send(&mut self.writer).await?;
+                        // another connection finished a piece this one was also downloading
+                        if self.pieces.contains(&index) {
+                            self.pieces.retain(|&p| p != index);
+                            let blocks: Vec<u32> = self.requested.keys().filter(|(i, _)| *i == index).map(|(_, b)| *b).collect();
+                            for begin in blocks {
+                                self.cancel(index, begin).await?;
+                            }
                         }
-                    }
+                        self.update_interest().await?;
+                    },
+                    Err(broadcast::error::RecvError::Lagged(n)) => warn!("peer {} missed {} Have messages", self.address, n),
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
                 },
-                State::Choked | State::Interested => {
-                    let msg = self.get_message().await?;
-                    if let Message::Unchoke = msg {
-                        info!("peer {} sent Unchoke", self.address);
-                        self.state = State::Unchoked;
-                    }
+                block = self.blocks.recv() => if let Ok((index, begin)) = block {
+                    self.cancel(index, begin).await?;
                 },
-                State::Unchoked => {
-                    if let Err(PeerError::Exhausted(_)) = self.try_download_piece().await {
-                        self.state = State::NotInterested;
+                Some(choke) = self.choke.recv() => self.set_choking(choke).await?,
+                _ = self.superseded.notified() => {
+                    let replacement = self.session.peer_ids.lock().unwrap().get(&self.peer_id).map(|registered| registered.address);
+                    return Err(PeerError::DuplicateConnection(replacement.unwrap_or(self.address)))
+                },
+                _ = std::future::ready(()), if can_upload => self.upload().await?,
+                _ = keep_alive.tick() => Message::KeepAlive.send(&mut self.writer).await?,
+                _ = tick.tick() => {
+                    self.check_banned()?;
+                    let snubbed = !self.requested.is_empty() && self.last_block.elapsed() >= SNUB_TIMEOUT;
+                    if snubbed && !self.counters.snubbed.swap(true, Ordering::Relaxed) {
+                        warn!("peer {} is snubbing us", self.address);
                     }
+                    self.update_interest().await?;
+                    self.send_extended().await?;
                 },
-                State::NotInterested => {
-                    break;
-                }
             }
         }
+    }
 
+    async fn handle(&mut self, message: Message) -> Result<(), PeerError> {
+        self.validate(&message)?;
+        match message {
+            Message::KeepAlive => (),
+            Message::Choke => {
+                info!("peer {} sent choke", self.address);
+                self.peer_choking = true;
+                // with the fast extension each dropped request is rejected individually
+                if self.fast {
+                    return Ok(())
+                }
+                // a choke discards outstanding requests
+                let mut guard = self.session.state.lock().await;
+                for ((index, begin), _) in self.requested.drain() {
+                    if let Some(progress) = guard.downloading.get_mut(&index) {
+                        progress.unrequest(begin);
+                    }
+                }
+            },
+            Message::Unchoke => {
+                info!("peer {} sent unchoke", self.address);
+                self.peer_choking = false;
+            },
+            Message::Interested => {
+                info!("peer {} is interested", self.address);
+                self.peer_interested = true;
+                self.counters.interested.store(true, Ordering::Relaxed);
+                self.session.rechoke.notify_one();
+            },
+            Message::NotInterested => {
+                info!("peer {} is not interested", self.address);
+                self.peer_interested = false;
+                self.counters.interested.store(false, Ordering::Relaxed);
+                self.session.rechoke.notify_one();
+            },
+            Message::Have { index } => {
+                let num_pieces = self.session.info.num_pieces();
+                let pieces = self.peer_pieces.get_or_insert_with(|| Bitfield::new(num_pieces, false));
+                if !pieces.has_piece(index as usize).map_err(PeerError::MalformedBitfield)? {
+                    pieces.mark_piece(index as usize).map_err(PeerError::MalformedBitfield)?;
+                    self.session.state.lock().await.picker.add_piece(index);
+                }
+                if !self.am_interested && self.wants(index) {
+                    self.update_interest().await?;
+                }
+            },
+            Message::Bitfield { bitfield } => {
+                let pieces = Bitfield::try_from_vec(bitfield.masks, self.session.info.num_pieces())
+                    .map_err(PeerError::MalformedBitfield)?;
+                self.set_peer_pieces(pieces).await?;
+            },
+            Message::HaveAll | Message::HaveNone if !self.fast => {
+                warn!("ignoring {:?} from peer {} without the fast extension", message, self.address);
+            },
+            Message::HaveAll => self.set_peer_pieces(Bitfield::new(self.session.info.num_pieces(), true)).await?,
+            Message::HaveNone => self.set_peer_pieces(Bitfield::new(self.session.info.num_pieces(), false)).await?,
+            Message::Request { index, begin, length } => {
+                if self.am_choking && !self.allowed_fast_out.contains(&index) {
+                    return self.reject(index, begin, length).await
+                }
+                if self.uploads.len() >= MAX_QUEUED_UPLOADS {
+                    warn!("dropping request from peer {} beyond {} queued", self.address, MAX_QUEUED_UPLOADS);
+                    self.reject(index, begin, length).await?;
+                } else if self.can_serve(index).await {
+                    self.uploads.push_back((index, begin, length));
+                } else {
+                    warn!("ignoring request from peer {} for {} bytes at offset {} of piece {} we do not have", self.address, length, begin, index);
+                    self.reject(index, begin, length).await?;
+                }
+            },
+            Message::Piece { index, begin, bytes } => self.receive_block(index, begin, bytes).await?,
+            Message::Cancel { index, begin, length } => {
+                let queued = self.uploads.len();
+                self.uploads.retain(|r| *r != (index, begin, length));
+                // a cancelled request is answered with either the block or a reject
+                if self.uploads.len() < queued {
+                    self.reject(index, begin, length).await?;
+                }
+            },
+            Message::SuggestPiece { .. } | Message::RejectRequest { .. } | Message::AllowedFast { .. } if !self.fast => {
+                warn!("ignoring {:?} from peer {} without the fast extension", message, self.address);
+            },
+            Message::SuggestPiece { index } => {
+                if self.wants(index) && !self.suggested.contains(&index) {
+                    if self.suggested.len() >= MAX_SUGGESTED {
+                        self.suggested.pop_front();
+                    }
+                    self.suggested.push_back(index);
+                }
+            },
+            Message::RejectRequest { index, begin, length } => {
+                info!("peer {} rejected request for {} bytes at offset {} of piece {}", self.address, length, begin, index);
+                // the block is available to be requested again right away, from this peer or another
+                if self.requested.remove(&(index, begin)).is_some()
+                    && let Some(progress) = self.session.state.lock().await.downloading.get_mut(&index) {
+                    progress.unrequest(begin);
+                }
+            },
+            Message::AllowedFast { index } => {
+                self.allowed_fast.insert(index);
+            },
+            Message::Extended { id, payload } => self.handle_extended(id, &payload)?,
+        }
         Ok(())
     }
 
-    async fn try_download_piece(&mut self) -> Result<(), PeerError> {
-        let piece = {
-            let mut guard = self.shared_state.lock().await;
+    async fn set_peer_pieces(&mut self, pieces: Bitfield) -> Result<(), PeerError> {
+        info!("peer {} has {}/{} pieces", self.address, pieces.num_set(), pieces.num);
+        let mut guard = self.session.state.lock().await;
+        if pieces.all()
+            && let Some(flags) = guard.connected.get_mut(&self.address) {
+            *flags |= FLAG_SEED;
+        }
+        if let Some(previous) = &self.peer_pieces {
+            guard.picker.remove_bitfield(previous);
+        }
+        guard.picker.add_bitfield(&pieces);
+        drop(guard);
+        self.peer_pieces = Some(pieces);
+        self.update_interest().await
+    }
 
-            if let Some(&p) = guard.todo.iter().find(|&&p| !self.skip_set.contains(&p)) {
-                guard.todo.remove(&p);
-                p
-            } else {
-                info!("peer {} exhausted all pieces; exiting...", self.address);
-                return Err(PeerError::Exhausted(self.address.to_string()));
-            }
-        };
+    // messages naming pieces or blocks outside the torrent are protocol violations
+    fn validate(&self, message: &Message) -> Result<(), PeerError> {
+        match *message {
+            Message::Have { index } | Message::SuggestPiece { index } | Message::AllowedFast { index }
+                if index as usize >= self.session.info.num_pieces() => Err(PeerError::PieceOutOfRange(index)),
+            Message::Request { index, begin, length } => self.validate_block(index, begin, length),
+            Message::Piece { index, begin, ref bytes } => self.validate_block(index, begin, bytes.len() as u32),
+            _ => Ok(()),
+        }
+    }
 
-        info!("peer {} selected piece {}", self.address, piece);
-        let expected_hash = self.info.piece_hashes[piece as usize];
-        info!("download of piece {} from peer {} starting, expecting hash {}", piece, self.address, to_string(&expected_hash));
-        let result = self.download_piece(piece, &expected_hash).await;
-        
-        if let Err(e) = &result {
-            error!("peer {} took error during download: {:?}", self.address, e);
-            self.skip_set.insert(piece);
-            let mut guard = self.shared_state.lock().await;
-            guard.requeue(piece);
-            self.state = State::Choked;
-            return Err(PeerError::Exhausted(self.address.to_string()));
+    fn validate_block(&self, index: u32, begin: u32, length: u32) -> Result<(), PeerError> {
+        if index as usize >= self.session.info.num_pieces() {
+            return Err(PeerError::PieceOutOfRange(index))
+        }
+        let fits = begin.checked_add(length).is_some_and(|end| end <= self.session.info.piece_length(index));
+        if !fits || length == 0 || length > MAX_BLOCK_LENGTH {
+            return Err(PeerError::InvalidBlock(index, begin, length))
+        }
+        Ok(())
+    }
+
+    fn peer_is_seed(&self) -> bool {
+        self.peer_pieces.as_ref().is_some_and(Bitfield::all)
+    }
+
+    // a piece is wanted from this peer if it has it and we need it
+    fn wants(&self, piece: u32) -> bool {
+        self.peer_pieces.as_ref().is_some_and(|p| p.has_piece(piece as usize).unwrap_or(false))
+    }
+
+    // while the peer chokes us, only its allowed fast pieces can be requested
+    fn can_request(&self, piece: u32) -> bool {
+        !self.peer_choking || self.allowed_fast.contains(&piece)
+    }
+
+    async fn update_interest(&mut self) -> Result<(), PeerError> {
+        let wanted = !self.pieces.is_empty() || {
+            let guard = self.session.state.lock().await;
+            guard.picker.any(|p| self.wants(p))
+                || (guard.in_endgame() && guard.downloading.keys().any(|&p| self.wants(p)))
+        };
+        if wanted && !self.am_interested {
+            Message::Interested.send(&mut self.writer).await?;
+            info!("peer {} has pieces we need; interest expressed", self.address);
+        } else if !wanted && self.am_interested {
+            Message::NotInterested.send(&mut self.writer).await?;
+            info!("peer {} has nothing more for us", self.address);
         }
+        self.am_interested = wanted;
         Ok(())
     }
 
-    async fn download_piece(&mut self, piece: u32, expected_hash: &[u8; 20]) -> Result<(), PeerError> {
-        let mut choked = false;
-        let mut request_size = 0u32;
+    async fn request_blocks(&mut self) -> Result<(), PeerError> {
+        if !self.am_interested || (self.peer_choking && self.allowed_fast.is_empty()) {
+            return Ok(())
+        }
+        let depth = self.throughput.depth().min(self.peer_reqq.map_or(usize::MAX, |r| r.max(1) as usize));
+        while self.requested.len() < depth {
+            let Some((piece, begin, length)) = self.next_block().await else {
+                break
+            };
+            if self.requested.is_empty() {
+                self.last_block = Instant::now();
+            }
+            info!("asking for {} bytes at offset {} for piece {} from peer {} ({} requests outstanding)",
+                length, begin, piece, self.address, self.requested.len());
+            Message::Request { index: piece, begin, length }.send(&mut self.writer).await?;
+            self.requested.insert((piece, begin), (length, Instant::now()));
+        }
+        if self.pieces.is_empty() {
+            info!("peer {} has no more pieces we need", self.address);
+            self.update_interest().await?;
+        }
+        Ok(())
+    }
 
-        self.progress.reset();
+    // blocks of pieces already started come first, so pieces finish before new ones are begun
+    async fn next_block(&mut self) -> Option<(u32, u32, u32)> {
+        let mut guard = self.session.state.lock().await;
+        let state = &mut *guard;
+        // pieces that failed verification elsewhere and were discarded
+        self.pieces.retain(|p| state.downloading.contains_key(p));
+        for &piece in self.pieces.iter().filter(|&&p| self.can_request(p)) {
+            if let Some((begin, length)) = state.downloading.get_mut(&piece).and_then(|p| p.next_block()) {
+                return Some((piece, begin, length))
+            }
+        }
 
-        while !self.progress.complete() {
-            if !choked {
-                request_size = self.progress.get_next_block_size();
-                info!("asking for {} bytes at offset {} for piece {} from peer {} ({} bytes remain)",
-                    request_size, self.progress.offset, piece, self.address, self.progress.remaining());
-                
-                // TODO improve upon lock-step by priming up to 5 requests at a given time
-                Message::send_request(&mut self.connection, piece, self.progress.offset, request_size).await?;
+        let suggested = loop {
+            match self.suggested.pop_front() {
+                Some(piece) if self.wants(piece) && self.can_request(piece) && state.picker.take(piece) => break Some(piece),
+                Some(_) => continue,
+                None => break None,
             }
+        };
+        let wants = |p| self.wants(p) && self.can_request(p);
+        if let Some(piece) = suggested.or_else(|| state.picker.pick(wants, |p| state.downloading.contains_key(&p))) {
+            let progress = state.downloading
+                .entry(piece)
+                .or_insert_with(|| PieceDownloadProgress::new(piece, self.session.info.piece_length(piece)));
+            progress.active += 1;
+            self.pieces.push(piece);
+            info!("peer {} selected piece {} ({} blocks already received)", self.address, piece, progress.received.len());
+            let (begin, length) = progress.next_block()?;
+            return Some((piece, begin, length))
+        }
 
-            match Message::read_message(&mut self.connection).await? {
-                Message::Piece { index, begin, bytes} => {
-                    info!("peer {} responded with piece {} at offset {} with length {}", self.address, index, begin, bytes.len());
-                    if index == piece && self.progress.offset == begin && bytes.len() as u32 == request_size {
-                        self.progress.add_block(bytes);
-                    }
-                },
-                Message::Choke => {
-                    info!("peer {} sent choke", self.address);
-                    choked = true;
-                }
-                Message::Unchoke => {
-                    info!("peer {} sent unchoke", self.address);
-                    choked = false;
+        // endgame: blocks already requested from other peers are requested from this one too
+        if !state.in_endgame() {
+            return None
+        }
+        let (piece, begin) = state.downloading
+            .iter()
+            .filter(|(p, _)| self.wants(**p) && self.can_request(**p) && !state.suspects.contains_key(p))
+            .flat_map(|(p, progress)| progress.missing().map(move |b| (*p, b)))
+            .find(|block| !self.requested.contains_key(block))?;
+        let progress = state.downloading.get_mut(&piece)?;
+        if !self.pieces.contains(&piece) {
+            info!("peer {} joined piece {} in endgame", self.address, piece);
+            progress.active += 1;
+            self.pieces.push(piece);
+        }
+        progress.unrequested.retain(|&b| b != begin);
+        Some((piece, begin, progress.block_size(begin)))
+    }
+
+    async fn receive_block(&mut self, index: u32, begin: u32, bytes: Vec<u8>) -> Result<(), PeerError> {
+        info!("peer {} responded with piece {} at offset {} with length {}", self.address, index, begin, bytes.len());
+        // a peer banned by another connection's finding contributes nothing more
+        self.check_banned()?;
+        if let Some((_, requested_at)) = self.requested.remove(&(index, begin)) {
+            self.throughput.record(bytes.len(), requested_at.elapsed());
+        }
+        self.last_block = Instant::now();
+        self.counters.downloaded.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        self.counters.snubbed.store(false, Ordering::Relaxed);
+        let mut guard = self.session.state.lock().await;
+        let endgame = guard.in_endgame();
+        // blocks arriving after a choke dropped their request are still accepted, but duplicates from endgame are not
+        let Some(progress) = guard.downloading.get_mut(&index) else {
+            return Ok(())
+        };
+        if !progress.add_block(begin, &bytes, self.address.ip()) {
+            return Ok(())
+        }
+        if endgame {
+            let _ = self.session.blocks.send((index, begin));
+        }
+        if progress.complete() {
+            let progress = guard.downloading.remove(&index).expect("piece checked above");
+            drop(guard);
+            self.pieces.retain(|&p| p != index);
+            info!("finished download of piece {} from peer {}", progress.piece, self.address);
+            self.verify_and_save_piece(progress).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_choking(&mut self, choke: bool) -> Result<(), PeerError> {
+        if choke == self.am_choking {
+            return Ok(())
+        }
+        if choke {
+            info!("choking peer {}", self.address);
+            Message::Choke.send(&mut self.writer).await?;
+            // a choke discards the peer's outstanding requests, other than those for allowed fast pieces
+            let uploads: Vec<(u32, u32, u32)> = self.uploads.drain(..).collect();
+            for (index, begin, length) in uploads {
+                if self.allowed_fast_out.contains(&index) {
+                    self.uploads.push_back((index, begin, length));
+                } else {
+                    self.reject(index, begin, length).await?;
                 }
-                _ => (),
             }
+        } else {
+            info!("unchoking peer {}", self.address);
+            Message::Unchoke.send(&mut self.writer).await?;
         }
+        self.am_choking = choke;
+        Ok(())
+    }
 
-        info!("finished download of piece {} from peer {}", piece, self.address);
+    // drops a request for a block that has arrived from another peer
+    async fn cancel(&mut self, index: u32, begin: u32) -> Result<(), PeerError> {
+        if let Some((length, _)) = self.requested.remove(&(index, begin)) {
+            info!("cancelling request for {} bytes at offset {} of piece {} from peer {}", length, begin, index, self.address);
+            Message::Cancel { index, begin, length }.send(&mut self.writer).await?;
+        }
+        Ok(())
+    }
 
-        self.verify_and_save_piece(piece, expected_hash).await
+    // tells a peer with the fast extension its request will not be served
+    async fn reject(&mut self, index: u32, begin: u32, length: u32) -> Result<(), PeerError> {
+        if self.fast {
+            Message::RejectRequest { index, begin, length }.send(&mut self.writer).await?;
+        }
+        Ok(())
     }
 
-    async fn verify_and_save_piece(&mut self, piece: u32, expected_hash: &[u8; 20]) -> Result<(), PeerError> {
-        let data_hash = sha1_hash(&self.progress.data);
+    async fn verify_and_save_piece(&mut self, progress: PieceDownloadProgress) -> Result<(), PeerError> {
+        let piece = progress.piece;
+        let expected_hash = self.session.info.piece_hashes[piece as usize];
+        let data_hash = sha1_hash(&progress.data);
         info!("peer {} retrieved piece {} with SHA1 hash {}", self.address, piece, to_string(&data_hash));
-        if data_hash == *expected_hash {
-            let path = self.dir.join(piece_filename!(piece));
+        if data_hash == expected_hash {
+            let path = self.session.dir.join(piece_filename!(piece));
             let path_str = path.to_string_lossy();
             info!("peer {} writing piece {} to {}...", self.address, piece, path_str);
-            Downloader::save_piece(&path, &self.progress.data)
+            Downloader::save_piece(&path, &progress.data)
                     .await
                     .map_err(|e| PeerError::DiskError(piece, e))?;
             info!("peer {} wrote piece {} to {}", self.address, piece, path_str);
-            let mut guard = self.shared_state.lock().await;
-            self.pb.inc(self.info.bytes_per_piece as u64);
-            guard.complete(piece);
-            Ok(())
+            let mut guard = self.session.state.lock().await;
+            self.session.pb.inc(progress.data.len() as u64);
+            guard.downloaded += progress.data.len() as u64;
+            guard.complete(piece, progress.data.len() as u64);
+            let _ = self.session.haves.send(piece);
+            // with a good copy in hand, whoever sent a block that differs from it sent the bad data
+            if let Some(suspects) = guard.suspects.remove(&piece) {
+                drop(guard);
+                for ip in suspects.culprits(&progress.data, BLOCK_SIZE as usize) {
+                    self.ban(ip, piece);
+                }
+            }
         } else {
-            error!("peer {} found hash of piece {} mismatches, adding piece to skip list and re-queueing for another peer", self.address, piece);
-            self.skip_set.insert(piece);
-            let mut guard = self.shared_state.lock().await;
+            let senders: HashSet<IpAddr> = progress.sources.values().copied().collect();
+            let mut guard = self.session.state.lock().await;
+            guard.downloaded += progress.data.len() as u64;
             guard.requeue(piece);
-            Err(PeerError::Exhausted(self.address.to_string()))
+            if senders.len() == 1 {
+                drop(guard);
+                error!("hash of piece {} mismatches", piece);
+                self.ban(*senders.iter().next().expect("one sender"), piece);
+            } else {
+                error!("hash of piece {} mismatches with blocks from {} peers; downloading it again from a single peer to find which sent bad data",
+                    piece, senders.len());
+                // the first failure's blocks are the ones compared once the piece verifies
+                if let Entry::Vacant(entry) = guard.suspects.entry(piece) {
+                    let suspects = entry.insert(SuspectBlocks::default());
+                    for (&begin, &from) in &progress.sources {
+                        let end = begin as usize + progress.block_size(begin) as usize;
+                        suspects.record(begin, from, &progress.data[begin as usize..end]);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn ban(&self, ip: IpAddr, piece: u32) {
+        error!("banning peer {} for sending corrupt data in piece {}", ip, piece);
+        self.session.bans.lock().unwrap().ban(ip);
+    }
+
+    fn check_banned(&self) -> Result<(), PeerError> {
+        match self.session.bans.lock().unwrap().contains(&self.address.ip()) {
+            true => Err(PeerError::Banned),
+            false => Ok(()),
         }
     }
 
     async fn save_piece(path: &Path, bytes: &[u8]) -> tokio::io::Result<()> {
         let mut file = File::create(path).await?;
-        file.write_all(bytes).await
+        file.write_all(bytes).await?;
+        // tokio writes in the background; the piece must be on disk before it is served or reconstituted
+        file.flush().await
+    }
+
+    // requests are validated on receipt, so only whether we have the piece remains
+    async fn can_serve(&self, index: u32) -> bool {
+        self.session.state.lock().await.done.has_piece(index as usize).unwrap_or(false)
+    }
+
+    async fn upload(&mut self) -> Result<(), PeerError> {
+        let Some((index, begin, length)) = self.uploads.pop_front() else {
+            return Ok(())
+        };
+        let source = self.session.state.lock().await.source.clone();
+        let block = source.read(index, begin, length).await.map_err(|e| PeerError::DiskError(index, e))?;
+        info!("sending {} bytes at offset {} of piece {} to peer {}", length, begin, index, self.address);
+        Message::Piece { index, begin, bytes: block }.send(&mut self.writer).await?;
+        self.session.state.lock().await.uploaded += length as u64;
+        self.counters.uploaded.fetch_add(length as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn handle_extended(&mut self, id: u8, payload: &[u8]) -> Result<(), PeerError> {
+        if id != extension::HANDSHAKE_ID {
+            self.extensions.receive(id, self.address, payload, &self.session);
+            return Ok(())
+        }
+        match ExtendedHandshake::decode(payload) {
+            Ok(handshake) => {
+                let described = client::describe(self.client.as_ref(), handshake.v.as_deref());
+                info!("peer {} ({}) supports extensions {:?}, queues {:?} requests, and sees us as {:?}",
+                    self.address, described, handshake.m, handshake.reqq, handshake.yourip);
+                if let Some(v) = &handshake.v {
+                    // a peer id mimicking another client does not get past the filter on the client's own name
+                    let mut names = vec![v.as_str()];
+                    let from_id = self.client.as_ref().map(ClientId::to_string);
+                    names.extend(from_id.as_deref());
+                    if !self.session.info.client_filter.permits(&names) {
+                        return Err(PeerError::ClientRejected(described))
+                    }
+                    *self.counters.client.lock().unwrap() = described;
+                }
+                self.extensions.set_remote(&handshake.m);
+                self.peer_reqq = handshake.reqq;
+            },
+            Err(e) => warn!("peer {} sent {}", self.address, e),
+        }
+        Ok(())
+    }
+
+    async fn send_extended_handshake(&mut self) -> Result<(), PeerError> {
+        let handshake = ExtendedHandshake {
+            m: self.extensions.ids(),
+            v: Some(CLIENT_NAME.to_string()),
+            p: Some(self.session.info.port),
+            reqq: Some(MAX_QUEUED_UPLOADS as u32),
+            metadata_size: Some(self.session.info.metadata_size),
+            yourip: Some(self.address.ip()),
+        };
+        Message::Extended { id: extension::HANDSHAKE_ID, payload: handshake.encode() }.send(&mut self.writer).await
+    }
+
+    async fn send_extended(&mut self) -> Result<(), PeerError> {
+        let messages = {
+            let guard = self.session.state.lock().await;
+            self.extensions.poll(self.address, &guard)
+        };
+        for (id, payload) in messages {
+            Message::Extended { id, payload }.send(&mut self.writer).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+    use tokio::time::timeout;
+
+    const PIECE_LENGTH: usize = 2 * BLOCK_SIZE as usize;
+
+    // a session downloading two pieces of `data` into `dir`, with nothing yet done
+    fn session(data: &[u8], dir: PathBuf) -> Arc<Session> {
+        let info = FileDownloadInfo {
+            bytes_per_piece: PIECE_LENGTH,
+            total_bytes: data.len() as u64,
+            piece_hashes: data.chunks(PIECE_LENGTH).map(sha1_hash).collect(),
+            hash: [1; 20],
+            peer_id: [2; 20],
+            shares_peers: false,
+            port: 6881,
+            metadata_size: 0,
+            encryption: EncryptionPolicy::Plaintext,
+            client_filter: ClientFilter::default(),
+        };
+        let state = FileDownloadState::new(info.num_pieces(), info.total_bytes, PieceSource::Pieces(dir.clone()));
+        Arc::new(Session {
+            info,
+            state: Arc::new(Mutex::new(state)),
+            dir,
+            pb: ProgressBar::hidden(),
+            discovered: mpsc::unbounded_channel().0,
+            haves: broadcast::channel(16).0,
+            blocks: broadcast::channel(16).0,
+            peers: std::sync::Mutex::new(HashMap::new()),
+            rechoke: Notify::new(),
+            utp: None,
+            peer_ids: std::sync::Mutex::new(HashMap::new()),
+            bans: std::sync::Mutex::new(BanList::default()),
+        })
+    }
+
+    #[tokio::test]
+    async fn downloads_a_piece_and_announces_it() {
+        let data: Vec<u8> = (0..PIECE_LENGTH + BLOCK_SIZE as usize).map(|i| (i % 251) as u8).collect();
+        let dir = std::env::temp_dir().join(format!("torrentium-downloader-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let session = session(&data, dir.clone());
+        let (ours, theirs) = duplex(64 * 1024);
+        let address = "127.0.0.1:6881".parse().unwrap();
+        let mut downloader = Downloader::new(address, Box::new(ours), [0; 8], [3; 20], None, Arc::new(Notify::new()), session.clone());
+
+        // the peer's side: offer the first piece, unchoke once asked, answer each request, and leave once told we have it
+        let peer = async {
+            let (reader, mut writer) = tokio::io::split(theirs);
+            let mut reader = MessageReader::new(reader);
+            let mut seen = Vec::new();
+            while let Ok(message) = reader.read().await {
+                match message {
+                    Message::Bitfield { .. } => {
+                        seen.push("bitfield");
+                        let mut bitfield = Bitfield::new(2, false);
+                        bitfield.mark_piece(0).unwrap();
+                        Message::Bitfield { bitfield }.send(&mut writer).await.unwrap();
+                    },
+                    Message::Interested => {
+                        seen.push("interested");
+                        Message::Unchoke.send(&mut writer).await.unwrap();
+                    },
+                    Message::Request { index, begin, length } => {
+                        seen.push("request");
+                        let start = index as usize * PIECE_LENGTH + begin as usize;
+                        let bytes = data[start..start + length as usize].to_vec();
+                        Message::Piece { index, begin, bytes }.send(&mut writer).await.unwrap();
+                    },
+                    Message::Have { index } => {
+                        assert_eq!(index, 0);
+                        seen.push("have");
+                        break
+                    },
+                    _ => (),
+                }
+            }
+            seen
+        };
+        let download = async {
+            let result = downloader.run().await;
+            drop(downloader);
+            result
+        };
+        let (result, seen) = timeout(Duration::from_secs(10), async { tokio::join!(download, peer) }).await.expect("download timed out");
+        result.unwrap();
+
+        assert_eq!(session.state.lock().await.num_complete(), 1);
+        assert_eq!(std::fs::read(dir.join(crate::piece_filename!(0))).unwrap(), data[..PIECE_LENGTH]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let position = |event| seen.iter().position(|e| *e == event).unwrap_or_else(|| panic!("no {} in {:?}", event, seen));
+        assert_eq!(position("bitfield"), 0);
+        assert!(position("interested") < position("request"));
+        assert!(position("request") < position("have"));
+        assert_eq!(seen.iter().filter(|e| **e == "request").count(), 2);
     }
 }
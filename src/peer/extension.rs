@@ -0,0 +1,172 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tracing::warn;
+
+use crate::metadata::bencode::BencodeValue;
+use crate::peer::PeerError;
+use crate::peer::downloader::{FileDownloadState, Session};
+
+// BEP 10 is advertised by the 20th bit from the right of the reserved handshake bytes
+pub const RESERVED_BYTE: usize = 5;
+pub const RESERVED_BIT: u8 = 0x10;
+
+pub const HANDSHAKE_ID: u8 = 0;
+
+#[derive(Debug, Default)]
+pub struct ExtendedHandshake {
+    // extension name to the message id the sender wants to receive it as; 0 disables it
+    pub m: HashMap<String, u8>,
+    // client name and version
+    pub v: Option<String>,
+    // the port the sender accepts connections on
+    pub p: Option<u16>,
+    // how many outstanding requests the sender queues before dropping more
+    pub reqq: Option<u32>,
+    // size of the info dictionary, for fetching metadata from peers
+    pub metadata_size: Option<u64>,
+    // the receiver's address as the sender sees it
+    pub yourip: Option<IpAddr>,
+}
+
+// a BEP 10 extension, handling the messages peers send under the id we advertise for it
+pub trait Extension: Send + Sync + fmt::Debug {
+    // the name the extension is known by in `m`
+    fn name(&self) -> &'static str;
+
+    fn receive(&mut self, peer: SocketAddr, payload: &[u8], session: &Session) -> Result<(), PeerError>;
+
+    // a message for the peer, if one is due; checked periodically
+    fn poll(&mut self, _peer: SocketAddr, _state: &FileDownloadState) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+// the extensions of a single connection
+#[derive(Debug, Default)]
+pub struct ExtensionRegistry {
+    // each is sent to us as its position plus one
+    extensions: Vec<Box<dyn Extension>>,
+    // extension names to the ids the peer wants them sent as
+    remote: HashMap<String, u8>,
+}
+
+pub fn supports_extensions(reserved: &[u8; 8]) -> bool {
+    reserved[RESERVED_BYTE] & RESERVED_BIT != 0
+}
+
+fn get_uint(items: &BTreeMap<Vec<u8>, BencodeValue>, key: &[u8]) -> Option<u64> {
+    match items.get(key) {
+        Some(BencodeValue::Integer(i)) => u64::try_from(*i).ok(),
+        _ => None,
+    }
+}
+
+impl ExtendedHandshake {
+    pub fn encode(&self) -> Vec<u8> {
+        let m: BTreeMap<Vec<u8>, BencodeValue> = self.m
+            .iter()
+            .map(|(name, id)| (name.as_bytes().to_vec(), BencodeValue::Integer(*id as i64)))
+            .collect();
+        let mut items: BTreeMap<Vec<u8>, BencodeValue> = BTreeMap::new();
+        items.insert(b"m".to_vec(), BencodeValue::Dictionary(m));
+        if let Some(v) = &self.v {
+            items.insert(b"v".to_vec(), BencodeValue::ByteString(v.as_bytes().to_vec()));
+        }
+        if let Some(p) = self.p {
+            items.insert(b"p".to_vec(), BencodeValue::Integer(p as i64));
+        }
+        if let Some(reqq) = self.reqq {
+            items.insert(b"reqq".to_vec(), BencodeValue::Integer(reqq as i64));
+        }
+        if let Some(size) = self.metadata_size {
+            items.insert(b"metadata_size".to_vec(), BencodeValue::Integer(size as i64));
+        }
+        if let Some(ip) = self.yourip {
+            let bytes = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            items.insert(b"yourip".to_vec(), BencodeValue::ByteString(bytes));
+        }
+        Vec::from(&BencodeValue::Dictionary(items))
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, PeerError> {
+        let Ok(BencodeValue::Dictionary(items)) = BencodeValue::try_from(payload) else {
+            return Err(PeerError::MalformedExtensionMessage("handshake is not a dictionary"))
+        };
+        let mut m: HashMap<String, u8> = HashMap::new();
+        if let Some(BencodeValue::Dictionary(entries)) = items.get(b"m".as_slice()) {
+            for (name, id) in entries {
+                if let BencodeValue::Integer(id) = id
+                    && let Ok(id) = u8::try_from(*id) {
+                    m.insert(String::from_utf8_lossy(name).into_owned(), id);
+                }
+            }
+        }
+        let v = match items.get(b"v".as_slice()) {
+            Some(BencodeValue::ByteString(v)) => Some(String::from_utf8_lossy(v).into_owned()),
+            _ => None,
+        };
+        let yourip = match items.get(b"yourip".as_slice()) {
+            Some(BencodeValue::ByteString(b)) => match b.len() {
+                4 => <[u8; 4]>::try_from(b.as_slice()).ok().map(|o| IpAddr::V4(Ipv4Addr::from(o))),
+                16 => <[u8; 16]>::try_from(b.as_slice()).ok().map(|o| IpAddr::V6(Ipv6Addr::from(o))),
+                _ => None,
+            },
+            _ => None,
+        };
+        Ok(ExtendedHandshake {
+            m,
+            v,
+            p: get_uint(&items, b"p").and_then(|p| u16::try_from(p).ok()).filter(|p| *p != 0),
+            reqq: get_uint(&items, b"reqq").and_then(|r| u32::try_from(r).ok()),
+            metadata_size: get_uint(&items, b"metadata_size"),
+            yourip,
+        })
+    }
+}
+
+impl ExtensionRegistry {
+    pub fn register(&mut self, extension: Box<dyn Extension>) {
+        self.extensions.push(extension);
+    }
+
+    // our `m` dictionary
+    pub fn ids(&self) -> HashMap<String, u8> {
+        self.extensions
+            .iter()
+            .enumerate()
+            .map(|(i, extension)| (extension.name().to_string(), i as u8 + 1))
+            .collect()
+    }
+
+    // records the ids from the peer's handshake; a later handshake replaces earlier ones
+    pub fn set_remote(&mut self, m: &HashMap<String, u8>) {
+        self.remote = m.iter().filter(|(_, id)| **id != 0).map(|(name, id)| (name.clone(), *id)).collect();
+    }
+
+    // hands a message to the extension we advertised `id` for
+    pub fn receive(&mut self, id: u8, peer: SocketAddr, payload: &[u8], session: &Session) {
+        let Some(extension) = (id as usize).checked_sub(1).and_then(|i| self.extensions.get_mut(i)) else {
+            return
+        };
+        if let Err(e) = extension.receive(peer, payload, session) {
+            warn!("peer {} sent {}", peer, e);
+        }
+    }
+
+    // messages due to the peer, with the ids it wants them sent as
+    pub fn poll(&mut self, peer: SocketAddr, state: &FileDownloadState) -> Vec<(u8, Vec<u8>)> {
+        let mut messages = Vec::new();
+        for extension in &mut self.extensions {
+            if let Some(&id) = self.remote.get(extension.name())
+                && let Some(payload) = extension.poll(peer, state) {
+                messages.push((id, payload));
+            }
+        }
+        messages
+    }
+}
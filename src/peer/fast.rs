@@ -0,0 +1,38 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+use crate::util::sha1::sha1_hash;
+
+// BEP 6 is advertised by the third bit from the right of the reserved handshake bytes
+pub const RESERVED_BYTE: usize = 7;
+pub const RESERVED_BIT: u8 = 0x04;
+
+// pieces a peer may request from us while choked
+const ALLOWED_FAST_SET_SIZE: usize = 10;
+
+pub fn supports_fast(reserved: &[u8; 8]) -> bool {
+    reserved[RESERVED_BYTE] & RESERVED_BIT != 0
+}
+
+// the canonical allowed fast set for a peer, derived from its IPv4 /24 so reconnecting does not earn it more pieces
+pub fn allowed_fast_set(ip: IpAddr, info_hash: &[u8; 20], num_pieces: usize) -> HashSet<u32> {
+    let mut set: HashSet<u32> = HashSet::new();
+    let IpAddr::V4(ip) = ip else {
+        return set
+    };
+    let size = ALLOWED_FAST_SET_SIZE.min(num_pieces);
+    let mut x: Vec<u8> = (u32::from(ip) & 0xFFFFFF00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    while set.len() < size {
+        let hash = sha1_hash(&x);
+        for chunk in hash.chunks_exact(4) {
+            if set.len() == size {
+                break
+            }
+            let y = u32::from_be_bytes(chunk.try_into().expect("chunks are 4 bytes"));
+            set.insert(y % num_pieces as u32);
+        }
+        x = hash.to_vec();
+    }
+    set
+}
@@ -1,14 +1,14 @@
 use std::fmt;
-use std::net::SocketAddrV4;
+use std::net::SocketAddr;
 use std::result::Result;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tracing::info;
 
-use crate::PEER_ID;
 use crate::metadata::bencode::{write_byte_string, write_bytes};
 use crate::peer::PeerError;
+use crate::peer::{extension, fast};
+use crate::peer::client::ClientId;
 
 const P_STR: &[u8] = b"BitTorrent protocol";
 
@@ -20,11 +20,14 @@ struct TorrentHandshake {
 }
 
 impl TorrentHandshake {
-    fn new(info_hash: &[u8; 20]) -> Self {
-        TorrentHandshake { 
-            flags: [0; 8],
+    fn new(info_hash: &[u8; 20], peer_id: &[u8; 20]) -> Self {
+        let mut flags = [0u8; 8];
+        flags[extension::RESERVED_BYTE] |= extension::RESERVED_BIT;
+        flags[fast::RESERVED_BYTE] |= fast::RESERVED_BIT;
+        TorrentHandshake {
+            flags,
             info_hash: info_hash.to_owned(),
-            peer_id: *PEER_ID
+            peer_id: peer_id.to_owned()
         }
     }
 }
@@ -53,6 +56,9 @@ impl fmt::Display for TorrentHandshake {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "peer id: ")?;
         write_byte_string(&self.peer_id, f)?;
+        if let Some(client) = ClientId::decode(&self.peer_id) {
+            write!(f, " ({client})")?;
+        }
         write!(f, ", flags: 0x")?;
         write_bytes(&self.flags, f)
     }
@@ -65,24 +71,91 @@ impl From<&TorrentHandshake> for [u8; 68] {
         bytes[1..20].copy_from_slice(P_STR);
         bytes[20..28].copy_from_slice(&handshake.flags);
         bytes[28..48].copy_from_slice(&handshake.info_hash);
-        bytes[48..68].copy_from_slice(PEER_ID);
+        bytes[48..68].copy_from_slice(&handshake.peer_id);
         bytes
     }
 }
 
-pub(crate) async fn handshake(address: &SocketAddrV4, stream: &mut TcpStream, info_hash: &[u8; 20]) -> Result<(), PeerError> {
-    let mine = TorrentHandshake::new(info_hash);
-    let my_bytes = <[u8;68]>::from(&mine);
-    stream.write_all(my_bytes.as_slice()).await.map_err(|e| PeerError::HandshakeTransmissionError(address.to_string(), e))?;
-    let mut buf: [u8; 68] = [0; 68];
-    stream.read_exact(&mut buf).await.map_err(|e| PeerError::HandshakeReceiveError(address.to_string(), e))?;
-
-    let slice: &[u8] = &buf;
-    let theirs = TorrentHandshake::try_from(slice)?;
+// returns the reserved bytes and peer id of the peer's handshake
+pub(crate) async fn handshake<S: AsyncRead + AsyncWrite + Unpin + ?Sized>(address: &SocketAddr, stream: &mut S, info_hash: &[u8; 20], peer_id: &[u8; 20]) -> Result<([u8; 8], [u8; 20]), PeerError> {
+    let mine = TorrentHandshake::new(info_hash, peer_id);
+    send_handshake(address, stream, &mine).await?;
+    let theirs = receive_handshake(address, stream).await?;
     if mine.info_hash == theirs.info_hash {
         info!("shook hands with peer {} ({})", address, &theirs);
-        Ok(())
+        Ok((theirs.flags, theirs.peer_id))
     } else {
         Err(PeerError::MismatchedHash(mine.info_hash, theirs.info_hash))
     }
 }
+
+// the inbound side waits for the peer to name the torrent before answering
+pub(crate) async fn accept_handshake<S: AsyncRead + AsyncWrite + Unpin + ?Sized>(address: &SocketAddr, stream: &mut S, info_hash: &[u8; 20], peer_id: &[u8; 20]) -> Result<([u8; 8], [u8; 20]), PeerError> {
+    let theirs = receive_handshake(address, stream).await?;
+    if theirs.info_hash != *info_hash {
+        return Err(PeerError::MismatchedHash(*info_hash, theirs.info_hash));
+    }
+    send_handshake(address, stream, &TorrentHandshake::new(info_hash, peer_id)).await?;
+    info!("accepted handshake from peer {} ({})", address, &theirs);
+    Ok((theirs.flags, theirs.peer_id))
+}
+
+async fn send_handshake<S: AsyncWrite + Unpin + ?Sized>(address: &SocketAddr, stream: &mut S, handshake: &TorrentHandshake) -> Result<(), PeerError> {
+    let bytes = <[u8;68]>::from(handshake);
+    stream.write_all(bytes.as_slice()).await.map_err(|e| PeerError::HandshakeTransmissionError(address.to_string(), e))?;
+    stream.flush().await.map_err(|e| PeerError::HandshakeTransmissionError(address.to_string(), e))
+}
+
+async fn receive_handshake<S: AsyncRead + Unpin + ?Sized>(address: &SocketAddr, stream: &mut S) -> Result<TorrentHandshake, PeerError> {
+    let mut buf: [u8; 68] = [0; 68];
+    stream.read_exact(&mut buf).await.map_err(|e| PeerError::HandshakeReceiveError(address.to_string(), e))?;
+    TorrentHandshake::try_from(buf.as_slice())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    const INFO_HASH: [u8; 20] = [1; 20];
+    const OUR_ID: [u8; 20] = *b"-TR0001-ourpeeridxxx";
+    const THEIR_ID: [u8; 20] = *b"-TR0001-theirpeeridx";
+
+    fn address() -> SocketAddr {
+        "127.0.0.1:6881".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn shakes_hands_over_a_stream() {
+        let (mut ours, mut theirs) = duplex(1024);
+        let address = address();
+        let (initiated, accepted) = tokio::join!(
+            handshake(&address, &mut ours, &INFO_HASH, &OUR_ID),
+            accept_handshake(&address, &mut theirs, &INFO_HASH, &THEIR_ID),
+        );
+        let (flags, peer_id) = initiated.unwrap();
+        assert_eq!(peer_id, THEIR_ID);
+        assert!(extension::supports_extensions(&flags));
+        assert!(fast::supports_fast(&flags));
+        let (_, peer_id) = accepted.unwrap();
+        assert_eq!(peer_id, OUR_ID);
+    }
+
+    #[tokio::test]
+    async fn refuses_handshakes_for_other_torrents() {
+        let (mut ours, mut theirs) = duplex(1024);
+        send_handshake(&address(), &mut ours, &TorrentHandshake::new(&[2; 20], &OUR_ID)).await.unwrap();
+        let result = accept_handshake(&address(), &mut theirs, &INFO_HASH, &THEIR_ID).await;
+        assert!(matches!(result, Err(PeerError::MismatchedHash(ours, theirs)) if ours == INFO_HASH && theirs == [2; 20]));
+    }
+
+    #[test]
+    fn rejects_other_protocols() {
+        let mut bytes = <[u8; 68]>::from(&TorrentHandshake::new(&INFO_HASH, &OUR_ID));
+        bytes[1] = b'b';
+        assert!(matches!(TorrentHandshake::try_from(bytes.as_slice()), Err(PeerError::InvalidProtocolId(_))));
+        bytes[0] = 18;
+        assert!(matches!(TorrentHandshake::try_from(bytes.as_slice()), Err(PeerError::InvalidProtocolIdLength(18))));
+        assert!(matches!(TorrentHandshake::try_from(&bytes[..67]), Err(PeerError::InvalidHandshakeLength(67))));
+    }
+}
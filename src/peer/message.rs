@@ -1,5 +1,4 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::peer::{Bitfield, PeerError};
 
@@ -15,6 +14,12 @@ pub enum MessageId {
     Request       = 6,
     Piece         = 7,
     Cancel        = 8,
+    SuggestPiece  = 13,
+    HaveAll       = 14,
+    HaveNone      = 15,
+    RejectRequest = 16,
+    AllowedFast   = 17,
+    Extended      = 20,
 }
 
 #[derive(Debug)]
@@ -29,6 +34,27 @@ pub enum Message {
     Request { index: u32, begin: u32, length: u32 },
     Piece { index: u32, begin: u32, bytes: Vec<u8> },
     Cancel { index: u32, begin: u32, length: u32 },
+    SuggestPiece { index: u32 },
+    HaveAll,
+    HaveNone,
+    RejectRequest { index: u32, begin: u32, length: u32 },
+    AllowedFast { index: u32 },
+    Extended { id: u8, payload: Vec<u8> },
+}
+
+impl MessageId {
+    // the shortest and longest payloads a message may have, not counting the id
+    fn payload_bounds(self) -> (usize, usize) {
+        match self {
+            MessageId::Choke | MessageId::Unchoke | MessageId::Interested | MessageId::NotInterested
+            | MessageId::HaveAll | MessageId::HaveNone => (0, 0),
+            MessageId::Have | MessageId::SuggestPiece | MessageId::AllowedFast => (4, 4),
+            MessageId::Request | MessageId::Cancel | MessageId::RejectRequest => (12, 12),
+            MessageId::Piece => (8, 8 + MAX_BLOCK_LENGTH as usize),
+            MessageId::Bitfield => (1, MAX_BITFIELD_LENGTH),
+            MessageId::Extended => (1, MAX_EXTENDED_LENGTH),
+        }
+    }
 }
 
 impl TryFrom<u8> for MessageId {
@@ -45,181 +71,294 @@ impl TryFrom<u8> for MessageId {
             6 => Ok(MessageId::Request),
             7 => Ok(MessageId::Piece),
             8 => Ok(MessageId::Cancel),
+            13 => Ok(MessageId::SuggestPiece),
+            14 => Ok(MessageId::HaveAll),
+            15 => Ok(MessageId::HaveNone),
+            16 => Ok(MessageId::RejectRequest),
+            17 => Ok(MessageId::AllowedFast),
+            20 => Ok(MessageId::Extended),
             _ => Err(PeerError::UnknownMessageId(value)),
         }
     }
 }
 
-impl Message {
-    pub async fn read_message(stream: &mut TcpStream) -> Result<Self, PeerError> {
-        let mut buf: [u8; 4] = [0; 4];
-        Message::read_bytes(stream, &mut buf).await?;
-
-        let total_length = u32::from_be_bytes(buf[0..4].try_into().expect("buf verified to be size 4"));
-                
-        if total_length == 0 {
-            return Ok(Message::KeepAlive)
-        }
-
-        let mut id_buf: [u8; 1] = [0; 1];
-        Message::read_bytes(stream, &mut id_buf).await?;
-        let id: MessageId = MessageId::try_from(id_buf[0])?;
-        let payload_length = total_length as usize - 1;
+// how much is read from the stream at a time
+const READ_SIZE: usize = 64 * 1024;
+// the largest block requested or served
+pub const MAX_BLOCK_LENGTH: u32 = 128 * 1024;
+// enough for torrents of up to 2^21 pieces
+const MAX_BITFIELD_LENGTH: usize = 256 * 1024;
+const MAX_EXTENDED_LENGTH: usize = 1024 * 1024;
 
-        if id == MessageId::Choke ||
-           id == MessageId::Unchoke ||
-           id == MessageId::Interested ||
-           id == MessageId::NotInterested {
-            Message::consume(stream, payload_length, 0).await?;
-        }
-
-        match id {
-            MessageId::Bitfield => Message::read_bitfield(stream, payload_length).await,
-            MessageId::Piece => Message::read_piece(stream, payload_length).await,
-            MessageId::Have => Message::read_have(stream, payload_length).await,
-            MessageId::Request => Message::read_12(stream, true, payload_length).await,
-            MessageId::Cancel => Message::read_12(stream, false, payload_length).await,
-            MessageId::Choke => Ok(Message::Choke),
-            MessageId::Unchoke => Ok(Message::Unchoke),
-            MessageId::Interested => Ok(Message::Interested),
-            MessageId::NotInterested => Ok(Message::NotInterested),
-        }
-    }
+// reads messages from any stream, decoding as many as each read delivers
+#[derive(Debug)]
+pub struct MessageReader<R> {
+    stream: R,
+    buf: Vec<u8>,
+}
 
-    async fn read_bitfield(stream: &mut TcpStream, payload_length: usize) -> Result<Self, PeerError> {
-        let bytes = Message::read_variable_message(stream, payload_length).await?;
-        Ok(Message::Bitfield{ bitfield: Bitfield::from(bytes) })
+impl<R: AsyncRead + Unpin> MessageReader<R> {
+    pub fn new(stream: R) -> Self {
+        MessageReader { stream, buf: Vec::with_capacity(READ_SIZE) }
     }
 
-    async fn read_piece(stream: &mut TcpStream, payload_length: usize) -> Result<Self, PeerError> {
-        let mut bytes = Message::read_variable_message(stream, payload_length).await?;
-        if bytes.len() < 8 {
-            return Err(PeerError::PieceMessageTooSmall(bytes.len()));
+    pub async fn read(&mut self) -> Result<Message, PeerError> {
+        loop {
+            if let Some((message, used)) = Message::decode(&self.buf)? {
+                self.buf.drain(..used);
+                return Ok(message)
+            }
+            self.buf.reserve(READ_SIZE);
+            let read = self.stream.read_buf(&mut self.buf).await.map_err(|e| PeerError::MessageReceiveError(e, READ_SIZE))?;
+            if read == 0 {
+                let eof = std::io::Error::from(std::io::ErrorKind::UnexpectedEof);
+                return Err(PeerError::MessageReceiveError(eof, self.buf.len()))
+            }
         }
-        let index: u32 = u32::from_be_bytes(bytes[0..4].try_into().expect("bytes length checked to be at least 8"));
-        let begin: u32 = u32::from_be_bytes(bytes[4..8].try_into().expect("bytes length checked to be at least 8"));
-        bytes.drain(0..8);
-        Ok(Message::Piece{index, begin, bytes})
-    }
-
-    async fn read_variable_message(stream: &mut TcpStream, payload_length: usize) -> Result<Vec<u8>, PeerError> {
-        let mut v = vec![0u8; payload_length];
-        Message::read_bytes(stream, v.as_mut_slice()).await?;
-        Ok(v)
-    }
-
-    async fn read_bytes(stream: &mut TcpStream, buf: &mut[u8]) -> Result<(), PeerError> {
-        stream.read_exact(buf).await.map(|_| ()).map_err(|e| PeerError::MessageReceiveError(e, buf.len()))
     }
+}
 
-    async fn read_have(stream: &mut TcpStream, payload_length: usize) -> Result<Self, PeerError> {
-        let mut buf: [u8; 4] = [0; 4];
-        Message::read_bytes(stream, &mut buf).await?;
-        Message::consume(stream, payload_length, 4).await?;
-        let index = u32::from_be_bytes(buf);
-        Ok(Message::Have {index})
+// the framing is a 4 byte big endian length followed by that many bytes: the message id, then its payload
+impl Message {
+    pub async fn send<W: AsyncWrite + Unpin>(&self, stream: &mut W) -> Result<(), PeerError> {
+        let bytes = self.encode();
+        stream.write_all(&bytes).await.map_err(|e| PeerError::MessageTransmitError(e, bytes.len()))?;
+        // transports that buffer, such as encrypted streams, send only once flushed
+        stream.flush().await.map_err(|e| PeerError::MessageTransmitError(e, bytes.len()))
     }
 
-    async fn read_12(stream: &mut TcpStream, request: bool, payload_length: usize) -> Result<Self, PeerError> {
-        let mut buf: [u8; 12] = [0; 12];
-        Message::read_bytes(stream, &mut buf).await?;
-        Message::consume(stream, payload_length, 12).await?;
-        let index = u32::from_be_bytes(buf[0..4].try_into().expect("buf verified to be size 12"));
-        let begin = u32::from_be_bytes(buf[4..8].try_into().expect("buf verified to be size 12"));
-        let length = u32::from_be_bytes(buf[8..12].try_into().expect("buf verified to be size 12"));
-        if request {
-            Ok(Message::Request { index, begin, length })
+    // the first whole message in `buf` and how many bytes it took, or None if more bytes are needed;
+    // a length the message id does not allow fails as soon as the id arrives rather than once the body does
+    pub fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>, PeerError> {
+        let Some(prefix) = buf.get(0..4) else {
+            return Ok(None)
+        };
+        let total_length = u32::from_be_bytes(prefix.try_into().expect("prefix is 4 bytes")) as usize;
+        if total_length == 0 {
+            return Ok(Some((Message::KeepAlive, 4)))
         }
-        else {
-            Ok(Message::Cancel { index, begin, length })
+        let Some(&id) = buf.get(4) else {
+            return Ok(None)
+        };
+        let id = MessageId::try_from(id)?;
+        let (min, max) = id.payload_bounds();
+        let payload_length = total_length - 1;
+        if payload_length > max {
+            return Err(PeerError::MessageTooLarge(id, payload_length));
         }
-    }
-
-    async fn consume(stream: &mut TcpStream, payload_length: usize, expected: usize) -> Result<(), PeerError> {
-        if payload_length > expected {
-            let extra = payload_length - expected;
-            let mut buf = vec![0; extra];
-            stream.read_exact(&mut buf).await.map_err(|e| PeerError::MessageReceiveError(e, extra))?;
+        if payload_length < min {
+            return Err(PeerError::MessageTooSmall(id, payload_length));
         }
-        Ok(())
-    }
-
-    pub async fn send_keep_alive(stream: &mut TcpStream) -> Result<(), PeerError> {
-        let buf: [u8; 4] = [0; 4];
-        Message::send_bytes(stream, &buf).await
+        let Some(payload) = buf.get(5..4 + total_length) else {
+            return Ok(None)
+        };
+        Ok(Some((Message::parse(id, payload)?, 4 + total_length)))
     }
 
-    pub async fn send_choke(stream: &mut TcpStream) -> Result<(), PeerError> {
-        Message::send_header(stream, MessageId::Choke).await
+    pub fn encode(&self) -> Vec<u8> {
+        let id = match self {
+            Message::KeepAlive => return vec![0; 4],
+            Message::Choke => MessageId::Choke,
+            Message::Unchoke => MessageId::Unchoke,
+            Message::Interested => MessageId::Interested,
+            Message::NotInterested => MessageId::NotInterested,
+            Message::Have { .. } => MessageId::Have,
+            Message::Bitfield { .. } => MessageId::Bitfield,
+            Message::Request { .. } => MessageId::Request,
+            Message::Piece { .. } => MessageId::Piece,
+            Message::Cancel { .. } => MessageId::Cancel,
+            Message::SuggestPiece { .. } => MessageId::SuggestPiece,
+            Message::HaveAll => MessageId::HaveAll,
+            Message::HaveNone => MessageId::HaveNone,
+            Message::RejectRequest { .. } => MessageId::RejectRequest,
+            Message::AllowedFast { .. } => MessageId::AllowedFast,
+            Message::Extended { .. } => MessageId::Extended,
+        };
+        // the length prefix is filled in once the payload is written
+        let mut buf = vec![0, 0, 0, 0, id as u8];
+        match self {
+            Message::Have { index } | Message::SuggestPiece { index } | Message::AllowedFast { index } => {
+                buf.extend_from_slice(&index.to_be_bytes());
+            },
+            Message::Bitfield { bitfield } => buf.extend_from_slice(bitfield.as_bytes()),
+            Message::Request { index, begin, length }
+            | Message::Cancel { index, begin, length }
+            | Message::RejectRequest { index, begin, length } => {
+                buf.extend_from_slice(&index.to_be_bytes());
+                buf.extend_from_slice(&begin.to_be_bytes());
+                buf.extend_from_slice(&length.to_be_bytes());
+            },
+            Message::Piece { index, begin, bytes } => {
+                buf.extend_from_slice(&index.to_be_bytes());
+                buf.extend_from_slice(&begin.to_be_bytes());
+                buf.extend_from_slice(bytes);
+            },
+            Message::Extended { id, payload } => {
+                buf.push(*id);
+                buf.extend_from_slice(payload);
+            },
+            _ => (),
+        }
+        let length = buf.len() as u32 - 4;
+        buf[0..4].copy_from_slice(&length.to_be_bytes());
+        buf
     }
 
-    pub async fn send_unchoke(stream: &mut TcpStream) -> Result<(), PeerError> {
-        Message::send_header(stream, MessageId::Unchoke).await
+    // a message from its id and a payload already checked against the id's bounds
+    fn parse(id: MessageId, payload: &[u8]) -> Result<Self, PeerError> {
+        match id {
+            MessageId::Choke => Ok(Message::Choke),
+            MessageId::Unchoke => Ok(Message::Unchoke),
+            MessageId::Interested => Ok(Message::Interested),
+            MessageId::NotInterested => Ok(Message::NotInterested),
+            MessageId::HaveAll => Ok(Message::HaveAll),
+            MessageId::HaveNone => Ok(Message::HaveNone),
+            MessageId::Have => Ok(Message::Have { index: Message::read_u32(payload, 0) }),
+            MessageId::SuggestPiece => Ok(Message::SuggestPiece { index: Message::read_u32(payload, 0) }),
+            MessageId::AllowedFast => Ok(Message::AllowedFast { index: Message::read_u32(payload, 0) }),
+            MessageId::Request | MessageId::Cancel | MessageId::RejectRequest => {
+                let index = Message::read_u32(payload, 0);
+                let begin = Message::read_u32(payload, 4);
+                let length = Message::read_u32(payload, 8);
+                match id {
+                    MessageId::Request => Ok(Message::Request { index, begin, length }),
+                    MessageId::Cancel => Ok(Message::Cancel { index, begin, length }),
+                    _ => Ok(Message::RejectRequest { index, begin, length }),
+                }
+            },
+            MessageId::Bitfield => Ok(Message::Bitfield { bitfield: Bitfield::from(payload.to_vec()) }),
+            MessageId::Piece => {
+                let index = Message::read_u32(payload, 0);
+                let begin = Message::read_u32(payload, 4);
+                Ok(Message::Piece { index, begin, bytes: payload[8..].to_vec() })
+            },
+            MessageId::Extended => {
+                let Some((&id, payload)) = payload.split_first() else {
+                    return Err(PeerError::MalformedExtensionMessage("missing extended message id"));
+                };
+                Ok(Message::Extended { id, payload: payload.to_vec() })
+            },
+        }
     }
 
-    pub async fn send_interested(stream: &mut TcpStream) -> Result<(), PeerError> {
-        Message::send_header(stream, MessageId::Interested).await
+    fn read_u32(payload: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(payload[offset..offset + 4].try_into().expect("payload length checked against its bounds"))
     }
+}
 
-    pub async fn send_not_interested(stream: &mut TcpStream) -> Result<(), PeerError> {
-        Message::send_header(stream, MessageId::NotInterested).await
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
 
-    pub async fn send_bitfield(stream: &mut TcpStream, bitmap: &[u8]) -> Result<(), PeerError> {
-        let mut buf = vec![0; 4 + 1 + bitmap.len()];
-        Message::encode_header(MessageId::Bitfield, 1 + bitmap.len() as u32, &mut buf);
-        buf[5..].copy_from_slice(bitmap);
-        Message::send_bytes(stream, &buf).await
-    }
+    const IDS: [MessageId; 15] = [
+        MessageId::Choke, MessageId::Unchoke, MessageId::Interested, MessageId::NotInterested, MessageId::Have,
+        MessageId::Bitfield, MessageId::Request, MessageId::Piece, MessageId::Cancel, MessageId::SuggestPiece,
+        MessageId::HaveAll, MessageId::HaveNone, MessageId::RejectRequest, MessageId::AllowedFast, MessageId::Extended,
+    ];
 
-    pub async fn send_piece(stream: &mut TcpStream, index: u32, begin: u32, data: &[u8]) -> Result<(), PeerError> {
-        let mut buf = vec![0; 4 + 1 + 8 + data.len()];
-        Message::encode_header(MessageId::Piece, 1 + 8 + data.len() as u32, &mut buf);
-        buf[5..9].copy_from_slice(index.to_be_bytes().as_slice());
-        buf[9..13].copy_from_slice(begin.to_be_bytes().as_slice());
-        buf[13..].copy_from_slice(data);
-        Message::send_bytes(stream, &buf).await
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have { index: 7 },
+            Message::Bitfield { bitfield: Bitfield::from(vec![0b1010_0000, 0b0000_0001]) },
+            Message::Request { index: 1, begin: 16384, length: 16384 },
+            Message::Piece { index: 2, begin: 32768, bytes: vec![5; 100] },
+            Message::Cancel { index: 3, begin: 0, length: 16384 },
+            Message::SuggestPiece { index: 4 },
+            Message::HaveAll,
+            Message::HaveNone,
+            Message::RejectRequest { index: 5, begin: 16384, length: 8192 },
+            Message::AllowedFast { index: 6 },
+            Message::Extended { id: 1, payload: b"d1:pi6881ee".to_vec() },
+        ]
     }
 
-    pub async fn send_have(stream: &mut TcpStream, index: u32) -> Result<(), PeerError> {
-        let mut buf: [u8; 9] = [0; 9];
-        Message::encode_header(MessageId::Have, 1 + 4, &mut buf);
-        buf[5..9].copy_from_slice(index.to_be_bytes().as_slice());
-        Message::send_bytes(stream, &buf).await
+    // a frame whose length prefix claims `payload_length` bytes after the id, holding `body` of them
+    fn frame(id: MessageId, payload_length: usize, body: usize) -> Vec<u8> {
+        let mut buf = ((payload_length + 1) as u32).to_be_bytes().to_vec();
+        buf.push(id as u8);
+        buf.resize(5 + body, 0);
+        buf
     }
 
-    pub async fn send_request(stream: &mut TcpStream, index: u32, begin: u32, length: u32) -> Result<(), PeerError> {
-        let mut buf: [u8; 17] = [0; 17];
-        Message::encode_12(true, index, begin, length, &mut buf);
-        Message::send_bytes(stream, &buf).await
+    #[test]
+    fn messages_round_trip() {
+        for message in messages() {
+            let bytes = message.encode();
+            let (decoded, used) = Message::decode(&bytes).unwrap().expect("a whole message");
+            assert_eq!(used, bytes.len());
+            assert_eq!(std::mem::discriminant(&decoded), std::mem::discriminant(&message));
+            assert_eq!(decoded.encode(), bytes);
+        }
     }
 
-    pub async fn send_cancel(stream: &mut TcpStream, index: u32, begin: u32, length: u32) -> Result<(), PeerError> {
-        let mut buf: [u8; 17] = [0; 17];
-        Message::encode_12(false, index, begin, length, &mut buf);
-        Message::send_bytes(stream, &buf).await
+    #[test]
+    fn partial_messages_need_more_bytes() {
+        for message in messages() {
+            let bytes = message.encode();
+            for end in 0..bytes.len() {
+                assert!(Message::decode(&bytes[..end]).unwrap().is_none());
+            }
+        }
     }
 
-    fn encode_12(request: bool, index: u32, begin: u32, length: u32, buf: &mut[u8]) {
-        let id = if request { MessageId::Request} else { MessageId::Cancel };
-        Message::encode_header(id, 13, buf);
-        buf[5..9].copy_from_slice(index.to_be_bytes().as_slice());
-        buf[9..13].copy_from_slice(begin.to_be_bytes().as_slice());
-        buf[13..17].copy_from_slice(length.to_be_bytes().as_slice());
+    #[test]
+    fn rejects_payloads_longer_than_the_id_allows() {
+        for id in IDS {
+            let (_, max) = id.payload_bounds();
+            // the header alone is enough to refuse it
+            let result = Message::decode(&frame(id, max + 1, 0));
+            assert!(matches!(result, Err(PeerError::MessageTooLarge(i, n)) if i == id && n == max + 1), "{:?}", id);
+        }
     }
 
-    fn encode_header(id: MessageId, length: u32, buf: &mut[u8]) {
-        buf[0..4].copy_from_slice(length.to_be_bytes().as_slice());
-        buf[4] = id as u8;
+    #[test]
+    fn rejects_payloads_shorter_than_the_id_allows() {
+        for id in IDS {
+            let (min, _) = id.payload_bounds();
+            if min == 0 {
+                continue
+            }
+            let result = Message::decode(&frame(id, min - 1, min - 1));
+            assert!(matches!(result, Err(PeerError::MessageTooSmall(i, n)) if i == id && n == min - 1), "{:?}", id);
+        }
     }
 
-    async fn send_header(stream: &mut TcpStream, id: MessageId) -> Result<(), PeerError> {
-        let mut buf: [u8; 5] = [0; 5];
-        Message::encode_header(id, 1, &mut buf);
-        Message::send_bytes(stream, &buf).await
+    #[test]
+    fn rejects_unknown_ids() {
+        let mut buf = 1u32.to_be_bytes().to_vec();
+        buf.push(9);
+        assert!(matches!(Message::decode(&buf), Err(PeerError::UnknownMessageId(9))));
     }
 
-    async fn send_bytes(stream: &mut TcpStream, bytes: &[u8]) -> Result<(), PeerError> {
-        stream.write_all(bytes).await.map_err(|e| PeerError::MessageTransmitError(e, bytes.len()))
+    #[tokio::test]
+    async fn reads_messages_split_across_reads() {
+        // a small pipe so that messages arrive in pieces
+        let (mut ours, theirs) = duplex(7);
+        let sent = messages();
+        let write = async {
+            for message in &sent {
+                message.send(&mut ours).await.unwrap();
+            }
+            drop(ours);
+        };
+        let read = async {
+            let mut reader = MessageReader::new(theirs);
+            let mut received = Vec::new();
+            while let Ok(message) = reader.read().await {
+                received.push(message);
+            }
+            received
+        };
+        let ((), received) = tokio::join!(write, read);
+        assert_eq!(received.len(), sent.len());
+        for (received, sent) in received.iter().zip(&sent) {
+            assert_eq!(received.encode(), sent.encode());
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,347 @@
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::timeout;
+
+use crate::peer::PeerError;
+use crate::util::dh::{mod_pow, DH_BYTES};
+use crate::util::from_hex;
+use crate::util::rc4::Rc4;
+use crate::util::sha1::sha1_hash;
+
+// the 768-bit prime of the Diffie-Hellman exchange; the generator is 2
+const P: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const G: &[u8] = &[2];
+// private keys of 160 bits are as strong as the exchange allows
+const PRIVATE_KEY_BYTES: usize = 20;
+// random padding after each public key and inside the handshake hides its length
+const MAX_PAD: usize = 512;
+// the verification constant, eight zero bytes, marks where encryption begins
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+// RC4's first keystream bytes leak the key
+const RC4_DISCARD: usize = 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const BT_HANDSHAKE_PREFIX: &[u8; 20] = b"\x13BitTorrent protocol";
+
+// whether peer connections are obfuscated with message stream encryption
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncryptionPolicy {
+    // never encrypted; encrypted handshakes from peers are refused
+    Plaintext,
+    // encrypted where the peer supports it, falling back to plaintext
+    #[default]
+    Prefer,
+    // only encrypted; plaintext handshakes from peers are refused
+    Require,
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "plaintext" => Ok(EncryptionPolicy::Plaintext),
+            "prefer" => Ok(EncryptionPolicy::Prefer),
+            "require" => Ok(EncryptionPolicy::Require),
+            _ => Err(format!("unknown encryption policy `{s}`; expected plaintext, prefer or require")),
+        }
+    }
+}
+
+impl EncryptionPolicy {
+    // the methods offered in `crypto_provide`
+    fn provide(self) -> u32 {
+        match self {
+            EncryptionPolicy::Require => CRYPTO_RC4,
+            _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        }
+    }
+
+    // the method chosen from a peer's `crypto_provide`
+    fn select(self, provided: u32) -> Option<u32> {
+        if provided & CRYPTO_RC4 != 0 {
+            Some(CRYPTO_RC4)
+        } else if provided & CRYPTO_PLAINTEXT != 0 && self != EncryptionPolicy::Require {
+            Some(CRYPTO_PLAINTEXT)
+        } else {
+            None
+        }
+    }
+}
+
+// a peer connection after the encryption handshake, plaintext if that is what was negotiated
+#[derive(Debug)]
+pub struct MseStream<S> {
+    inner: S,
+    encrypt: Option<Rc4>,
+    decrypt: Option<Rc4>,
+    // decrypted bytes received during the handshake, read before anything else
+    prefix: Vec<u8>,
+    // encrypted bytes not yet accepted by the inner stream
+    pending: Vec<u8>,
+    written: usize,
+}
+
+impl<S> MseStream<S> {
+    fn new(inner: S, ciphers: Option<(Rc4, Rc4)>, prefix: Vec<u8>) -> Self {
+        let (encrypt, decrypt) = ciphers.unzip();
+        MseStream { inner, encrypt, decrypt, prefix, pending: Vec::new(), written: 0 }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt.is_some()
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()))
+            }
+            self.written += n;
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.prefix.is_empty() {
+            let n = this.prefix.len().min(buf.remaining());
+            buf.put_slice(&this.prefix[..n]);
+            this.prefix.drain(..n);
+            return Poll::Ready(Ok(()))
+        }
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(decrypt) = &mut this.decrypt {
+            decrypt.apply(&mut buf.filled_mut()[start..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+// encrypted bytes are taken whole, and whatever the inner stream does not accept goes out on the next write or flush
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.encrypt.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf)
+        }
+        ready!(this.poll_pending(cx))?;
+        let mut bytes = buf.to_vec();
+        if let Some(encrypt) = &mut this.encrypt {
+            encrypt.apply(&mut bytes);
+        }
+        this.pending = bytes;
+        if let Poll::Ready(Err(e)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(e))
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+fn prime() -> Vec<u8> {
+    from_hex(P).expect("prime is valid hex")
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    sha1_hash(&parts.concat())
+}
+
+// (base ^ exponent) mod P, which takes long enough to hold up other connections, so it runs on the blocking pool
+async fn exponentiate(base: Vec<u8>, exponent: [u8; PRIVATE_KEY_BYTES]) -> [u8; DH_BYTES] {
+    tokio::task::spawn_blocking(move || mod_pow(&base, &exponent, &prime()))
+        .await
+        .expect("modular exponentiation should not panic")
+}
+
+// a random private key and its public key
+async fn keypair() -> ([u8; PRIVATE_KEY_BYTES], [u8; DH_BYTES]) {
+    let private: [u8; PRIVATE_KEY_BYTES] = rand::random();
+    (private, exponentiate(G.to_vec(), private).await)
+}
+
+fn random_pad() -> Vec<u8> {
+    (0..rand::random_range(0..=MAX_PAD)).map(|_| rand::random()).collect()
+}
+
+// the initiator encrypts with keyA and the responder with keyB
+fn cipher(name: &[u8], secret: &[u8], info_hash: &[u8; 20]) -> Rc4 {
+    let mut rc4 = Rc4::new(&hash(&[name, secret, info_hash]));
+    rc4.discard(RC4_DISCARD);
+    rc4
+}
+
+fn io_error(e: io::Error) -> PeerError {
+    PeerError::EncryptionIoError(e)
+}
+
+// reads until the last `marker.len()` bytes equal `marker`, skipping at most MAX_PAD bytes of padding
+async fn synchronize<S: AsyncRead + Unpin>(stream: &mut S, marker: &[u8]) -> Result<(), PeerError> {
+    let mut window = vec![0u8; marker.len()];
+    stream.read_exact(&mut window).await.map_err(io_error)?;
+    for _ in 0..MAX_PAD {
+        if window == marker {
+            return Ok(())
+        }
+        window.remove(0);
+        window.push(stream.read_u8().await.map_err(io_error)?);
+    }
+    if window == marker {
+        Ok(())
+    } else {
+        Err(PeerError::EncryptionHandshakeFailed("peer's handshake never synchronized"))
+    }
+}
+
+// reads and decrypts exactly `n` bytes
+async fn read_decrypted<S: AsyncRead + Unpin>(stream: &mut S, decrypt: &mut Rc4, n: usize) -> Result<Vec<u8>, PeerError> {
+    let mut bytes = vec![0u8; n];
+    stream.read_exact(&mut bytes).await.map_err(io_error)?;
+    decrypt.apply(&mut bytes);
+    Ok(bytes)
+}
+
+// the outbound side of the handshake, before the BitTorrent handshake is sent over the result
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(stream: S, info_hash: &[u8; 20], policy: EncryptionPolicy) -> Result<MseStream<S>, PeerError> {
+    timeout(HANDSHAKE_TIMEOUT, initiate_handshake(stream, info_hash, policy))
+        .await
+        .map_err(|_| PeerError::EncryptionHandshakeFailed("timed out"))?
+}
+
+async fn initiate_handshake<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, info_hash: &[u8; 20], policy: EncryptionPolicy) -> Result<MseStream<S>, PeerError> {
+    let (private, public) = keypair().await;
+    stream.write_all(&[public.as_slice(), &random_pad()].concat()).await.map_err(io_error)?;
+    stream.flush().await.map_err(io_error)?;
+
+    let mut theirs = [0u8; DH_BYTES];
+    stream.read_exact(&mut theirs).await.map_err(io_error)?;
+    let secret = exponentiate(theirs.to_vec(), private).await;
+    let mut encrypt = cipher(b"keyA", &secret, info_hash);
+    let mut decrypt = cipher(b"keyB", &secret, info_hash);
+
+    // the responder finds the torrent by the hash of its info hash, masked so it cannot be read off the wire
+    let req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    let obfuscated: Vec<u8> = req2.iter().zip(req3).map(|(a, b)| a ^ b).collect();
+    let pad_c = random_pad();
+    let mut plain = VC.to_vec();
+    plain.extend_from_slice(&policy.provide().to_be_bytes());
+    plain.extend_from_slice(&(pad_c.len() as u16).to_be_bytes());
+    plain.extend_from_slice(&pad_c);
+    // no initial payload; the BitTorrent handshake follows once this completes
+    plain.extend_from_slice(&0u16.to_be_bytes());
+    encrypt.apply(&mut plain);
+    let message = [hash(&[b"req1", &secret]).as_slice(), &obfuscated, &plain].concat();
+    stream.write_all(&message).await.map_err(io_error)?;
+    stream.flush().await.map_err(io_error)?;
+
+    // the responder's reply starts with VC, found past its padding by what VC encrypts to
+    let mut marker = VC;
+    decrypt.clone().apply(&mut marker);
+    synchronize(&mut stream, &marker).await?;
+    decrypt.discard(VC.len());
+    let reply = read_decrypted(&mut stream, &mut decrypt, 6).await?;
+    let selected = u32::from_be_bytes(reply[0..4].try_into().expect("reply is 6 bytes"));
+    let pad_d = u16::from_be_bytes(reply[4..6].try_into().expect("reply is 6 bytes")) as usize;
+    if pad_d > MAX_PAD {
+        return Err(PeerError::EncryptionHandshakeFailed("padding too long"))
+    }
+    read_decrypted(&mut stream, &mut decrypt, pad_d).await?;
+    match selected {
+        CRYPTO_RC4 => Ok(MseStream::new(stream, Some((encrypt, decrypt)), Vec::new())),
+        CRYPTO_PLAINTEXT if policy != EncryptionPolicy::Require => Ok(MseStream::new(stream, None, Vec::new())),
+        _ => Err(PeerError::EncryptionHandshakeFailed("peer selected a method we did not offer")),
+    }
+}
+
+// the inbound side of the handshake, also letting through plaintext BitTorrent handshakes if the policy allows
+pub async fn respond<S: AsyncRead + AsyncWrite + Unpin>(stream: S, info_hash: &[u8; 20], policy: EncryptionPolicy) -> Result<MseStream<S>, PeerError> {
+    timeout(HANDSHAKE_TIMEOUT, respond_handshake(stream, info_hash, policy))
+        .await
+        .map_err(|_| PeerError::EncryptionHandshakeFailed("timed out"))?
+}
+
+async fn respond_handshake<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, info_hash: &[u8; 20], policy: EncryptionPolicy) -> Result<MseStream<S>, PeerError> {
+    let mut theirs = [0u8; DH_BYTES];
+    stream.read_exact(&mut theirs[..BT_HANDSHAKE_PREFIX.len()]).await.map_err(io_error)?;
+    if theirs[..BT_HANDSHAKE_PREFIX.len()] == *BT_HANDSHAKE_PREFIX {
+        if policy == EncryptionPolicy::Require {
+            return Err(PeerError::EncryptionHandshakeFailed("plaintext connections are refused"))
+        }
+        return Ok(MseStream::new(stream, None, BT_HANDSHAKE_PREFIX.to_vec()))
+    }
+    if policy == EncryptionPolicy::Plaintext {
+        return Err(PeerError::EncryptionHandshakeFailed("encrypted connections are refused"))
+    }
+    stream.read_exact(&mut theirs[BT_HANDSHAKE_PREFIX.len()..]).await.map_err(io_error)?;
+
+    let (private, public) = keypair().await;
+    stream.write_all(&[public.as_slice(), &random_pad()].concat()).await.map_err(io_error)?;
+    stream.flush().await.map_err(io_error)?;
+    let secret = exponentiate(theirs.to_vec(), private).await;
+
+    synchronize(&mut stream, &hash(&[b"req1", &secret])).await?;
+    let mut obfuscated = [0u8; 20];
+    stream.read_exact(&mut obfuscated).await.map_err(io_error)?;
+    let req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    if obfuscated.iter().zip(req3).map(|(a, b)| a ^ b).ne(req2) {
+        return Err(PeerError::EncryptionHandshakeFailed("peer asked for another torrent"))
+    }
+    let mut encrypt = cipher(b"keyB", &secret, info_hash);
+    let mut decrypt = cipher(b"keyA", &secret, info_hash);
+
+    let request = read_decrypted(&mut stream, &mut decrypt, VC.len() + 6).await?;
+    if request[..VC.len()] != VC {
+        return Err(PeerError::EncryptionHandshakeFailed("verification constant mismatch"))
+    }
+    let provided = u32::from_be_bytes(request[8..12].try_into().expect("request is 14 bytes"));
+    let pad_c = u16::from_be_bytes(request[12..14].try_into().expect("request is 14 bytes")) as usize;
+    if pad_c > MAX_PAD {
+        return Err(PeerError::EncryptionHandshakeFailed("padding too long"))
+    }
+    read_decrypted(&mut stream, &mut decrypt, pad_c).await?;
+    let ia_length = read_decrypted(&mut stream, &mut decrypt, 2).await?;
+    let ia_length = u16::from_be_bytes(ia_length.try_into().expect("length is 2 bytes")) as usize;
+    let initial_payload = read_decrypted(&mut stream, &mut decrypt, ia_length).await?;
+
+    let Some(selected) = policy.select(provided) else {
+        return Err(PeerError::EncryptionHandshakeFailed("peer offered no method we accept"))
+    };
+    let pad_d = random_pad();
+    let mut reply = VC.to_vec();
+    reply.extend_from_slice(&selected.to_be_bytes());
+    reply.extend_from_slice(&(pad_d.len() as u16).to_be_bytes());
+    reply.extend_from_slice(&pad_d);
+    encrypt.apply(&mut reply);
+    stream.write_all(&reply).await.map_err(io_error)?;
+    stream.flush().await.map_err(io_error)?;
+
+    let ciphers = (selected == CRYPTO_RC4).then_some((encrypt, decrypt));
+    Ok(MseStream::new(stream, ciphers, initial_payload))
+}
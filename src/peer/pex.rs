@@ -0,0 +1,211 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use tracing::info;
+
+use crate::metadata::bencode::BencodeValue;
+use crate::metadata::tracker::{parse_compact_peers, parse_compact_peers6};
+use crate::peer::PeerError;
+use crate::peer::downloader::{FileDownloadState, Session};
+use crate::peer::extension::Extension;
+
+pub const UT_PEX: &str = "ut_pex";
+
+// flags describing each added peer
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+// messages arriving sooner than this after the last are ignored, allowing peers' timers some jitter
+const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(50);
+// BEP 11 limits each message to this many added peers; any beyond are ignored
+const MAX_PEERS_PER_MESSAGE: usize = 50;
+
+#[derive(Debug, Default)]
+pub struct PexMessage {
+    pub added: Vec<(SocketAddr, u8)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+// what has been advertised to a single peer
+#[derive(Debug, Default)]
+pub struct PexState {
+    advertised: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+fn compact<'a>(peers: impl Iterator<Item = &'a SocketAddr>) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for peer in peers {
+        match peer {
+            SocketAddr::V4(a) => bytes.extend_from_slice(&a.ip().octets()),
+            SocketAddr::V6(a) => bytes.extend_from_slice(&a.ip().octets()),
+        }
+        bytes.extend_from_slice(&peer.port().to_be_bytes());
+    }
+    bytes
+}
+
+fn get_bytes<'a>(items: &'a BTreeMap<Vec<u8>, BencodeValue>, key: &[u8]) -> &'a [u8] {
+    match items.get(key) {
+        Some(BencodeValue::ByteString(b)) => b.as_slice(),
+        _ => &[],
+    }
+}
+
+impl PexMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (added4, added6): (Vec<_>, Vec<_>) = self.added.iter().partition(|(a, _)| a.is_ipv4());
+        let mut items: BTreeMap<Vec<u8>, BencodeValue> = BTreeMap::new();
+        items.insert(b"added".to_vec(), BencodeValue::ByteString(compact(added4.iter().map(|(a, _)| a))));
+        items.insert(b"added.f".to_vec(), BencodeValue::ByteString(added4.iter().map(|(_, f)| *f).collect()));
+        items.insert(b"added6".to_vec(), BencodeValue::ByteString(compact(added6.iter().map(|(a, _)| a))));
+        items.insert(b"added6.f".to_vec(), BencodeValue::ByteString(added6.iter().map(|(_, f)| *f).collect()));
+        items.insert(b"dropped".to_vec(), BencodeValue::ByteString(compact(self.dropped.iter().filter(|a| a.is_ipv4()))));
+        items.insert(b"dropped6".to_vec(), BencodeValue::ByteString(compact(self.dropped.iter().filter(|a| a.is_ipv6()))));
+        Vec::from(&BencodeValue::Dictionary(items))
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, PeerError> {
+        let malformed = || PeerError::MalformedExtensionMessage("ut_pex");
+        let Ok(BencodeValue::Dictionary(items)) = BencodeValue::try_from(payload) else {
+            return Err(malformed())
+        };
+
+        let mut added: Vec<(SocketAddr, u8)> = Vec::new();
+        let added4 = parse_compact_peers(get_bytes(&items, b"added")).map_err(|_| malformed())?;
+        let added6 = parse_compact_peers6(get_bytes(&items, b"added6")).map_err(|_| malformed())?;
+        for (peers, flags) in [(added4, get_bytes(&items, b"added.f")), (added6, get_bytes(&items, b"added6.f"))] {
+            for (i, peer) in peers.into_iter().enumerate() {
+                added.push((peer, flags.get(i).copied().unwrap_or(0)));
+            }
+        }
+
+        let mut dropped = parse_compact_peers(get_bytes(&items, b"dropped")).map_err(|_| malformed())?;
+        dropped.extend(parse_compact_peers6(get_bytes(&items, b"dropped6")).map_err(|_| malformed())?);
+
+        Ok(PexMessage { added, dropped })
+    }
+}
+
+impl PexState {
+    // the changes to `connected` since the last message sent to `remote`, at most once per interval
+    pub fn next_message(&mut self, connected: &HashMap<SocketAddr, u8>, remote: SocketAddr) -> Option<PexMessage> {
+        if self.last_sent.is_some_and(|t| t.elapsed() < PEX_INTERVAL) {
+            return None
+        }
+
+        let added: Vec<(SocketAddr, u8)> = connected
+            .iter()
+            .filter(|(a, _)| **a != remote && !self.advertised.contains(a))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .map(|(a, f)| (*a, *f))
+            .collect();
+        let dropped: Vec<SocketAddr> = self.advertised
+            .iter()
+            .filter(|a| !connected.contains_key(a))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None
+        }
+
+        for (a, _) in &added {
+            self.advertised.insert(*a);
+        }
+        for a in &dropped {
+            self.advertised.remove(a);
+        }
+        self.last_sent = Some(Instant::now());
+        Some(PexMessage { added, dropped })
+    }
+
+    // the added peers worth trying from a message `peer` sent, holding it to BEP 11's limits
+    fn accept(&mut self, message: PexMessage, peer: SocketAddr) -> Vec<SocketAddr> {
+        if self.last_received.is_some_and(|t| t.elapsed() < MIN_RECEIVE_INTERVAL) {
+            info!("ignoring peer exchange from peer {}: sent too soon after its last", peer);
+            return Vec::new()
+        }
+        self.last_received = Some(Instant::now());
+        if message.added.len() > MAX_PEERS_PER_MESSAGE {
+            info!("peer {} exchanged {} added peers; taking only the first {}", peer, message.added.len(), MAX_PEERS_PER_MESSAGE);
+        }
+        message.added.into_iter().take(MAX_PEERS_PER_MESSAGE).map(|(a, _)| a).collect()
+    }
+}
+
+impl Extension for PexState {
+    fn name(&self) -> &'static str {
+        UT_PEX
+    }
+
+    fn receive(&mut self, peer: SocketAddr, payload: &[u8], session: &Session) -> Result<(), PeerError> {
+        let message = PexMessage::decode(payload)?;
+        info!("peer {} exchanged {} added and {} dropped peers", peer, message.added.len(), message.dropped.len());
+        let peers = self.accept(message, peer);
+        if !peers.is_empty() {
+            let _ = session.discovered.send(peers);
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self, peer: SocketAddr, state: &FileDownloadState) -> Option<Vec<u8>> {
+        let message = self.next_message(&state.connected, peer)?;
+        info!("sending {} added and {} dropped peers to peer {}", message.added.len(), message.dropped.len(), peer);
+        Some(message.encode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REMOTE: &str = "10.0.0.1:6881";
+
+    fn peers(n: usize) -> Vec<(SocketAddr, u8)> {
+        (0..n).map(|i| (SocketAddr::from(([10, 1, (i >> 8) as u8, i as u8], 6881)), FLAG_REACHABLE)).collect()
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let message = PexMessage {
+            added: vec![("10.0.0.2:6881".parse().unwrap(), FLAG_SEED), ("[::1]:6882".parse().unwrap(), FLAG_REACHABLE)],
+            dropped: vec!["10.0.0.3:6883".parse().unwrap()],
+        };
+        let decoded = PexMessage::decode(&message.encode()).unwrap();
+        assert_eq!(decoded.added, message.added);
+        assert_eq!(decoded.dropped, message.dropped);
+    }
+
+    #[test]
+    fn takes_at_most_fifty_added_peers() {
+        let mut state = PexState::default();
+        let accepted = state.accept(PexMessage { added: peers(1000), dropped: Vec::new() }, REMOTE.parse().unwrap());
+        assert_eq!(accepted.len(), MAX_PEERS_PER_MESSAGE);
+    }
+
+    #[test]
+    fn ignores_messages_sent_too_often() {
+        let mut state = PexState::default();
+        let remote = REMOTE.parse().unwrap();
+        assert_eq!(state.accept(PexMessage { added: peers(3), dropped: Vec::new() }, remote).len(), 3);
+        assert!(state.accept(PexMessage { added: peers(3), dropped: Vec::new() }, remote).is_empty());
+        state.last_received = Instant::now().checked_sub(PEX_INTERVAL);
+        assert_eq!(state.accept(PexMessage { added: peers(3), dropped: Vec::new() }, remote).len(), 3);
+    }
+
+    #[test]
+    fn sends_changes_at_most_once_per_interval() {
+        let mut state = PexState::default();
+        let remote: SocketAddr = REMOTE.parse().unwrap();
+        let mut connected: HashMap<SocketAddr, u8> = peers(60).into_iter().collect();
+        connected.insert(remote, 0);
+        let first = state.next_message(&connected, remote).unwrap();
+        assert_eq!(first.added.len(), MAX_PEERS_PER_MESSAGE);
+        assert!(first.added.iter().all(|(a, _)| *a != remote));
+        assert!(state.next_message(&connected, remote).is_none());
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::HashSet;
+
+use crate::peer::Bitfield;
+
+// chooses the piece a connection downloads next
+#[derive(Debug)]
+pub struct PiecePicker {
+    // how many connected peers have each piece
+    availability: Vec<u32>,
+    // pieces no connection is downloading
+    todo: HashSet<u32>,
+}
+
+impl PiecePicker {
+    pub fn new(num_pieces: usize) -> Self {
+        PiecePicker { availability: vec![0; num_pieces], todo: (0..num_pieces as u32).collect() }
+    }
+
+    pub fn add_bitfield(&mut self, pieces: &Bitfield) {
+        for (piece, count) in self.availability.iter_mut().enumerate() {
+            if pieces.has_piece(piece).unwrap_or(false) {
+                *count += 1;
+            }
+        }
+    }
+
+    pub fn remove_bitfield(&mut self, pieces: &Bitfield) {
+        for (piece, count) in self.availability.iter_mut().enumerate() {
+            if pieces.has_piece(piece).unwrap_or(false) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    pub fn add_piece(&mut self, piece: u32) {
+        if let Some(count) = self.availability.get_mut(piece as usize) {
+            *count += 1;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.todo.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.todo.clear();
+    }
+
+    pub fn any(&self, wants: impl Fn(u32) -> bool) -> bool {
+        self.todo.iter().any(|&p| wants(p))
+    }
+
+    pub fn requeue(&mut self, piece: u32) {
+        self.todo.insert(piece);
+    }
+
+    // takes a specific piece, if no connection is downloading it
+    pub fn take(&mut self, piece: u32) -> bool {
+        self.todo.remove(&piece)
+    }
+
+    // takes a wanted piece: started pieces before fresh ones, then the rarest, with ties broken at random
+    pub fn pick(&mut self, wants: impl Fn(u32) -> bool, started: impl Fn(u32) -> bool) -> Option<u32> {
+        let mut best: Option<(bool, u32)> = None;
+        let mut chosen: Option<u32> = None;
+        let mut ties = 0;
+        for &piece in self.todo.iter().filter(|&&p| wants(p)) {
+            let rank = (!started(piece), self.availability[piece as usize]);
+            if best.is_none_or(|b| rank < b) {
+                best = Some(rank);
+                chosen = Some(piece);
+                ties = 1;
+            } else if best == Some(rank) {
+                ties += 1;
+                if rand::random_range(0..ties) == 0 {
+                    chosen = Some(piece);
+                }
+            }
+        }
+        let piece = chosen?;
+        self.todo.remove(&piece);
+        Some(piece)
+    }
+}
@@ -0,0 +1,158 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// a peer that failed is next tried after this, doubling with each further failure
+const INITIAL_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+// failures in a row after which a peer is forgotten until some source offers it again
+const MAX_FAILURES: u32 = 6;
+// how long a peer that closed a working connection waits before being tried again
+const RECONNECT_DELAY: Duration = Duration::from_secs(60);
+// candidates kept at once; once full, new offers displace failing or long-untried peers
+const MAX_CANDIDATES: usize = 2000;
+
+#[derive(Debug)]
+struct Candidate {
+    failures: u32,
+    retry_at: Instant,
+    offered: Instant,
+    // whether a connection to the peer has ever worked
+    connected: bool,
+}
+
+// peers offered by trackers, the DHT, local discovery and PEX, with when each may next be tried
+#[derive(Debug, Default)]
+pub struct PeerPool {
+    candidates: HashMap<SocketAddr, Candidate>,
+    // addresses that turned out to be our own, which are never tried
+    own: HashSet<SocketAddr>,
+}
+
+impl PeerPool {
+    // adds a peer unless already known, making room if full by evicting one that is not `busy`
+    pub fn add(&mut self, peer: SocketAddr, busy: impl Fn(&SocketAddr) -> bool) {
+        if self.own.contains(&peer) || self.candidates.contains_key(&peer) {
+            return
+        }
+        if self.candidates.len() >= MAX_CANDIDATES {
+            let Some(evicted) = self.evictable(busy) else {
+                return
+            };
+            self.candidates.remove(&evicted);
+        }
+        let now = Instant::now();
+        self.candidates.insert(peer, Candidate { failures: 0, retry_at: now, offered: now, connected: false });
+    }
+
+    // the candidate that failed most, or failing that the longest offered one never connected to
+    fn evictable(&self, busy: impl Fn(&SocketAddr) -> bool) -> Option<SocketAddr> {
+        self.candidates
+            .iter()
+            .filter(|(peer, candidate)| (candidate.failures > 0 || !candidate.connected) && !busy(peer))
+            .max_by_key(|(_, candidate)| (candidate.failures, Reverse(candidate.offered)))
+            .map(|(peer, _)| *peer)
+    }
+
+    // the longest waiting peer that may be tried now and is not `busy`
+    pub fn next(&self, busy: impl Fn(&SocketAddr) -> bool) -> Option<SocketAddr> {
+        let now = Instant::now();
+        self.candidates
+            .iter()
+            .filter(|(peer, candidate)| candidate.retry_at <= now && !busy(peer))
+            .min_by_key(|(_, candidate)| candidate.retry_at)
+            .map(|(peer, _)| *peer)
+    }
+
+    // puts off the peer's next try, forgetting it once it has failed too often
+    pub fn failed(&mut self, peer: SocketAddr) {
+        let Some(candidate) = self.candidates.get_mut(&peer) else {
+            return
+        };
+        candidate.failures += 1;
+        if candidate.failures >= MAX_FAILURES {
+            self.candidates.remove(&peer);
+            return
+        }
+        let backoff = (INITIAL_BACKOFF * 2u32.pow(candidate.failures - 1)).min(MAX_BACKOFF);
+        candidate.retry_at = Instant::now() + backoff;
+    }
+
+    // a connection that worked ended, so the peer is worth trying again later
+    pub fn closed(&mut self, peer: SocketAddr) {
+        if let Some(candidate) = self.candidates.get_mut(&peer) {
+            candidate.failures = 0;
+            candidate.retry_at = Instant::now() + RECONNECT_DELAY;
+            candidate.connected = true;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    pub fn forget(&mut self, peer: SocketAddr) {
+        self.candidates.remove(&peer);
+    }
+
+    pub fn mark_own(&mut self, peer: SocketAddr) {
+        self.candidates.remove(&peer);
+        self.own.insert(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(i: usize) -> SocketAddr {
+        SocketAddr::from(([10, 0, (i >> 8) as u8, i as u8], 6881))
+    }
+
+    // a full pool in which lower numbered peers were offered earlier
+    fn full_pool() -> PeerPool {
+        let mut pool = PeerPool::default();
+        let now = Instant::now();
+        for i in 0..MAX_CANDIDATES {
+            pool.add(peer(i), |_| false);
+            pool.candidates.get_mut(&peer(i)).unwrap().offered = now - Duration::from_secs((MAX_CANDIDATES - i) as u64);
+        }
+        pool
+    }
+
+    #[test]
+    fn evicts_the_peer_that_failed_most() {
+        let mut pool = full_pool();
+        pool.failed(peer(5));
+        pool.failed(peer(7));
+        pool.failed(peer(7));
+        pool.add(peer(MAX_CANDIDATES), |_| false);
+        assert_eq!(pool.candidates.len(), MAX_CANDIDATES);
+        assert!(!pool.candidates.contains_key(&peer(7)));
+        assert!(pool.candidates.contains_key(&peer(5)));
+        assert!(pool.candidates.contains_key(&peer(MAX_CANDIDATES)));
+    }
+
+    #[test]
+    fn evicts_the_oldest_untried_peer() {
+        let mut pool = full_pool();
+        pool.closed(peer(0));
+        pool.add(peer(MAX_CANDIDATES), |p| *p == peer(1));
+        assert!(pool.candidates.contains_key(&peer(0)));
+        assert!(pool.candidates.contains_key(&peer(1)));
+        assert!(!pool.candidates.contains_key(&peer(2)));
+        assert!(pool.candidates.contains_key(&peer(MAX_CANDIDATES)));
+    }
+
+    #[test]
+    fn keeps_peers_that_worked() {
+        let mut pool = full_pool();
+        for i in 0..MAX_CANDIDATES {
+            pool.closed(peer(i));
+        }
+        pool.add(peer(MAX_CANDIDATES), |_| false);
+        assert!(!pool.candidates.contains_key(&peer(MAX_CANDIDATES)));
+        assert_eq!(pool.candidates.len(), MAX_CANDIDATES);
+    }
+}
@@ -0,0 +1,904 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::collections::hash_map::Entry;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::task::AbortHandle;
+use tokio::time::sleep_until;
+use tracing::{info, warn};
+
+// BEP 29 packets are a 20 byte header, then any extensions, then the payload
+const HEADER_LENGTH: usize = 20;
+const VERSION: u8 = 1;
+const MAX_DATAGRAM: usize = 1500;
+// payload per packet, keeping datagrams under common path MTUs
+const MSS: usize = 1380;
+
+// LEDBAT keeps the queuing delay it adds to the link near this, yielding to other traffic beyond it
+const TARGET_DELAY: u32 = 100_000;
+// the most the window grows per round trip, in packets
+const GAIN: f64 = 1.0;
+const MIN_WINDOW: f64 = MSS as f64;
+const INITIAL_WINDOW: f64 = 2.0 * MSS as f64;
+const MAX_WINDOW: f64 = 1024.0 * 1024.0;
+// the base delay is the smallest seen over the last two of these
+const BASE_DELAY_PERIOD: Duration = Duration::from_secs(60);
+
+// bytes received but not yet read, advertised to the peer as the space it may fill
+const RECEIVE_BUFFER: usize = 1024 * 1024;
+// bytes written but not yet sent before writes wait
+const SEND_BUFFER: usize = 256 * 1024;
+// how far ahead of the next expected packet one may arrive and be held; any further are dropped
+const MAX_REORDER: usize = 512;
+// connections a socket holds at once; SYNs beyond this are reset rather than each spawning a task
+const MAX_CONNECTIONS: usize = 1024;
+
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+// a connection is given up when a packet goes unacknowledged this many times
+const MAX_TRANSMISSIONS: u32 = 8;
+// a peer that does not answer a second SYN most likely does not speak uTP
+const MAX_SYN_TRANSMISSIONS: u32 = 2;
+const DUPLICATE_ACKS: u32 = 3;
+// how long a closed connection waits for the peer's FIN before giving up on it
+const LINGER: Duration = Duration::from_secs(10);
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PacketType {
+    Data  = 0,
+    Fin   = 1,
+    State = 2,
+    Reset = 3,
+    Syn   = 4,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, ()> {
+        match value {
+            0 => Ok(PacketType::Data),
+            1 => Ok(PacketType::Fin),
+            2 => Ok(PacketType::State),
+            3 => Ok(PacketType::Reset),
+            4 => Ok(PacketType::Syn),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Packet {
+    kind: PacketType,
+    connection_id: u16,
+    timestamp: u32,
+    // the sender's clock when it received our latest packet, minus that packet's timestamp
+    timestamp_difference: u32,
+    window: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LENGTH + self.payload.len());
+        buf.push((self.kind as u8) << 4 | VERSION);
+        // no extensions
+        buf.push(0);
+        buf.extend_from_slice(&self.connection_id.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp_difference.to_be_bytes());
+        buf.extend_from_slice(&self.window.to_be_bytes());
+        buf.extend_from_slice(&self.seq_nr.to_be_bytes());
+        buf.extend_from_slice(&self.ack_nr.to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    // extensions, such as selective acks, are skipped
+    fn decode(datagram: &[u8]) -> Option<Self> {
+        if datagram.len() < HEADER_LENGTH || datagram[0] & 0x0F != VERSION {
+            return None
+        }
+        let kind = PacketType::try_from(datagram[0] >> 4).ok()?;
+        let u16_at = |i: usize| u16::from_be_bytes([datagram[i], datagram[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(datagram[i..i + 4].try_into().expect("header length checked"));
+        let mut extension = datagram[1];
+        let mut offset = HEADER_LENGTH;
+        while extension != 0 {
+            let (&next, &length) = (datagram.get(offset)?, datagram.get(offset + 1)?);
+            extension = next;
+            offset += 2 + length as usize;
+        }
+        Some(Packet {
+            kind,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            window: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            payload: datagram.get(offset..)?.to_vec(),
+        })
+    }
+}
+
+// whether `a` comes at or before `b`, allowing for sequence numbers wrapping
+fn seq_le(a: u16, b: u16) -> bool {
+    b.wrapping_sub(a) < 0x8000
+}
+
+#[derive(Debug)]
+struct Transport {
+    socket: Arc<UdpSocket>,
+    // the fraction of outgoing data packets dropped, for tests over a lossy link
+    #[cfg(test)]
+    loss: f64,
+}
+
+impl Transport {
+    fn new(socket: UdpSocket) -> Self {
+        Transport {
+            socket: Arc::new(socket),
+            #[cfg(test)]
+            loss: 0.0,
+        }
+    }
+
+    async fn send(&self, packet: &Packet, to: SocketAddr) {
+        #[cfg(test)]
+        if packet.kind == PacketType::Data && rand::random::<f64>() < self.loss {
+            return
+        }
+        if let Err(e) = self.socket.send_to(&packet.encode(), to).await {
+            warn!("uTP send to {} failed: {:?}", to, e);
+        }
+    }
+}
+
+type Connections = Arc<Mutex<HashMap<(SocketAddr, u16), mpsc::UnboundedSender<Packet>>>>;
+type Datagrams = mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>;
+
+// a UDP socket carrying uTP connections, and handing any other datagrams, such as the DHT's, to whoever shares it
+#[derive(Debug)]
+pub struct UtpSocket {
+    transport: Arc<Transport>,
+    connections: Connections,
+    incoming: tokio::sync::Mutex<mpsc::UnboundedReceiver<(UtpStream, SocketAddr)>>,
+    others: Arc<Mutex<Option<Datagrams>>>,
+    dispatcher: AbortHandle,
+}
+
+impl Drop for UtpSocket {
+    fn drop(&mut self) {
+        self.dispatcher.abort();
+    }
+}
+
+impl UtpSocket {
+    pub async fn bind(address: SocketAddr) -> io::Result<Self> {
+        Ok(UtpSocket::over(Transport::new(UdpSocket::bind(address).await?)))
+    }
+
+    fn over(transport: Transport) -> Self {
+        let transport = Arc::new(transport);
+        let connections: Connections = Arc::new(Mutex::new(HashMap::new()));
+        let others = Arc::new(Mutex::new(None));
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let dispatcher = tokio::spawn(dispatch(transport.clone(), connections.clone(), others.clone(), incoming_tx)).abort_handle();
+        UtpSocket { transport, connections, incoming: tokio::sync::Mutex::new(incoming), others, dispatcher }
+    }
+
+    // the socket and the datagrams arriving on it that are not uTP
+    pub fn share(&self) -> (Arc<UdpSocket>, mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        *self.others.lock().unwrap() = Some(tx);
+        (self.transport.socket.clone(), rx)
+    }
+
+    pub async fn connect(&self, peer: SocketAddr) -> io::Result<UtpStream> {
+        let (tx, packets) = mpsc::unbounded_channel();
+        let recv_id = loop {
+            let id: u16 = rand::random();
+            let mut connections = self.connections.lock().unwrap();
+            if let Entry::Vacant(entry) = connections.entry((peer, id)) {
+                entry.insert(tx);
+                break id
+            }
+        };
+        let (connected_tx, connected) = oneshot::channel();
+        let (mut connection, stream) = Connection::new(peer, recv_id, recv_id.wrapping_add(1), 1, 0, &self.transport, &self.connections);
+        connection.connected = Some(connected_tx);
+        let syn = connection.packet(PacketType::Syn, Vec::new());
+        let syn = Packet { connection_id: recv_id, ..syn };
+        connection.seq_nr = connection.seq_nr.wrapping_add(1);
+        connection.transport.send(&syn, peer).await;
+        connection.in_flight.push_back(Sent { packet: syn, sent_at: Instant::now(), transmissions: 1 });
+        tokio::spawn(connection.drive(packets));
+        match connected.await {
+            Ok(Ok(())) => Ok(stream),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(io::ErrorKind::ConnectionAborted.into()),
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<(UtpStream, SocketAddr)> {
+        self.incoming.lock().await.recv().await.ok_or(io::ErrorKind::NotConnected.into())
+    }
+}
+
+async fn dispatch(transport: Arc<Transport>, connections: Connections, others: Arc<Mutex<Option<Datagrams>>>, incoming: mpsc::UnboundedSender<(UtpStream, SocketAddr)>) {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let (n, from) = match transport.socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("uTP receive failed: {:?}", e);
+                continue
+            },
+        };
+        let Some(packet) = Packet::decode(&buf[..n]) else {
+            if let Some(others) = others.lock().unwrap().as_ref() {
+                let _ = others.send((buf[..n].to_vec(), from));
+            }
+            continue
+        };
+        // a SYN's id is the one the initiator receives on; it sends on, and we receive on, the next
+        let key = match packet.kind {
+            PacketType::Syn => (from, packet.connection_id.wrapping_add(1)),
+            _ => (from, packet.connection_id),
+        };
+        let route = connections.lock().unwrap().get(&key).cloned();
+        match route {
+            Some(connection) => {
+                let _ = connection.send(packet);
+            },
+            None if packet.kind == PacketType::Syn && connections.lock().unwrap().len() < MAX_CONNECTIONS => {
+                let (tx, packets) = mpsc::unbounded_channel();
+                connections.lock().unwrap().insert(key, tx);
+                let (mut connection, stream) = Connection::new(from, key.1, packet.connection_id, rand::random(), packet.seq_nr, &transport, &connections);
+                connection.state = ConnectionState::Connected;
+                connection.reply_micro = connection.now_micros().wrapping_sub(packet.timestamp);
+                connection.ack().await;
+                info!("accepted uTP connection from {}", from);
+                tokio::spawn(connection.drive(packets));
+                let _ = incoming.send((stream, from));
+            },
+            None if packet.kind != PacketType::Reset => {
+                let reset = Packet { kind: PacketType::Reset, timestamp_difference: 0, window: 0, payload: Vec::new(), ..packet };
+                transport.send(&reset, from).await;
+            },
+            None => (),
+        }
+    }
+}
+
+// what a stream and its connection's task share
+#[derive(Debug, Default)]
+struct Shared {
+    // in order bytes received but not yet read
+    received: VecDeque<u8>,
+    // bytes written but not yet sent
+    unsent: VecDeque<u8>,
+    // the peer has finished sending
+    eof: bool,
+    error: Option<io::ErrorKind>,
+    // no more will be written; a FIN follows what is queued
+    closing: bool,
+    // nothing more will be read either
+    dropped: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Shared {
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+// a reliable, ordered connection to a peer over uTP
+#[derive(Debug)]
+pub struct UtpStream {
+    shared: Arc<Mutex<Shared>>,
+    notify: Arc<Notify>,
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.closing = true;
+        shared.dropped = true;
+        self.notify.notify_one();
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut shared = self.shared.lock().unwrap();
+        if !shared.received.is_empty() {
+            let n = shared.received.len().min(buf.remaining());
+            let (front, back) = shared.received.as_slices();
+            let from_front = n.min(front.len());
+            buf.put_slice(&front[..from_front]);
+            buf.put_slice(&back[..n - from_front]);
+            shared.received.drain(..n);
+            // the connection may need to tell the peer there is room again
+            self.notify.notify_one();
+            return Poll::Ready(Ok(()))
+        }
+        if let Some(kind) = shared.error {
+            return Poll::Ready(Err(kind.into()))
+        }
+        if shared.eof {
+            return Poll::Ready(Ok(()))
+        }
+        shared.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(kind) = shared.error {
+            return Poll::Ready(Err(kind.into()))
+        }
+        if shared.closing {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+        let room = SEND_BUFFER.saturating_sub(shared.unsent.len());
+        if room == 0 {
+            shared.write_waker = Some(cx.waker().clone());
+            return Poll::Pending
+        }
+        let n = room.min(buf.len());
+        shared.unsent.extend(&buf[..n]);
+        self.notify.notify_one();
+        Poll::Ready(Ok(n))
+    }
+
+    // queued bytes go out as fast as the congestion window allows
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.shared.lock().unwrap().error {
+            Some(kind) => Poll::Ready(Err(kind.into())),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.lock().unwrap().closing = true;
+        self.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ConnectionState {
+    SynSent,
+    Connected,
+}
+
+#[derive(Debug)]
+struct Sent {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+}
+
+// the state of one connection, owned by the task driving it
+#[derive(Debug)]
+struct Connection {
+    peer: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    state: ConnectionState,
+    transport: Arc<Transport>,
+    connections: Connections,
+    shared: Arc<Mutex<Shared>>,
+    notify: Arc<Notify>,
+    connected: Option<oneshot::Sender<io::Result<()>>>,
+    epoch: Instant,
+    // the next packet's sequence number, and the last one received in order
+    seq_nr: u16,
+    ack_nr: u16,
+    in_flight: VecDeque<Sent>,
+    in_flight_bytes: usize,
+    reorder: BTreeMap<u16, Packet>,
+    reorder_bytes: usize,
+    fin_sent: bool,
+    fin_acked: bool,
+    closed_at: Option<Instant>,
+    // the window in bytes, grown or shrunk by how far the queuing delay is from target
+    cwnd: f64,
+    peer_window: usize,
+    advertised: usize,
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    timeout: Duration,
+    duplicate_acks: u32,
+    last_ack: u16,
+    // the last packet sent when a loss was found; until it is acknowledged, each ack that falls short of it
+    // points at the next lost packet
+    recovery: Option<u16>,
+    // the lowest one way delay seen in this period and the last, standing in for the delay of an empty queue
+    delay_minima: [u32; 2],
+    period_start: Instant,
+    reply_micro: u32,
+}
+
+impl Connection {
+    fn new(peer: SocketAddr, recv_id: u16, send_id: u16, seq_nr: u16, ack_nr: u16, transport: &Arc<Transport>, connections: &Connections) -> (Self, UtpStream) {
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let notify = Arc::new(Notify::new());
+        let connection = Connection {
+            peer,
+            recv_id,
+            send_id,
+            state: ConnectionState::SynSent,
+            transport: transport.clone(),
+            connections: connections.clone(),
+            shared: shared.clone(),
+            notify: notify.clone(),
+            connected: None,
+            epoch: Instant::now(),
+            seq_nr,
+            ack_nr,
+            in_flight: VecDeque::new(),
+            in_flight_bytes: 0,
+            reorder: BTreeMap::new(),
+            reorder_bytes: 0,
+            fin_sent: false,
+            fin_acked: false,
+            closed_at: None,
+            cwnd: INITIAL_WINDOW,
+            peer_window: RECEIVE_BUFFER,
+            advertised: RECEIVE_BUFFER,
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            timeout: INITIAL_TIMEOUT,
+            duplicate_acks: 0,
+            last_ack: seq_nr.wrapping_sub(1),
+            recovery: None,
+            delay_minima: [u32::MAX; 2],
+            period_start: Instant::now(),
+            reply_micro: 0,
+        };
+        (connection, UtpStream { shared, notify })
+    }
+
+    fn now_micros(&self) -> u32 {
+        self.epoch.elapsed().as_micros() as u32
+    }
+
+    fn window(&self) -> usize {
+        RECEIVE_BUFFER.saturating_sub(self.shared.lock().unwrap().received.len())
+    }
+
+    fn packet(&self, kind: PacketType, payload: Vec<u8>) -> Packet {
+        Packet {
+            kind,
+            connection_id: self.send_id,
+            timestamp: self.now_micros(),
+            timestamp_difference: self.reply_micro,
+            window: self.window() as u32,
+            seq_nr: self.seq_nr,
+            ack_nr: self.ack_nr,
+            payload,
+        }
+    }
+
+    async fn ack(&mut self) {
+        let state = self.packet(PacketType::State, Vec::new());
+        self.advertised = state.window as usize;
+        self.transport.send(&state, self.peer).await;
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        if let Some(connected) = self.connected.take() {
+            let _ = connected.send(Err(kind.into()));
+        }
+        let mut shared = self.shared.lock().unwrap();
+        shared.error = Some(kind);
+        shared.wake();
+    }
+
+    // abandons the connection, telling the peer
+    async fn reset(&mut self) {
+        let reset = self.packet(PacketType::Reset, Vec::new());
+        self.transport.send(&reset, self.peer).await;
+        self.fail(io::ErrorKind::ConnectionReset);
+    }
+
+    fn failed(&self) -> bool {
+        self.shared.lock().unwrap().error.is_some()
+    }
+
+    // done once our FIN is acknowledged and the peer's has arrived, or the stream is gone and the peer took too long
+    fn finished(&self) -> bool {
+        let shared = self.shared.lock().unwrap();
+        shared.error.is_some()
+            || (self.fin_acked && (shared.eof || shared.dropped))
+            || self.closed_at.is_some_and(|at| at.elapsed() >= LINGER)
+    }
+
+    async fn drive(mut self, mut packets: mpsc::UnboundedReceiver<Packet>) {
+        loop {
+            self.transmit().await;
+            if self.finished() {
+                break
+            }
+            let deadline = self.in_flight
+                .iter()
+                .map(|sent| sent.sent_at + self.timeout)
+                .min()
+                .unwrap_or_else(|| Instant::now() + LINGER);
+            tokio::select! {
+                packet = packets.recv() => match packet {
+                    Some(packet) => self.receive(packet).await,
+                    None => break,
+                },
+                _ = self.notify.notified() => {
+                    // a read made room after we advertised too little for a full packet
+                    if self.state == ConnectionState::Connected && self.advertised < MSS && self.window() >= MSS {
+                        self.ack().await;
+                    }
+                },
+                _ = sleep_until(deadline.into()) => self.expire().await,
+            }
+        }
+        self.connections.lock().unwrap().remove(&(self.peer, self.recv_id));
+        let mut shared = self.shared.lock().unwrap();
+        shared.eof = true;
+        shared.wake();
+    }
+
+    // sends queued bytes as the window allows, then a FIN once everything written is sent
+    async fn transmit(&mut self) {
+        if self.state != ConnectionState::Connected || self.failed() {
+            return
+        }
+        let window = (self.cwnd as usize).min(self.peer_window).max(MSS);
+        loop {
+            let payload: Vec<u8> = {
+                let mut shared = self.shared.lock().unwrap();
+                let n = shared.unsent.len().min(MSS);
+                if n == 0 || self.in_flight_bytes + n > window {
+                    break
+                }
+                let payload = shared.unsent.drain(..n).collect();
+                if let Some(waker) = shared.write_waker.take() {
+                    waker.wake();
+                }
+                payload
+            };
+            self.send_new(PacketType::Data, payload).await;
+        }
+        let closing = {
+            let shared = self.shared.lock().unwrap();
+            shared.closing && shared.unsent.is_empty()
+        };
+        if closing && !self.fin_sent {
+            self.fin_sent = true;
+            self.closed_at = Some(Instant::now());
+            self.send_new(PacketType::Fin, Vec::new()).await;
+        }
+    }
+
+    async fn send_new(&mut self, kind: PacketType, payload: Vec<u8>) {
+        let packet = self.packet(kind, payload);
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.advertised = packet.window as usize;
+        self.transport.send(&packet, self.peer).await;
+        self.in_flight_bytes += packet.payload.len();
+        self.in_flight.push_back(Sent { packet, sent_at: Instant::now(), transmissions: 1 });
+    }
+
+    async fn resend_oldest(&mut self) {
+        let (timestamp, reply_micro, window, ack_nr) = (self.now_micros(), self.reply_micro, self.window() as u32, self.ack_nr);
+        let Some(sent) = self.in_flight.front_mut() else {
+            return
+        };
+        sent.packet = Packet { timestamp, timestamp_difference: reply_micro, window, ack_nr, ..sent.packet.clone() };
+        sent.sent_at = Instant::now();
+        sent.transmissions += 1;
+        let packet = sent.packet.clone();
+        self.transport.send(&packet, self.peer).await;
+    }
+
+    // a packet unacknowledged past the timeout is taken as lost, collapsing the window
+    async fn expire(&mut self) {
+        let Some(oldest) = self.in_flight.front() else {
+            return
+        };
+        let limit = if oldest.packet.kind == PacketType::Syn { MAX_SYN_TRANSMISSIONS } else { MAX_TRANSMISSIONS };
+        if oldest.transmissions >= limit {
+            warn!("uTP connection to {} timed out", self.peer);
+            self.fail(io::ErrorKind::TimedOut);
+            return
+        }
+        self.cwnd = MIN_WINDOW;
+        self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+        self.recovery = Some(self.seq_nr.wrapping_sub(1));
+        self.resend_oldest().await;
+    }
+
+    async fn receive(&mut self, packet: Packet) {
+        self.reply_micro = self.now_micros().wrapping_sub(packet.timestamp);
+        self.peer_window = packet.window as usize;
+        match packet.kind {
+            PacketType::Reset => {
+                self.fail(io::ErrorKind::ConnectionReset);
+                return
+            },
+            // the initiator did not get our reply
+            PacketType::Syn => {
+                self.ack().await;
+                return
+            },
+            _ => (),
+        }
+        if self.state == ConnectionState::SynSent {
+            if packet.kind != PacketType::State {
+                return
+            }
+            self.state = ConnectionState::Connected;
+            // the responder's first data packet reuses the sequence number of its reply
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            if let Some(connected) = self.connected.take() {
+                let _ = connected.send(Ok(()));
+            }
+        }
+        let lost = self.acknowledged(packet.ack_nr, packet.timestamp_difference, packet.kind == PacketType::State);
+        if matches!(packet.kind, PacketType::Data | PacketType::Fin) {
+            if !self.accept_data(packet) {
+                warn!("uTP peer {} overran the receive window; resetting the connection", self.peer);
+                self.reset().await;
+                return
+            }
+            self.ack().await;
+        }
+        if lost {
+            self.resend_oldest().await;
+        }
+    }
+
+    // whether the oldest packet in flight is now taken as lost
+    fn acknowledged(&mut self, ack_nr: u16, delay: u32, bare: bool) -> bool {
+        let mut acked = 0;
+        while let Some(sent) = self.in_flight.front() {
+            if !seq_le(sent.packet.seq_nr, ack_nr) {
+                break
+            }
+            let sent = self.in_flight.pop_front().expect("front checked above");
+            if sent.packet.kind == PacketType::Fin {
+                self.fin_acked = true;
+            }
+            // retransmitted packets make for ambiguous samples
+            if sent.transmissions == 1 {
+                self.sample_rtt(sent.sent_at.elapsed());
+            }
+            acked += sent.packet.payload.len();
+            self.in_flight_bytes -= sent.packet.payload.len();
+        }
+        if acked == 0 {
+            // data packets repeat the ack while we have nothing new for them, so only bare acks count
+            if bare && ack_nr == self.last_ack && !self.in_flight.is_empty() {
+                self.duplicate_acks += 1;
+                if self.duplicate_acks == DUPLICATE_ACKS && self.recovery.is_none() {
+                    self.cwnd = (self.cwnd / 2.0).max(MIN_WINDOW);
+                    self.recovery = Some(self.seq_nr.wrapping_sub(1));
+                    return true
+                }
+            }
+            return false
+        }
+        self.last_ack = ack_nr;
+        self.duplicate_acks = 0;
+        if delay != 0 {
+            self.update_window(acked, delay);
+        }
+        if let Some(waker) = self.shared.lock().unwrap().write_waker.take() {
+            waker.wake();
+        }
+        match self.recovery {
+            Some(point) if seq_le(point, ack_nr) => {
+                self.recovery = None;
+                false
+            },
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    fn sample_rtt(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            },
+            Some(rtt) => {
+                let deviation = rtt.abs_diff(sample);
+                self.rtt_var = (self.rtt_var * 3 + deviation) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            },
+        }
+        let rtt = self.rtt.expect("set above");
+        self.timeout = (rtt + self.rtt_var * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    // LEDBAT: grow the window while the queuing delay is under target, shrink it as the delay passes target
+    fn update_window(&mut self, acked: usize, delay: u32) {
+        if self.period_start.elapsed() >= BASE_DELAY_PERIOD {
+            self.delay_minima = [u32::MAX, self.delay_minima[0]];
+            self.period_start = Instant::now();
+        }
+        self.delay_minima[0] = self.delay_minima[0].min(delay);
+        let base_delay = self.delay_minima[0].min(self.delay_minima[1]);
+        let queuing_delay = delay.wrapping_sub(base_delay).min(u32::MAX / 2) as f64;
+        let off_target = (TARGET_DELAY as f64 - queuing_delay) / TARGET_DELAY as f64;
+        self.cwnd += GAIN * off_target * acked as f64 * MSS as f64 / self.cwnd;
+        self.cwnd = self.cwnd.clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    // delivers packets in order, holding those that arrive early; false if the peer sent past the window we advertised
+    fn accept_data(&mut self, packet: Packet) -> bool {
+        let expected = self.ack_nr.wrapping_add(1);
+        // duplicates, and packets too far ahead to hold, are dropped for the peer to resend
+        if !seq_le(expected, packet.seq_nr)
+            || packet.seq_nr.wrapping_sub(expected) as usize >= MAX_REORDER
+            || self.reorder.contains_key(&packet.seq_nr) {
+            return true
+        }
+        let mut shared = self.shared.lock().unwrap();
+        // a packet's worth of slack, as senders probe a closed window with one
+        if shared.received.len() + self.reorder_bytes + packet.payload.len() > RECEIVE_BUFFER + MSS {
+            return false
+        }
+        if packet.seq_nr != expected {
+            self.reorder_bytes += packet.payload.len();
+            self.reorder.insert(packet.seq_nr, packet);
+            return true
+        }
+        let mut next = Some(packet);
+        while let Some(packet) = next {
+            self.ack_nr = packet.seq_nr;
+            if packet.kind == PacketType::Fin {
+                shared.eof = true;
+                self.reorder.clear();
+                self.reorder_bytes = 0;
+                break
+            }
+            shared.received.extend(packet.payload);
+            next = self.reorder.remove(&self.ack_nr.wrapping_add(1));
+            if let Some(packet) = &next {
+                self.reorder_bytes -= packet.payload.len();
+            }
+        }
+        if let Some(waker) = shared.read_waker.take() {
+            waker.wake();
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::timeout;
+
+    const LOCALHOST: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 0);
+
+    // a socket that drops the given fraction of the data packets it sends
+    async fn lossy(loss: f64) -> UtpSocket {
+        let socket = UdpSocket::bind(LOCALHOST).await.unwrap();
+        UtpSocket::over(Transport { loss, ..Transport::new(socket) })
+    }
+
+    // sends `length` bytes from one socket to the other, returning what arrived
+    async fn transfer(sender: UtpSocket, receiver: UtpSocket, length: usize) -> (Vec<u8>, Vec<u8>) {
+        let sent: Vec<u8> = (0..length).map(|i| (i % 251) as u8).collect();
+        let address = receiver.transport.socket.local_addr().unwrap();
+        let send = async {
+            let mut stream = sender.connect(address).await.unwrap();
+            stream.write_all(&sent).await.unwrap();
+            stream.shutdown().await.unwrap();
+            // the connection lives until the receiver has everything
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).await.unwrap();
+        };
+        let receive = async {
+            let (mut stream, _) = receiver.accept().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            stream.shutdown().await.unwrap();
+            received
+        };
+        let ((), received) = timeout(Duration::from_secs(60), async { tokio::join!(send, receive) }).await.expect("transfer timed out");
+        (sent, received)
+    }
+
+    #[tokio::test]
+    async fn transfers_over_loopback() {
+        let (sent, received) = transfer(UtpSocket::bind(LOCALHOST).await.unwrap(), UtpSocket::bind(LOCALHOST).await.unwrap(), 2 * 1024 * 1024).await;
+        assert!(received == sent);
+    }
+
+    #[tokio::test]
+    async fn transfers_over_a_lossy_link() {
+        let (sent, received) = transfer(lossy(0.05).await, lossy(0.05).await, 256 * 1024).await;
+        assert!(received == sent);
+    }
+
+    // a connected connection whose stream is never read from
+    async fn connection() -> (Connection, UtpStream) {
+        let socket = UtpSocket::bind(LOCALHOST).await.unwrap();
+        let peer = "127.0.0.1:6881".parse().unwrap();
+        let (mut connection, stream) = Connection::new(peer, 1, 2, 1, 0, &socket.transport, &socket.connections);
+        connection.state = ConnectionState::Connected;
+        (connection, stream)
+    }
+
+    fn data(seq_nr: u16) -> Packet {
+        Packet {
+            kind: PacketType::Data,
+            connection_id: 1,
+            timestamp: 0,
+            timestamp_difference: 0,
+            window: RECEIVE_BUFFER as u32,
+            seq_nr,
+            ack_nr: 0,
+            payload: vec![0; MSS],
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_packets_too_far_ahead() {
+        let (mut connection, _stream) = connection().await;
+        assert!(connection.accept_data(data(MAX_REORDER as u16 + 1)));
+        assert!(connection.reorder.is_empty());
+        assert!(connection.accept_data(data(MAX_REORDER as u16)));
+        assert!(connection.accept_data(data(MAX_REORDER as u16)));
+        assert_eq!(connection.reorder.len(), 1);
+        assert_eq!(connection.reorder_bytes, MSS);
+    }
+
+    #[tokio::test]
+    async fn delivers_held_packets_in_order() {
+        let (mut connection, _stream) = connection().await;
+        assert!(connection.accept_data(data(3)));
+        assert!(connection.accept_data(data(2)));
+        assert_eq!(connection.ack_nr, 0);
+        assert!(connection.accept_data(data(1)));
+        assert_eq!(connection.ack_nr, 3);
+        assert!(connection.reorder.is_empty());
+        assert_eq!(connection.reorder_bytes, 0);
+        assert_eq!(connection.shared.lock().unwrap().received.len(), 3 * MSS);
+    }
+
+    #[tokio::test]
+    async fn refuses_data_past_the_receive_window() {
+        let (mut connection, _stream) = connection().await;
+        let fits = RECEIVE_BUFFER / MSS + 1;
+        for seq_nr in 1..=fits as u16 {
+            assert!(connection.accept_data(data(seq_nr)));
+        }
+        assert!(!connection.accept_data(data(fits as u16 + 1)));
+    }
+}
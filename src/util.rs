@@ -3,11 +3,23 @@ use thiserror::Error;
 pub mod io;
 pub mod sha1;
 pub mod md5;
+pub mod rc4;
+pub mod dh;
 
 pub fn to_string(bytes: &[u8]) -> String {
      bytes.iter().map(|&byte| format!("{byte:02x}")).collect::<Vec<_>>().join("")
 }
 
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
 fn pad_bytes(bytes: &[u8], big_endian: bool) -> Vec<u8> {
     let n = bytes.len() as u64;
     let message_length: u64 = n * 8;
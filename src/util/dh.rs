@@ -0,0 +1,79 @@
+// arithmetic on 768-bit unsigned integers, enough for the Diffie-Hellman exchange of message stream encryption
+const LIMBS: usize = 24;
+pub const DH_BYTES: usize = LIMBS * 4;
+
+// little endian 32-bit limbs
+type Limbs = [u32; LIMBS];
+
+fn from_bytes(bytes: &[u8]) -> Limbs {
+    let mut padded = [0u8; DH_BYTES];
+    let n = bytes.len().min(DH_BYTES);
+    padded[DH_BYTES - n..].copy_from_slice(&bytes[bytes.len() - n..]);
+    let mut limbs: Limbs = [0; LIMBS];
+    for (i, chunk) in padded.rchunks_exact(4).enumerate() {
+        limbs[i] = u32::from_be_bytes(chunk.try_into().expect("chunks are 4 bytes"));
+    }
+    limbs
+}
+
+fn to_bytes(limbs: &Limbs) -> [u8; DH_BYTES] {
+    let mut bytes = [0u8; DH_BYTES];
+    for (i, chunk) in bytes.rchunks_exact_mut(4).enumerate() {
+        chunk.copy_from_slice(&limbs[i].to_be_bytes());
+    }
+    bytes
+}
+
+fn less_than(a: &Limbs, b: &Limbs) -> bool {
+    a.iter().rev().cmp(b.iter().rev()).is_lt()
+}
+
+// (a + b) mod m, for a and b already below m
+fn add_mod(a: &Limbs, b: &Limbs, m: &Limbs) -> Limbs {
+    let mut sum: Limbs = [0; LIMBS];
+    let mut carry = 0u64;
+    for i in 0..LIMBS {
+        let s = a[i] as u64 + b[i] as u64 + carry;
+        sum[i] = s as u32;
+        carry = s >> 32;
+    }
+    // a sum past 2^768 wraps, which the subtraction undoes
+    if carry != 0 || !less_than(&sum, m) {
+        let mut borrow = 0i64;
+        for i in 0..LIMBS {
+            let d = sum[i] as i64 - m[i] as i64 - borrow;
+            sum[i] = d as u32;
+            borrow = (d < 0) as i64;
+        }
+    }
+    sum
+}
+
+// (a * b) mod m by doubling and adding over the bits of b
+fn mul_mod(a: &Limbs, b: &Limbs, m: &Limbs) -> Limbs {
+    let mut product: Limbs = [0; LIMBS];
+    for i in (0..LIMBS * 32).rev() {
+        product = add_mod(&product, &product, m);
+        if b[i / 32] >> (i % 32) & 1 == 1 {
+            product = add_mod(&product, a, m);
+        }
+    }
+    product
+}
+
+// (base ^ exponent) mod modulus, all big endian
+pub fn mod_pow(base: &[u8], exponent: &[u8], modulus: &[u8]) -> [u8; DH_BYTES] {
+    let m = from_bytes(modulus);
+    // the modulus exceeds 2^767, so one subtraction brings any base below it
+    let b = add_mod(&from_bytes(base), &[0; LIMBS], &m);
+    let mut result = from_bytes(&[1]);
+    for byte in exponent {
+        for bit in (0..8).rev() {
+            result = mul_mod(&result, &result, &m);
+            if byte >> bit & 1 == 1 {
+                result = mul_mod(&result, &b, &m);
+            }
+        }
+    }
+    to_bytes(&result)
+}
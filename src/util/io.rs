@@ -1,7 +1,9 @@
 use std::fs::{self, File, remove_file};
-use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::io::{self, BufReader, BufWriter, Read, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
 use crate::metadata::file::{FileModeInfo, TorrentFile};
 use crate::piece_filename;
 use crate::util::md5::md5_hash;
@@ -34,6 +36,59 @@ impl FileInfo {
     }
 }
 
+// where verified pieces are read from when serving peers
+#[derive(Debug, Clone)]
+pub enum PieceSource {
+    // a `piece_N.bin` file per completed piece, while downloading
+    Pieces(PathBuf),
+    // the torrent's own files, once reconstituted or when seeding existing data
+    Files { files: Vec<(PathBuf, u64)>, piece_length: u64 },
+}
+
+impl PieceSource {
+    pub fn files(torrent: &TorrentFile, root: &Path) -> Self {
+        PieceSource::Files {
+            files: torrent.info.files().iter().map(|f| (root.join(&f.filepath), f.length)).collect(),
+            piece_length: torrent.num_bytes_per_piece,
+        }
+    }
+
+    pub async fn read(&self, piece: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; length as usize];
+        match self {
+            PieceSource::Pieces(dir) => {
+                let mut file = tokio::fs::File::open(dir.join(piece_filename!(piece))).await?;
+                file.seek(SeekFrom::Start(begin as u64)).await?;
+                file.read_exact(&mut buf).await?;
+            },
+            PieceSource::Files { files, piece_length } => {
+                // a block may span the end of one file and the start of the next
+                let mut offset = piece as u64 * piece_length + begin as u64;
+                let mut filled = 0;
+                for (path, file_length) in files {
+                    if filled == buf.len() {
+                        break
+                    }
+                    if offset >= *file_length {
+                        offset -= file_length;
+                        continue
+                    }
+                    let n = ((file_length - offset) as usize).min(buf.len() - filled);
+                    let mut file = tokio::fs::File::open(path).await?;
+                    file.seek(SeekFrom::Start(offset)).await?;
+                    file.read_exact(&mut buf[filled..filled + n]).await?;
+                    filled += n;
+                    offset = 0;
+                }
+                if filled < buf.len() {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+            },
+        }
+        Ok(buf)
+    }
+}
+
 impl FileModeInfo {
     fn files(&self) -> Box<[FileInfo]> {
         match self {
@@ -63,10 +118,6 @@ pub fn reconstitute_files_from_torrent(torrent: &TorrentFile, dir: &Path) -> Res
 
     reconstitute_files(&files, &piece_paths)?;
 
-    for piece_path in piece_paths {
-        remove_file(&piece_path).map_err(FileError::FileSystemError)?;
-    }
-
     for file in &files {
         verify_md5(file)?;
     }
@@ -74,6 +125,13 @@ pub fn reconstitute_files_from_torrent(torrent: &TorrentFile, dir: &Path) -> Res
     Ok(())
 }
 
+pub fn remove_piece_files(torrent: &TorrentFile, dir: &Path) -> Result<(), FileError> {
+    for i in 0..torrent.num_pieces {
+        remove_file(dir.join(piece_filename!(i))).map_err(FileError::FileSystemError)?;
+    }
+    Ok(())
+}
+
 fn open_pieces_stream(piece_paths: &[PathBuf]) -> Result<Box<dyn Read>, FileError> {
     fn open_file(path: &PathBuf) -> Result<BufReader<File>, FileError> {
         Ok(BufReader::new(File::open(path).map_err(FileError::FileSystemError)?))